@@ -0,0 +1,70 @@
+// The corpus and timing helpers shared by the benchmark targets, each of which
+// uses only some of them.
+#![allow(dead_code)]
+
+use std::time::{Duration, Instant};
+
+#[path = "../../src/test_support.rs"]
+mod test_support;
+
+use test_support::identifier;
+
+// How long each measurement runs for, after one warm-up run.
+const MEASURE_FOR: Duration = Duration::from_millis(500);
+const MIN_RUNS: u32 = 3;
+
+// Programs that run the same on the evaluator and the VM. Functions recurse by
+// being passed themselves, since the compiler can't bind a let to itself.
+pub const PROGRAMS: [(&str, &str); 4] = [
+    ("fib", "let fib = fn(f, n) { if (n < 2) { n } else { f(f, n - 1) + f(f, n - 2) } };
+             fib(fib, 18)"),
+    ("map_reduce", "let range = fn(f, n, acc) { if (n == 0) { acc } else { f(f, n - 1, push(acc, n)) } };
+                    let numbers = range(range, 300, []);
+                    let doubled = map(numbers, fn(x) { x * 2 });
+                    let even = filter(doubled, fn(x) { x / 4 * 4 == x });
+                    reduce(even, 0, fn(acc, x) { acc + x })"),
+    ("strings", "let build = fn(f, n, s) { if (n == 0) { s } else { f(f, n - 1, s + \"ab\") } };
+                 let text = build(build, 400, \"\");
+                 len(join(split(upper(text), \"A\"), \"-\"))"),
+    ("hashes", "let h = {0: 1, 1: 3, 2: 5, 3: 7, 4: 11, 5: 13, 6: 17, 7: 19, 8: 23, 9: 29};
+                let sum = fn(f, n, acc) { if (n == 0) { acc } else { f(f, n - 1, acc + h[n - n / 10 * 10]) } };
+                sum(sum, 400, 0)"),
+];
+
+// A long program made of many bindings of arrays and hashes that call one
+// small function. The evaluator's closures copy the environment they were
+// created in, so the function is defined before everything else.
+pub fn generated_source(bindings: usize) -> String {
+    let mut source = String::from("let scale = fn(x, y) { if (x > y) { x * 2 - y } else { x + y } };\n");
+    for i in 0..bindings {
+        let name = identifier(i);
+        source += &format!(
+            "let {name} = [{i}, scale({i}, 3), \"{name}\"];\nlet {name}h = {{\"key\": {name}[1], \"other\": !true}};\n"
+        );
+    }
+    source + &format!("{}h[\"key\"]", identifier(bindings - 1))
+}
+
+// The mean time of one call to run, over at least MEASURE_FOR.
+pub fn measure(mut run: impl FnMut()) -> Duration {
+    measure_with(|| (), |_| run())
+}
+
+// Like measure, but each run gets a fresh input from setup, which isn't timed.
+pub fn measure_with<T>(mut setup: impl FnMut() -> T, mut run: impl FnMut(T)) -> Duration {
+    run(setup());
+    let mut spent = Duration::ZERO;
+    let mut runs = 0;
+    while runs < MIN_RUNS || spent < MEASURE_FOR {
+        let input = setup();
+        let started = Instant::now();
+        run(input);
+        spent += started.elapsed();
+        runs += 1;
+    }
+    spent / runs
+}
+
+pub fn per_second(amount: f64, time: Duration) -> f64 {
+    amount / time.as_secs_f64()
+}
@@ -0,0 +1,70 @@
+// Runs the corpus on the tree-walking evaluator and on the VM, with and
+// without the optimizer, so the engines can be compared directly. Parsing,
+// compiling and building the bytecode happen outside the timed part. Run with
+// `cargo bench --bench engines`.
+mod common;
+
+use std::hint::black_box;
+use monkey_interpreter::ast::{Node, Program};
+use monkey_interpreter::compiler::{ByteCode, Compiler};
+use monkey_interpreter::environment::Environment;
+use monkey_interpreter::evaluator::eval;
+use monkey_interpreter::lexer::Lexer;
+use monkey_interpreter::parser::Parser;
+use monkey_interpreter::vm::Vm;
+use monkey_interpreter::{Object, VmConfig};
+use common::{generated_source, measure_with, per_second, PROGRAMS};
+
+fn parse(source: &str) -> Program {
+    Parser::new(Lexer::new(source.to_string())).parse_program()
+}
+
+fn evaluate(program: Program) -> Object {
+    eval(Node::Program(program), &mut Environment::new())
+}
+
+fn compile(program: &Program, optimize: bool) -> Compiler {
+    let mut compiler = Compiler::new();
+    compiler.optimize = optimize;
+    compiler.compile(Node::Program(program.clone())).expect("benchmark doesn't compile");
+    compiler
+}
+
+fn execute(bytecode: ByteCode) -> Object {
+    let mut vm = Vm::new(bytecode, VmConfig::default());
+    vm.run().expect("benchmark failed");
+    vm.last_popped_stack_element()
+}
+
+fn main() {
+    let generated = generated_source(1000);
+    let mut sources: Vec<(&str, &str)> = PROGRAMS.to_vec();
+    sources.push(("generated", &generated));
+
+    println!("{:<12} {:>14} {:>14} {:>14} {:>10}", "program", "evaluator/s", "vm/s", "vm opt/s", "vm/eval");
+    for (name, source) in sources {
+        let program = parse(source);
+        let expected = evaluate(program.clone());
+        let evaluating = measure_with(|| program.clone(), |program| {
+            black_box(evaluate(program));
+        });
+
+        let mut runs = Vec::new();
+        for optimize in [false, true] {
+            let compiler = compile(&program, optimize);
+            assert_eq!(execute(compiler.get_bytecode()), expected, "{} gives a different result on the VM", name);
+            runs.push(measure_with(|| compiler.get_bytecode(), |bytecode| {
+                black_box(execute(bytecode));
+            }));
+        }
+
+        println!(
+            "{:<12} {:>14.1} {:>14.1} {:>14.1} {:>9.2}x",
+            name,
+            per_second(1.0, evaluating),
+            per_second(1.0, runs[0]),
+            per_second(1.0, runs[1]),
+            evaluating.as_secs_f64() / runs[0].as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,51 @@
+// Throughput of the lexer and the parser over the corpus and a large generated
+// source. Run with `cargo bench --bench frontend`.
+mod common;
+
+use std::hint::black_box;
+use monkey_interpreter::lexer::Lexer;
+use monkey_interpreter::parser::Parser;
+use monkey_interpreter::token::Token;
+use common::{generated_source, measure, per_second, PROGRAMS};
+
+fn count_tokens(source: &str) -> usize {
+    let mut lexer = Lexer::new(source.to_string());
+    let mut tokens = 0;
+    while lexer.next_token() != Token::EOF {
+        tokens += 1;
+    }
+    tokens
+}
+
+fn main() {
+    let generated = generated_source(2000);
+    let mut sources: Vec<(&str, &str)> = PROGRAMS.to_vec();
+    sources.push(("generated", &generated));
+
+    println!("{:<12} {:>10} {:>14} {:>10} {:>14} {:>10}", "source", "bytes", "lexer tok/s", "lexer MB/s", "parser stmt/s", "parser MB/s");
+    for (name, source) in sources {
+        let megabytes = source.len() as f64 / 1_000_000.0;
+        let tokens = count_tokens(source);
+        let lexing = measure(|| {
+            black_box(count_tokens(black_box(source)));
+        });
+
+        let mut parser = Parser::new(Lexer::new(source.to_string()));
+        let statements = parser.parse_program().statements.len();
+        assert!(parser.errors.is_empty(), "{} doesn't parse: {:?}", name, parser.errors);
+        let parsing = measure(|| {
+            let mut parser = Parser::new(Lexer::new(black_box(source).to_string()));
+            black_box(parser.parse_program());
+        });
+
+        println!(
+            "{:<12} {:>10} {:>14.0} {:>10.2} {:>14.0} {:>10.2}",
+            name,
+            source.len(),
+            per_second(tokens as f64, lexing),
+            per_second(megabytes, lexing),
+            per_second(statements as f64, parsing),
+            per_second(megabytes, parsing)
+        );
+    }
+}
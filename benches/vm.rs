@@ -0,0 +1,54 @@
+// Times numeric loops on the VM with the optimizer off and on, which is what
+// selects the superinstructions. Run with `cargo bench --bench vm`.
+mod common;
+
+use std::time::Duration;
+use monkey_interpreter::ast::Node;
+use monkey_interpreter::compiler::Compiler;
+use monkey_interpreter::lexer::Lexer;
+use monkey_interpreter::parser::Parser;
+use monkey_interpreter::vm::Vm;
+use monkey_interpreter::{Object, VmConfig};
+
+const PROGRAMS: [(&str, &str); 3] = [
+    ("sum", "let sum = fn(f, i, acc) { if (i > 0) { f(f, i - 1, acc + i) } else { acc } };
+             sum(sum, 200000, 0)"),
+    ("fib", "let fib = fn(f, n) { if (n < 2) { n } else { f(f, n - 1) + f(f, n - 2) } };
+             fib(fib, 22)"),
+    ("countdown", "let count = fn(f, n, hits) { if (n == 0) { hits } else { f(f, n - 1, hits + 1) } };
+                   count(count, 200000, 0)"),
+];
+
+// The mean time of a run, and the value the program produced.
+fn time(source: &str, optimize: bool) -> (Duration, Object) {
+    let program = Parser::new(Lexer::new(source.to_string())).parse_program();
+    let mut compiler = Compiler::new();
+    compiler.optimize = optimize;
+    compiler.compile(Node::Program(program)).expect("benchmark doesn't compile");
+
+    let mut result = Object::Null;
+    let mean = common::measure_with(
+        || Vm::new(compiler.get_bytecode(), VmConfig::default()),
+        |mut vm| {
+            vm.run().expect("benchmark failed");
+            result = vm.last_popped_stack_element();
+        }
+    );
+    (mean, result)
+}
+
+fn main() {
+    println!("{:<12} {:>12} {:>12} {:>8}", "program", "plain", "optimized", "speedup");
+    for (name, source) in PROGRAMS {
+        let (plain, expected) = time(source, false);
+        let (optimized, result) = time(source, true);
+        assert_eq!(result, expected, "{} gives a different result when optimized", name);
+        println!(
+            "{:<12} {:>10.2}ms {:>10.2}ms {:>7.2}x",
+            name,
+            plain.as_secs_f64() * 1000.0,
+            optimized.as_secs_f64() * 1000.0,
+            plain.as_secs_f64() / optimized.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,48 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use monkey_interpreter::object::FunctionCaller;
+use monkey_interpreter::{Engine, Object};
+
+fn greet(args: Vec<Object>, _caller: &mut dyn FunctionCaller) -> Object {
+    match args.first() {
+        Some(Object::StringObject(name)) => Object::from(format!("Hello, {}!", name)),
+        _ => Object::Error("greet expects a STRING".to_string()),
+    }
+}
+
+fn main() {
+    let mut engine = Engine::new();
+    engine.register_fn("greet", greet);
+
+    // Native functions can also be closures that keep state on the host side.
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let sink = log.clone();
+    engine.register_fn("log", move |args, _caller| {
+        sink.borrow_mut().extend(args.iter().map(|arg| arg.inspect()));
+        Object::Null
+    });
+    engine.set_global("names", vec!["Ada", "Grace", "Barbara"]);
+    engine.set_global("threshold", 4);
+
+    let script = "
+        let long_names = filter(names, fn(name) { len(name) > threshold });
+        let greetings = map(long_names, greet);
+        log(len(greetings));
+        join(greetings, \" \")
+    ";
+    match engine.run(script) {
+        Ok(value) => println!("{}", value.inspect()),
+        Err(error) => {
+            eprintln!("{}", error);
+            return;
+        }
+    }
+
+    let long_names: Vec<String> = engine
+        .get_global("long_names")
+        .expect("long_names is defined by the script")
+        .try_into()
+        .expect("long_names is an array of strings");
+    println!("{} names are longer than 4 characters", long_names.len());
+    println!("script logged: {:?}", log.borrow());
+}
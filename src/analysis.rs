@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use crate::ast::{Expression, FnStruct, Identifier, Node, Program, Statement};
+use crate::builtins::Builtins;
+use crate::compiler::Compiler;
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Lexer;
+use crate::parser::{ParseError, Parser};
+use crate::symbol_table::{SymbolScope, SymbolTable};
+use crate::token::{Position, Span};
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum DefinitionKind {
+    Let,
+    Parameter,
+    CatchBinding,
+    Builtin
+}
+
+// A name introduced by the program or provided as a builtin.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Definition {
+    pub name: String,
+    pub kind: DefinitionKind,
+    // Where the name is written; None for builtins.
+    pub span: Option<Span>,
+    // The whole let statement, for a Let.
+    pub statement: Option<Span>,
+    pub scope: usize,
+    // Code before this point can't use the name.
+    pub visible_from: Position
+}
+
+// An identifier used as a value, and the definition it resolves to, if any.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Reference {
+    pub span: Span,
+    pub definition: Option<usize>
+}
+
+// The program, or the body of a function.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Scope {
+    pub parent: Option<usize>,
+    // None for the program, which covers the whole source.
+    pub span: Option<Span>
+}
+
+// What editor tooling needs to know about a source: its syntax errors and how
+// every identifier resolves. Names resolve the way the compiler resolves them,
+// through SymbolTable scopes, so a let is only visible after its statement.
+pub struct Analysis {
+    pub program: Program,
+    pub errors: Vec<ParseError>,
+    pub definitions: Vec<Definition>,
+    pub references: Vec<Reference>,
+    pub scopes: Vec<Scope>
+}
+
+impl Analysis {
+    pub fn new(source: &str) -> Self {
+        let mut parser = Parser::new(Lexer::new(source.to_string()));
+        let program = parser.parse_program();
+        let mut resolver = Resolver::new();
+        for stmt in &program.statements {
+            resolver.statement(stmt);
+        }
+        Analysis {
+            program,
+            errors: parser.errors,
+            definitions: resolver.definitions,
+            references: resolver.references,
+            scopes: resolver.scopes
+        }
+    }
+
+    // The syntax errors or, when there are none, the compiler's error.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        if !self.errors.is_empty() {
+            return self.errors.iter().map(Diagnostic::from).collect();
+        }
+        let mut compiler = Compiler::new();
+        match compiler.compile(Node::Program(self.program.clone())) {
+            Ok(()) => Vec::new(),
+            Err(error) => vec![Diagnostic::from(&error)]
+        }
+    }
+
+    // The definition of the identifier at position, whether it is written
+    // there as a use or as the definition itself.
+    pub fn definition_at(&self, position: Position) -> Option<usize> {
+        let used = self.references.iter()
+            .find(|reference| contains(reference.span, position))
+            .and_then(|reference| reference.definition);
+        used.or_else(|| self.definitions.iter().position(|definition| {
+            definition.span.is_some_and(|span| contains(span, position))
+        }))
+    }
+
+    pub fn references_to(&self, definition: usize) -> Vec<Span> {
+        self.references.iter()
+            .filter(|reference| reference.definition == Some(definition))
+            .map(|reference| reference.span)
+            .collect()
+    }
+
+    // The definitions code at position can use, innermost first. A name that
+    // is redefined is only listed once.
+    pub fn visible_at(&self, position: Position) -> Vec<usize> {
+        let mut scope = self.scope_at(position);
+        let mut visible: Vec<usize> = Vec::new();
+        loop {
+            for (index, definition) in self.definitions.iter().enumerate().rev() {
+                if definition.scope == scope
+                    && definition.visible_from <= position
+                    && !visible.iter().any(|seen| self.definitions[*seen].name == definition.name)
+                {
+                    visible.push(index);
+                }
+            }
+            match self.scopes[scope].parent {
+                Some(parent) => scope = parent,
+                None => return visible
+            }
+        }
+    }
+
+    // The innermost scope containing position.
+    fn scope_at(&self, position: Position) -> usize {
+        self.scopes.iter()
+            .enumerate()
+            .filter(|(_, scope)| scope.span.is_some_and(|span| contains(span, position)))
+            .max_by_key(|(_, scope)| scope.span.map(|span| span.start))
+            .map_or(0, |(index, _)| index)
+    }
+}
+
+fn contains(span: Span, position: Position) -> bool {
+    span.start <= position && position <= span.end
+}
+
+// Walks the program with a SymbolTable per function, as the compiler does,
+// keeping the definition behind every symbol it defines.
+struct Resolver {
+    table: SymbolTable,
+    // The scopes being walked, innermost last.
+    chain: Vec<usize>,
+    // For each scope, the definition behind each symbol index.
+    symbols: Vec<HashMap<usize, usize>>,
+    builtins: HashMap<usize, usize>,
+    definitions: Vec<Definition>,
+    references: Vec<Reference>,
+    scopes: Vec<Scope>
+}
+
+impl Resolver {
+    fn new() -> Self {
+        let mut table = SymbolTable::new();
+        Builtins::new().define_in(&mut table);
+        let mut resolver = Resolver {
+            table,
+            chain: vec![0],
+            symbols: vec![HashMap::new()],
+            builtins: HashMap::new(),
+            definitions: Vec::new(),
+            references: Vec::new(),
+            scopes: vec![Scope { parent: None, span: None }]
+        };
+        for symbol in resolver.table.symbols() {
+            if symbol.scope == SymbolScope::BuiltIn {
+                resolver.builtins.insert(symbol.index, resolver.definitions.len());
+                resolver.definitions.push(Definition {
+                    name: symbol.name,
+                    kind: DefinitionKind::Builtin,
+                    span: None,
+                    statement: None,
+                    scope: 0,
+                    visible_from: Position::default()
+                });
+            }
+        }
+        resolver
+    }
+
+    fn define(&mut self, id: &Identifier, kind: DefinitionKind, statement: Option<Span>, visible_from: Position) {
+        let symbol = self.table.define(id.get_id());
+        let scope = self.chain[self.chain.len() - 1];
+        self.symbols[scope].insert(symbol.index, self.definitions.len());
+        self.definitions.push(Definition {
+            name: id.get_id(),
+            kind,
+            span: Some(id.span),
+            statement,
+            scope,
+            visible_from
+        });
+    }
+
+    fn resolve(&mut self, id: &Identifier) {
+        let definition = self.table.resolve_with_depth(&id.id).and_then(|(symbol, depth)| {
+            if symbol.scope == SymbolScope::BuiltIn {
+                return self.builtins.get(&symbol.index).copied();
+            }
+            let scope = self.chain[self.chain.len() - 1 - depth];
+            self.symbols[scope].get(&symbol.index).copied()
+        });
+        self.references.push(Reference { span: id.span, definition });
+    }
+
+    fn statement(&mut self, stmt: &Statement) {
+        match stmt {
+            Statement::LetStatement(id, expr, span) => {
+                self.expression(expr);
+                self.define(id, DefinitionKind::Let, Some(*span), span.end);
+            }
+            Statement::ReturnStatement(expr, _) | Statement::ExpressionStatement(expr, _) => {
+                self.expression(expr);
+            }
+        }
+    }
+
+    fn block(&mut self, block: &[Statement]) {
+        for stmt in block {
+            self.statement(stmt);
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression) {
+        match expr {
+            Expression::IdentifierExpression(id) => self.resolve(id),
+            Expression::PrefixExpression(_, right) => self.expression(right),
+            Expression::InfixExpression(left, _, right) => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Expression::IfExpression(content) => {
+                self.expression(&content.condition);
+                self.block(&content.consequence);
+                if let Some(alternative) = &content.alternative {
+                    self.block(alternative);
+                }
+            }
+            Expression::FnExpression(content) => self.function(content),
+            Expression::CallExpression(content) => {
+                self.expression(&content.function);
+                for arg in &content.args {
+                    self.expression(arg);
+                }
+            }
+            Expression::ArrayLiteral(content) => {
+                for element in &content.elements {
+                    self.expression(element);
+                }
+            }
+            Expression::IndexExpression(content) => {
+                self.expression(&content.left);
+                self.expression(&content.index);
+            }
+            Expression::HashExpression(content) => {
+                for (key, value) in &content.pairs {
+                    self.expression(key);
+                    self.expression(value);
+                }
+            }
+            Expression::TryExpression(content) => {
+                self.block(&content.body);
+                self.define(&content.error, DefinitionKind::CatchBinding, None, content.error.span.start);
+                self.block(&content.handler);
+            }
+            Expression::IntegerExpression(_) | Expression::BoolExpression(_)
+            | Expression::StringExpression(_) | Expression::Error(_) => {}
+        }
+    }
+
+    fn function(&mut self, function: &FnStruct) {
+        let outer = std::mem::replace(&mut self.table, SymbolTable::new());
+        self.table = SymbolTable::new_enclosed(outer);
+        self.scopes.push(Scope { parent: self.chain.last().copied(), span: Some(function.span) });
+        self.chain.push(self.scopes.len() - 1);
+        self.symbols.push(HashMap::new());
+
+        for param in &function.params {
+            self.define(param, DefinitionKind::Parameter, None, param.span.start);
+        }
+        self.block(&function.body);
+
+        self.chain.pop();
+        if let Some(outer) = self.table.outer.take() {
+            self.table = *outer;
+        }
+    }
+}
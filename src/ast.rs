@@ -1,4 +1,4 @@
-use crate::token::Token;
+use crate::token::{Span, Token};
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum Node {
@@ -28,26 +28,41 @@ impl Program {
         }
         result
     }
+
+    // Whether running the program leaves a value, that of its last statement.
+    // Only then is there a last popped element on the VM to read.
+    pub fn returns_value(&self) -> bool {
+        matches!(self.statements.last(), Some(Statement::ExpressionStatement(..)))
+    }
 }
+// Every statement carries the span of source it was parsed from.
 #[derive(PartialEq, Debug, Clone)]
 pub enum Statement {
-    LetStatement(Identifier, Expression),
-    ReturnStatement(Expression),
-    ExpressionStatement(Expression)
+    LetStatement(Identifier, Expression, Span),
+    ReturnStatement(Expression, Span),
+    ExpressionStatement(Expression, Span)
 }
 
 impl Statement{
+    pub fn span(&self) -> Span {
+        match self {
+            Statement::LetStatement(_, _, span) => *span,
+            Statement::ReturnStatement(_, span) => *span,
+            Statement::ExpressionStatement(_, span) => *span
+        }
+    }
+
     pub fn to_string(&self) -> String {
         match self {
-            Statement::LetStatement(id, expr) => {
+            Statement::LetStatement(id, expr, _) => {
                 let str = "let ".to_string() + id.get_id().as_str() + " = " + expr.to_string().as_str() + ";";
                 str
             },
-            Statement::ReturnStatement(expr) => {
+            Statement::ReturnStatement(expr, _) => {
                 let str = "return ".to_string() + expr.to_string().as_str() + ";";
                 str
             },
-            Statement::ExpressionStatement(expr) => {
+            Statement::ExpressionStatement(expr, _) => {
                 let str = expr.to_string() + ";";
                 str
             }
@@ -68,7 +83,9 @@ pub enum Expression {
     ArrayLiteral(ArrayStruct),
     IndexExpression(IndexStruct),
     HashExpression(HashStruct),
-    None
+    TryExpression(TryStruct),
+    // Stands in for an expression that didn't parse.
+    Error(Span)
 }
 
 impl Expression {
@@ -110,22 +127,42 @@ impl Expression {
             Expression::HashExpression(content) => {
                 content.to_string()
             }
-            Expression::None => {
-                String::from("None")
+            Expression::TryExpression(content) => {
+                content.to_string()
+            }
+            Expression::Error(_) => {
+                String::from("<error>")
             }
         }
     }
 }
 
-#[derive(PartialEq, Debug, Clone)]
+// Identifiers compare by name only: where an identifier was written doesn't
+// change what it refers to.
+#[derive(Debug, Clone)]
 pub struct Identifier {
-    pub id: String
+    pub id: String,
+    pub span: Span
+}
+
+impl PartialEq for Identifier {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
 }
 
 impl Identifier {
     pub fn new(id: String) -> Self {
         Identifier {
-            id
+            id,
+            span: Span::default()
+        }
+    }
+
+    pub fn new_at(id: String, span: Span) -> Self {
+        Identifier {
+            id,
+            span
         }
     }
 
@@ -137,6 +174,7 @@ impl Identifier {
 #[derive(PartialEq, Debug, Clone)]
 pub struct IfStruct {
     pub condition: Box<Expression>,
+    pub condition_span: Span,
     pub consequence: Vec<Statement>,
     pub alternative: Option<Vec<Statement>>
 }
@@ -163,7 +201,9 @@ impl IfStruct {
 #[derive(PartialEq, Debug, Clone)]
 pub struct FnStruct {
     pub params: Vec<Identifier>,
-    pub body: Vec<Statement>
+    pub body: Vec<Statement>,
+    // From `fn` to the closing brace of the body.
+    pub span: Span
 }
 
 impl FnStruct {
@@ -246,4 +286,26 @@ impl HashStruct
         result = result + pairs.join(",").as_str() + "}";
         result
     }
-}
\ No newline at end of file
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct TryStruct {
+    pub body: Vec<Statement>,
+    pub error: Identifier,
+    pub handler: Vec<Statement>
+}
+
+impl TryStruct {
+    pub fn to_string(&self) -> String {
+        let mut result = String::from("try {");
+        for stmt in &self.body {
+            result = result + stmt.to_string().as_str();
+        }
+        result = result + "} catch (" + self.error.get_id().as_str() + ") {";
+        for stmt in &self.handler {
+            result = result + stmt.to_string().as_str();
+        }
+        result = result + "}";
+        result
+    }
+}
@@ -0,0 +1,109 @@
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+// Reading the clock on every step would dominate small instructions, so the
+// deadline is only checked once every this many steps.
+const STEPS_BETWEEN_CLOCK_CHECKS: u64 = 256;
+
+// Limits for a single run of untrusted code. A step is one VM instruction or
+// one expression visited by the evaluator. Memory counts the approximate size
+// of every string, array and hash created during the run; values are never
+// given back, so this caps total allocation rather than live memory.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Budget {
+    pub max_steps: Option<u64>,
+    pub timeout: Option<Duration>,
+    pub max_memory: Option<usize>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum LimitExceeded {
+    Steps(u64),
+    Time(Duration),
+    Memory(usize),
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitExceeded::Steps(limit) => write!(f, "step limit exceeded: the limit is {} steps", limit),
+            LimitExceeded::Time(limit) => write!(f, "time limit exceeded: the limit is {}ms", limit.as_millis()),
+            LimitExceeded::Memory(limit) => write!(f, "out of memory: the limit is {} bytes", limit),
+        }
+    }
+}
+
+// Tracks how much of a Budget has been spent. Once a limit is hit every later
+// tick fails too, so code that swallows the first error can't keep running.
+#[derive(Debug, Clone)]
+pub struct Meter {
+    max_steps: Option<u64>,
+    timeout: Option<Duration>,
+    max_memory: Option<usize>,
+    deadline: Option<Instant>,
+    steps: u64,
+    allocated: usize,
+    exceeded: Option<LimitExceeded>,
+}
+
+impl Meter {
+    // The clock for the timeout starts when the meter is created.
+    pub fn new(budget: &Budget) -> Self {
+        Meter {
+            max_steps: budget.max_steps,
+            timeout: budget.timeout,
+            max_memory: budget.max_memory,
+            deadline: budget.timeout.map(|timeout| Instant::now() + timeout),
+            steps: 0,
+            allocated: 0,
+            exceeded: None,
+        }
+    }
+
+    pub fn tick(&mut self) -> Result<(), LimitExceeded> {
+        if let Some(limit) = &self.exceeded {
+            return Err(limit.clone());
+        }
+        self.steps += 1;
+        if let Some(max_steps) = self.max_steps {
+            if self.steps > max_steps {
+                self.exceeded = Some(LimitExceeded::Steps(max_steps));
+            }
+        }
+        if let (Some(deadline), Some(timeout)) = (self.deadline, self.timeout) {
+            if self.steps.is_multiple_of(STEPS_BETWEEN_CLOCK_CHECKS) && Instant::now() >= deadline {
+                self.exceeded = Some(LimitExceeded::Time(timeout));
+            }
+        }
+        match &self.exceeded {
+            Some(limit) => Err(limit.clone()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn allocate(&mut self, bytes: usize) -> Result<(), LimitExceeded> {
+        if let Some(limit) = &self.exceeded {
+            return Err(limit.clone());
+        }
+        self.allocated = self.allocated.saturating_add(bytes);
+        match self.max_memory {
+            Some(max_memory) if self.allocated > max_memory => {
+                self.exceeded = Some(LimitExceeded::Memory(max_memory));
+                Err(LimitExceeded::Memory(max_memory))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    pub fn exceeded(&self) -> Option<LimitExceeded> {
+        self.exceeded.clone()
+    }
+}
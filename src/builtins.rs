@@ -1,65 +1,152 @@
-use crate::object::{BuiltInFn, Object};
-#[allow(dead_code)]
+use std::collections::HashMap;
+use crate::object::{BuiltInFn, BuiltInFunction, FunctionCaller, Object};
+use crate::symbol_table::SymbolTable;
+// How many arguments a builtin takes, as (least, most), or None for any number.
+pub type Arity = Option<(usize, usize)>;
 
-pub const BUILT_INS: [&'static str; 6] = [
-        "len",
-        "first",
-        "rest",
-        "last",
-        "push",
-        "puts"
+// Each builtin with its arity. The functions check their own arguments when
+// called; the arity is for checking calls statically.
+#[allow(dead_code)]
+pub const BUILT_INS: [(&str, BuiltInFn, Arity); 30] = [
+        ("len", len, Some((1, 1))),
+        ("first", first, Some((1, 1))),
+        ("rest", rest, Some((1, 1))),
+        ("last", last, Some((1, 1))),
+        ("push", push, Some((2, 2))),
+        ("puts", puts, None),
+        ("keys", keys, Some((1, 1))),
+        ("values", values, Some((1, 1))),
+        ("has", has, Some((2, 2))),
+        ("delete", delete, Some((2, 2))),
+        ("merge", merge, Some((2, 2))),
+        ("entries", entries, Some((1, 1))),
+        ("split", split, Some((2, 2))),
+        ("join", join, Some((2, 2))),
+        ("trim", trim, Some((1, 1))),
+        ("upper", upper, Some((1, 1))),
+        ("lower", lower, Some((1, 1))),
+        ("contains", contains, Some((2, 2))),
+        ("starts_with", starts_with, Some((2, 2))),
+        ("ends_with", ends_with, Some((2, 2))),
+        ("replace", replace, Some((3, 3))),
+        ("substr", substr, Some((2, 3))),
+        ("chars", chars, Some((1, 1))),
+        ("index_of", index_of, Some((2, 2))),
+        ("map", map, Some((2, 2))),
+        ("filter", filter, Some((2, 2))),
+        ("reduce", reduce, Some((3, 3))),
+        ("sort_by", sort_by, Some((2, 2))),
+        ("find", find, Some((2, 2))),
+        ("error", error, Some((1, 1)))
 ];
+
+// How many arguments one of BUILT_INS takes, or None for puts.
+pub fn arity(name: &str) -> Arity {
+    BUILT_INS.iter().find(|(builtin, _, _)| *builtin == name).and_then(|(_, _, arity)| *arity)
+}
+
+// How many arguments something takes, for messages: "1 argument", "2 or 3 arguments".
+pub fn describe_arity(least: usize, most: usize) -> String {
+    match (least, most) {
+        (1, 1) => "1 argument".to_string(),
+        (least, most) if least == most => format!("{} arguments", least),
+        (least, most) => format!("{} or {} arguments", least, most)
+    }
+}
+
 pub fn get_built_in(id: String) -> Option<Object> {
-    match id.as_str() {
-        "len" =>
-            {
-                Some(Object::BuiltIn(len))
-            },
-        "first" =>
-            {
-                Some(Object::BuiltIn(first))
-            },
-        "rest" =>
-            {
-                Some(Object::BuiltIn(rest))
-            },
-        "last" =>
-            {
-                Some(Object::BuiltIn(last))
-            },
-        "push" =>
-            {
-                Some(Object::BuiltIn(push))
+    BUILT_INS.iter()
+        .find(|(name, _, _)| *name == id.as_str())
+        .map(|(name, function, _)| Object::BuiltIn(BuiltInFunction::new(name, *function)))
+}
+
+// The builtins visible to a program, in the order the compiler numbers them
+// for OpGetBuiltin. It starts with BUILT_INS and the host can add or replace
+// functions, including closures that capture their own state.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Builtins {
+    functions: Vec<BuiltInFunction>
+}
+
+impl Builtins {
+    pub fn new() -> Self {
+        let mut builtins = Builtins::empty();
+        for (name, function, _) in BUILT_INS {
+            builtins.register(name, function);
+        }
+        builtins
+    }
+
+    pub fn empty() -> Self {
+        Builtins {
+            functions: Vec::new()
+        }
+    }
+
+    // Returns the index of the function, reusing the slot of a builtin with the same name.
+    pub fn register<F>(&mut self, name: &str, function: F) -> usize
+    where
+        F: Fn(Vec<Object>, &mut dyn FunctionCaller) -> Object + 'static
+    {
+        let function = BuiltInFunction::new(name, function);
+        match self.functions.iter().position(|builtin| builtin.name == name) {
+            Some(index) => {
+                self.functions[index] = function;
+                index
             }
-        "puts" =>
-            {
-                Some(Object::BuiltIn(puts))
+            None => {
+                self.functions.push(function);
+                self.functions.len() - 1
             }
-        _ => {
-            None
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&BuiltInFunction> {
+        self.functions.get(index)
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&BuiltInFunction> {
+        self.functions.iter().find(|builtin| builtin.name == name)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.functions.iter().map(|builtin| builtin.name.clone()).collect()
+    }
+
+    pub fn define_in(&self, symbol_table: &mut SymbolTable) {
+        for (index, builtin) in self.functions.iter().enumerate() {
+            symbol_table.define_builtin(index, builtin.name.clone());
         }
     }
 }
 
+impl Default for Builtins {
+    fn default() -> Self {
+        Builtins::new()
+    }
+}
 
-fn len(args: Vec<Object>) -> Object {
+fn len(args: Vec<Object>, _caller: &mut dyn FunctionCaller) -> Object {
     if args.len() != 1 {
         Object::Error(format!("wrong number of arguments: got = {}, want = 1",args.len()))
     }
     else {
         if let Object::StringObject(content) = &args[0] {
-            Object::IntegerObject(content.len() as i64)
+            Object::IntegerObject(content.chars().count() as i64)
         }
         else if let Object::Array(content) = &args[0] {
             Object::IntegerObject(content.len() as i64)
         }
+        else if let Object::HashMap(content) = &args[0] {
+            Object::IntegerObject(content.len() as i64)
+        }
         else {
             Object::Error(format!("not suported type: {}", args[0].get_type()))
         }
     }
 }
 
-fn first(args:Vec<Object>) -> Object
+fn first(args: Vec<Object>, _caller: &mut dyn FunctionCaller) -> Object
 {
     if args.len() != 1 {
         Object::Error(format!("wrong number of arguments: got = {}, want = 1",args.len()))
@@ -68,7 +155,7 @@ fn first(args:Vec<Object>) -> Object
     {
         if let Object::Array(content) = &args[0]
         {
-            content[0].as_ref().clone()
+            content.get(0).unwrap_or(Object::Null)
         }
         else
         {
@@ -77,7 +164,7 @@ fn first(args:Vec<Object>) -> Object
     }
 }
 
-fn last(args:Vec<Object>) -> Object
+fn last(args: Vec<Object>, _caller: &mut dyn FunctionCaller) -> Object
 {
     if args.len() != 1 {
         Object::Error(format!("wrong number of arguments: got = {}, want = 1",args.len()))
@@ -86,7 +173,7 @@ fn last(args:Vec<Object>) -> Object
     {
         if let Object::Array(content) = &args[0]
         {
-            content[content.len()-1].as_ref().clone()
+            content.len().checked_sub(1).and_then(|index| content.get(index)).unwrap_or(Object::Null)
         }
         else
         {
@@ -95,19 +182,15 @@ fn last(args:Vec<Object>) -> Object
     }
 }
 
-fn rest(args:Vec<Object>) -> Object {
+fn rest(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object {
     if args.len() != 1 {
         Object::Error(format!("wrong number of arguments: got = {}, want = 1",args.len()))
     }
     else {
         if let Object::Array(content) = &args[0]
         {
-            let mut result = Vec::new();
-            for i in 1..content.len()
-            {
-                result.push(content[i].clone());
-            }
-            Object::Array(result)
+            // The result shares the elements, so only the array itself is new.
+            allocated_bytes(Object::Array(content.rest()), std::mem::size_of::<Object>(), caller)
         }
         else
         {
@@ -116,7 +199,7 @@ fn rest(args:Vec<Object>) -> Object {
     }
 }
 
-fn push(args:Vec<Object>) -> Object
+fn push(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
 {
     if args.len() != 2
     {
@@ -125,9 +208,14 @@ fn push(args:Vec<Object>) -> Object
     else {
         if let Object::Array(content) = &args[0]
         {
-            let mut content = content.clone();
-            content.push(Box::new(args[1].clone()));
-            Object::Array(content)
+            let pushed = content.push(args[1].clone());
+            // Unless push had to copy the array, only the new element is new.
+            let bytes = if pushed.shares_elements(content) {
+                args[1].approximate_size()
+            } else {
+                Object::Array(pushed.clone()).approximate_size()
+            };
+            allocated_bytes(Object::Array(pushed), bytes, caller)
         }
         else
         {
@@ -136,7 +224,7 @@ fn push(args:Vec<Object>) -> Object
     }
 }
 
-fn puts(args:Vec<Object>) -> Object
+fn puts(args: Vec<Object>, _caller: &mut dyn FunctionCaller) -> Object
 {
     for arg in args {
         print!("{} ",arg.inspect())
@@ -144,3 +232,487 @@ fn puts(args:Vec<Object>) -> Object
     print!("\n");
     Object::Null
 }
+
+fn wrong_number_of_args(got: usize, want: usize) -> Object
+{
+    Object::Error(format!("wrong number of arguments: got = {}, want = {}", got, want))
+}
+
+fn unexpected_type(object: &Object, position: usize, expected: &str) -> Object
+{
+    Object::Error(format!("not suported type: {} in position {}, expected {}", object.get_type(), position, expected))
+}
+
+fn key_rank(key: &Object) -> u8
+{
+    match key {
+        Object::IntegerObject(_) => 0,
+        Object::BooleanObject(_) => 1,
+        _ => 2
+    }
+}
+
+// HashMap iteration order is random, so keys are sorted to keep the output of
+// keys, values and entries stable between runs and between engines.
+fn sorted_keys(map: &HashMap<Object, Object>) -> Vec<Object>
+{
+    let mut keys = map.keys().cloned().collect::<Vec<Object>>();
+    keys.sort_by(|a, b| {
+        match (a, b) {
+            (Object::IntegerObject(a), Object::IntegerObject(b)) => a.cmp(b),
+            (Object::BooleanObject(a), Object::BooleanObject(b)) => a.cmp(b),
+            (Object::StringObject(a), Object::StringObject(b)) => a.cmp(b),
+            _ => key_rank(a).cmp(&key_rank(b))
+        }
+    });
+    keys
+}
+
+fn keys(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 1 {
+        wrong_number_of_args(args.len(), 1)
+    }
+    else if let Object::HashMap(content) = &args[0] {
+        allocated(Object::Array(sorted_keys(content).into_iter().collect()), caller)
+    }
+    else {
+        unexpected_type(&args[0], 0, "HASH MAP")
+    }
+}
+
+fn values(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 1 {
+        wrong_number_of_args(args.len(), 1)
+    }
+    else if let Object::HashMap(content) = &args[0] {
+        allocated(Object::Array(sorted_keys(content).iter().map(|key| content[key].clone()).collect()), caller)
+    }
+    else {
+        unexpected_type(&args[0], 0, "HASH MAP")
+    }
+}
+
+fn entries(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 1 {
+        wrong_number_of_args(args.len(), 1)
+    }
+    else if let Object::HashMap(content) = &args[0] {
+        let pairs = sorted_keys(content).into_iter().map(|key| {
+            let value = content[&key].clone();
+            Object::from(vec![key, value])
+        });
+        allocated(Object::Array(pairs.collect()), caller)
+    }
+    else {
+        unexpected_type(&args[0], 0, "HASH MAP")
+    }
+}
+
+fn has(args: Vec<Object>, _caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 2 {
+        wrong_number_of_args(args.len(), 2)
+    }
+    else if let Object::HashMap(content) = &args[0] {
+        if !args[1].is_hashable() {
+            return Object::Error(format!("type {} is not hashable", args[1].get_type()));
+        }
+        Object::BooleanObject(content.contains_key(&args[1]))
+    }
+    else {
+        unexpected_type(&args[0], 0, "HASH MAP")
+    }
+}
+
+fn delete(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 2 {
+        wrong_number_of_args(args.len(), 2)
+    }
+    else if let Object::HashMap(content) = &args[0] {
+        if !args[1].is_hashable() {
+            return Object::Error(format!("type {} is not hashable", args[1].get_type()));
+        }
+        let mut content = content.clone();
+        content.remove(&args[1]);
+        allocated(Object::HashMap(content), caller)
+    }
+    else {
+        unexpected_type(&args[0], 0, "HASH MAP")
+    }
+}
+
+fn merge(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 2 {
+        return wrong_number_of_args(args.len(), 2);
+    }
+    match (&args[0], &args[1]) {
+        (Object::HashMap(first), Object::HashMap(second)) => {
+            let mut content = first.clone();
+            for (key, value) in second {
+                content.insert(key.clone(), value.clone());
+            }
+            allocated(Object::HashMap(content), caller)
+        }
+        (Object::HashMap(_), other) => {
+            unexpected_type(other, 1, "HASH MAP")
+        }
+        (other, _) => {
+            unexpected_type(other, 0, "HASH MAP")
+        }
+    }
+}
+
+// Charges a value built by a builtin to the run's memory budget.
+fn allocated(object: Object, caller: &mut dyn FunctionCaller) -> Object
+{
+    let bytes = object.approximate_size();
+    allocated_bytes(object, bytes, caller)
+}
+
+fn allocated_bytes(object: Object, bytes: usize, caller: &mut dyn FunctionCaller) -> Object
+{
+    if caller.allocate(bytes) {
+        object
+    }
+    else {
+        out_of_memory()
+    }
+}
+
+fn out_of_memory() -> Object
+{
+    Object::Error("out of memory".to_string())
+}
+
+fn string_arg(args: &[Object], position: usize) -> Result<String, Object>
+{
+    if let Object::StringObject(content) = &args[position] {
+        Ok(content.clone())
+    }
+    else {
+        Err(unexpected_type(&args[position], position, "STRING"))
+    }
+}
+
+fn integer_arg(args: &[Object], position: usize) -> Result<i64, Object>
+{
+    if let Object::IntegerObject(content) = &args[position] {
+        Ok(*content)
+    }
+    else {
+        Err(unexpected_type(&args[position], position, "INTEGER"))
+    }
+}
+
+fn string_array(strings: Vec<String>) -> Object
+{
+    Object::from(strings)
+}
+
+fn split(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 2 {
+        return wrong_number_of_args(args.len(), 2);
+    }
+    let content = match string_arg(&args, 0) { Ok(content) => content, Err(error) => return error };
+    let separator = match string_arg(&args, 1) { Ok(separator) => separator, Err(error) => return error };
+    if separator.is_empty() {
+        return allocated(string_array(content.chars().map(|c| c.to_string()).collect()), caller);
+    }
+    allocated(string_array(content.split(separator.as_str()).map(|part| part.to_string()).collect()), caller)
+}
+
+fn join(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 2 {
+        return wrong_number_of_args(args.len(), 2);
+    }
+    let separator = match string_arg(&args, 1) { Ok(separator) => separator, Err(error) => return error };
+    if let Object::Array(content) = &args[0] {
+        allocated(Object::StringObject(content.to_vec().iter().map(|element| element.inspect()).collect::<Vec<String>>().join(separator.as_str())), caller)
+    }
+    else {
+        unexpected_type(&args[0], 0, "ARRAY")
+    }
+}
+
+fn trim(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 1 {
+        return wrong_number_of_args(args.len(), 1);
+    }
+    match string_arg(&args, 0) {
+        Ok(content) => allocated(Object::StringObject(content.trim().to_string()), caller),
+        Err(error) => error
+    }
+}
+
+fn upper(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 1 {
+        return wrong_number_of_args(args.len(), 1);
+    }
+    match string_arg(&args, 0) {
+        Ok(content) => allocated(Object::StringObject(content.to_uppercase()), caller),
+        Err(error) => error
+    }
+}
+
+fn lower(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 1 {
+        return wrong_number_of_args(args.len(), 1);
+    }
+    match string_arg(&args, 0) {
+        Ok(content) => allocated(Object::StringObject(content.to_lowercase()), caller),
+        Err(error) => error
+    }
+}
+
+fn contains(args: Vec<Object>, _caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 2 {
+        return wrong_number_of_args(args.len(), 2);
+    }
+    let content = match string_arg(&args, 0) { Ok(content) => content, Err(error) => return error };
+    let pattern = match string_arg(&args, 1) { Ok(pattern) => pattern, Err(error) => return error };
+    Object::BooleanObject(content.contains(pattern.as_str()))
+}
+
+fn starts_with(args: Vec<Object>, _caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 2 {
+        return wrong_number_of_args(args.len(), 2);
+    }
+    let content = match string_arg(&args, 0) { Ok(content) => content, Err(error) => return error };
+    let prefix = match string_arg(&args, 1) { Ok(prefix) => prefix, Err(error) => return error };
+    Object::BooleanObject(content.starts_with(prefix.as_str()))
+}
+
+fn ends_with(args: Vec<Object>, _caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 2 {
+        return wrong_number_of_args(args.len(), 2);
+    }
+    let content = match string_arg(&args, 0) { Ok(content) => content, Err(error) => return error };
+    let suffix = match string_arg(&args, 1) { Ok(suffix) => suffix, Err(error) => return error };
+    Object::BooleanObject(content.ends_with(suffix.as_str()))
+}
+
+fn replace(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 3 {
+        return wrong_number_of_args(args.len(), 3);
+    }
+    let content = match string_arg(&args, 0) { Ok(content) => content, Err(error) => return error };
+    let from = match string_arg(&args, 1) { Ok(from) => from, Err(error) => return error };
+    let to = match string_arg(&args, 2) { Ok(to) => to, Err(error) => return error };
+    if from.is_empty() {
+        return allocated(Object::StringObject(content), caller);
+    }
+    // The result can be far larger than the input, so it is paid for before it is built.
+    let size = std::mem::size_of::<Object>() + content.len() + content.matches(from.as_str()).count() * to.len();
+    if !caller.allocate(size) {
+        return out_of_memory();
+    }
+    Object::StringObject(content.replace(from.as_str(), to.as_str()))
+}
+
+// substr(string, start) or substr(string, start, length), counted in characters.
+// Out of range bounds are clamped to the string instead of failing.
+fn substr(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 2 && args.len() != 3 {
+        return Object::Error(format!("wrong number of arguments: got = {}, want = 2 or 3", args.len()));
+    }
+    let content = match string_arg(&args, 0) { Ok(content) => content, Err(error) => return error };
+    let start = match integer_arg(&args, 1) { Ok(start) => start, Err(error) => return error };
+    let length = if args.len() == 3 {
+        match integer_arg(&args, 2) { Ok(length) => Some(length), Err(error) => return error }
+    }
+    else {
+        None
+    };
+    if start < 0 || length.is_some_and(|length| length < 0) {
+        let length = length.map(|length| format!(", length = {}", length)).unwrap_or_default();
+        return Object::Error(format!("substr bounds must not be negative: start = {}{}", start, length));
+    }
+    let length = length.map_or(usize::MAX, |length| length as usize);
+    allocated(Object::StringObject(content.chars().skip(start as usize).take(length).collect()), caller)
+}
+
+fn chars(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 1 {
+        return wrong_number_of_args(args.len(), 1);
+    }
+    match string_arg(&args, 0) {
+        Ok(content) => allocated(string_array(content.chars().map(|c| c.to_string()).collect()), caller),
+        Err(error) => error
+    }
+}
+
+fn index_of(args: Vec<Object>, _caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 2 {
+        return wrong_number_of_args(args.len(), 2);
+    }
+    let content = match string_arg(&args, 0) { Ok(content) => content, Err(error) => return error };
+    let pattern = match string_arg(&args, 1) { Ok(pattern) => pattern, Err(error) => return error };
+    match content.find(pattern.as_str()) {
+        Some(byte_index) => Object::IntegerObject(content[..byte_index].chars().count() as i64),
+        None => Object::IntegerObject(-1)
+    }
+}
+
+fn is_truthy(object: &Object) -> bool
+{
+    match object {
+        Object::BooleanObject(content) => *content,
+        Object::IntegerObject(content) => *content != 0,
+        Object::Null => false,
+        _ => true
+    }
+}
+
+fn array_arg(args: &[Object], position: usize) -> Result<Vec<Object>, Object>
+{
+    if let Object::Array(content) = &args[position] {
+        Ok(content.to_vec())
+    }
+    else {
+        Err(unexpected_type(&args[position], position, "ARRAY"))
+    }
+}
+
+fn map(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 2 {
+        return wrong_number_of_args(args.len(), 2);
+    }
+    let content = match array_arg(&args, 0) { Ok(content) => content, Err(error) => return error };
+    let mut result = Vec::new();
+    for element in content {
+        let mapped = caller.call_function(args[1].clone(), vec![element]);
+        if mapped.is_error() {
+            return mapped;
+        }
+        result.push(mapped);
+    }
+    allocated(Object::from(result), caller)
+}
+
+fn filter(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 2 {
+        return wrong_number_of_args(args.len(), 2);
+    }
+    let content = match array_arg(&args, 0) { Ok(content) => content, Err(error) => return error };
+    let mut result = Vec::new();
+    for element in content {
+        let keep = caller.call_function(args[1].clone(), vec![element.clone()]);
+        if keep.is_error() {
+            return keep;
+        }
+        if is_truthy(&keep) {
+            result.push(element);
+        }
+    }
+    allocated(Object::from(result), caller)
+}
+
+fn reduce(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 3 {
+        return wrong_number_of_args(args.len(), 3);
+    }
+    let content = match array_arg(&args, 0) { Ok(content) => content, Err(error) => return error };
+    let mut accumulator = args[1].clone();
+    for element in content {
+        accumulator = caller.call_function(args[2].clone(), vec![accumulator, element]);
+        if accumulator.is_error() {
+            return accumulator;
+        }
+    }
+    accumulator
+}
+
+fn find(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 2 {
+        return wrong_number_of_args(args.len(), 2);
+    }
+    let content = match array_arg(&args, 0) { Ok(content) => content, Err(error) => return error };
+    for element in content {
+        let found = caller.call_function(args[1].clone(), vec![element.clone()]);
+        if found.is_error() {
+            return found;
+        }
+        if is_truthy(&found) {
+            return element;
+        }
+    }
+    Object::Null
+}
+
+fn compare(comparator: &Object, a: &Object, b: &Object, caller: &mut dyn FunctionCaller) -> Result<bool, Object>
+{
+    match caller.call_function(comparator.clone(), vec![a.clone(), b.clone()]) {
+        Object::IntegerObject(order) => Ok(order > 0),
+        Object::Error(content) => Err(Object::Error(content)),
+        other => Err(Object::Error(format!("sort_by comparator must return INTEGER, got {}", other.get_type())))
+    }
+}
+
+// sort_by(array, fn(a, b) { ... }) where the comparator returns a negative
+// number, zero or a positive number. A hand written merge sort is used because
+// the comparator may fail, and std's sort may panic on inconsistent orderings.
+fn sort_by(args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 2 {
+        return wrong_number_of_args(args.len(), 2);
+    }
+    let mut content = match array_arg(&args, 0) { Ok(content) => content, Err(error) => return error };
+    let mut width = 1;
+    while width < content.len() {
+        let mut merged = Vec::with_capacity(content.len());
+        let mut start = 0;
+        while start < content.len() {
+            let middle = usize::min(start + width, content.len());
+            let end = usize::min(start + 2 * width, content.len());
+            let (mut left, mut right) = (start, middle);
+            while left < middle && right < end {
+                match compare(&args[1], &content[left], &content[right], caller) {
+                    Ok(true) => {
+                        merged.push(content[right].clone());
+                        right += 1;
+                    }
+                    Ok(false) => {
+                        merged.push(content[left].clone());
+                        left += 1;
+                    }
+                    Err(error) => return error
+                }
+            }
+            merged.extend_from_slice(&content[left..middle]);
+            merged.extend_from_slice(&content[right..end]);
+            start = end;
+        }
+        content = merged;
+        width *= 2;
+    }
+    allocated(Object::from(content), caller)
+}
+
+fn error(args: Vec<Object>, _caller: &mut dyn FunctionCaller) -> Object
+{
+    if args.len() != 1 {
+        return wrong_number_of_args(args.len(), 1);
+    }
+    Object::Error(args[0].inspect())
+}
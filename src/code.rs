@@ -1,13 +1,16 @@
 use std::fmt::{Display,Formatter};
-use std::io::Cursor;
 use byteorder;
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, WriteBytesExt};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
+use crate::token::Span;
 
 pub type Byte = u8;
 
-#[derive(Clone, Debug, PartialEq)]
+// The longest instruction: OpWide, the opcode and two four byte operands.
+pub const MAX_INSTRUCTION_WIDTH: usize = 10;
+
+#[derive(Clone, Debug, PartialEq, Default)]
 pub struct Instructions {
     pub content: Vec<Byte>
 }
@@ -18,55 +21,143 @@ impl Instructions {
             content: Vec::new()
         }
     }
+
+    // Reads the instruction starting at offset: its opcode, operands and width
+    // in bytes. An OpWide prefix is folded into the instruction it widens. None
+    // past the end or on an unknown opcode.
+    pub fn decode(&self, offset: usize) -> Option<(Opcode, Vec<usize>, usize)>
+    {
+        let mut code = Opcode::from_u8(*self.content.get(offset)?)?;
+        let mut width = 1;
+        let wide = code == Opcode::OpWide;
+        if wide {
+            code = Opcode::from_u8(*self.content.get(offset + 1)?)?;
+            width += 1;
+        }
+        let def = look_up(&code)?;
+        let mut operands = Vec::new();
+        for operand_width in def.operand_withs
+        {
+            let operand_width = if wide { 4 } else { operand_width as usize };
+            let bytes = self.content.get(offset + width..offset + width + operand_width)?;
+            operands.push(bytes.iter().fold(0, |operand, byte| operand << 8 | *byte as usize));
+            width += operand_width;
+        }
+        Some((code, operands, width))
+    }
+
+    // Disassembles the single instruction starting at offset, returning its text
+    // and its width in bytes.
+    pub fn instruction_at(&self, offset: usize) -> Option<(String, usize)>
+    {
+        let (code, operands, width) = self.decode(offset)?;
+        let mut output = look_up(&code)?.name;
+        if self.content[offset] == Opcode::OpWide as u8 {
+            output = format!("OpWide {}", output);
+        }
+        for operand in operands
+        {
+            output += format!(" {}", operand).as_str();
+        }
+        Some((output, width))
+    }
+}
+
+// Maps instruction offsets back to the source they were compiled from. The
+// compiler adds an entry at the first instruction of every statement, and
+// another where a statement carries on after a nested block, in increasing
+// offset order, so an instruction belongs to the last entry at or before it.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct SourceMap {
+    entries: Vec<SourceMapEntry>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SourceMapEntry {
+    pub offset: usize,
+    pub span: Span,
+    // False for the entries that resume a statement after a nested block.
+    pub statement_start: bool
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap { entries: Vec::new() }
+    }
+
+    pub fn add(&mut self, offset: usize, span: Span) {
+        self.push(SourceMapEntry { offset, span, statement_start: true });
+    }
+
+    // Points the code from offset on back at span, a statement that was
+    // interrupted by the statements of a nested block.
+    pub fn resume(&mut self, offset: usize, span: Span) {
+        if self.lookup(offset) != Some(span) {
+            self.push(SourceMapEntry { offset, span, statement_start: false });
+        }
+    }
+
+    // An entry that covers no code is replaced by the next one at its offset.
+    fn push(&mut self, entry: SourceMapEntry) {
+        match self.entries.last_mut() {
+            Some(last) if last.offset == entry.offset => *last = entry,
+            _ => self.entries.push(entry)
+        }
+    }
+
+    // Forgets the entries for code from offset on, after the compiler drops it.
+    pub fn truncate(&mut self, offset: usize) {
+        self.entries.retain(|entry| entry.offset < offset);
+    }
+
+    pub fn lookup(&self, offset: usize) -> Option<Span> {
+        let index = self.entries.partition_point(|entry| entry.offset <= offset);
+        index.checked_sub(1).map(|index| self.entries[index].span)
+    }
+
+    // The span of the statement whose code starts exactly at offset.
+    pub fn starting_at(&self, offset: usize) -> Option<Span> {
+        self.entries
+            .binary_search_by_key(&offset, |entry| entry.offset)
+            .ok()
+            .map(|index| self.entries[index])
+            .filter(|entry| entry.statement_start)
+            .map(|entry| entry.span)
+    }
+
+    pub fn entries(&self) -> &[SourceMapEntry] {
+        &self.entries
+    }
+
+    // Moves every entry to new_offset(entry.offset), for code that has been
+    // rewritten. new_offset must never decrease as its argument grows.
+    pub fn remap(&self, new_offset: impl Fn(usize) -> usize) -> SourceMap {
+        let mut remapped = SourceMap::new();
+        for entry in &self.entries {
+            remapped.push(SourceMapEntry { offset: new_offset(entry.offset), ..*entry });
+        }
+        remapped
+    }
 }
 
 impl Display for Instructions
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let mut len = 0;
-        let mut output = String::new();
-        let mut cursor = Cursor::new(self.content.clone());
-        while cursor.position() < (self.content.len()) as u64
+        let mut offset = 0;
+        while offset < self.content.len()
         {
-            let code_value = cursor.read_u8().unwrap();
-            let code = Opcode::from_u8(code_value);
-            output += format!("{:0>4} ", len).as_str();
-            len += 1;
-            if let None = code {
-                output += format!("Opcode not supported {}", code_value).as_str();
-                continue;
-            }
-            let code = code.unwrap();
-            let def = look_up(&code);
-            if let Some(def) = def
-            {
-                output += def.name.as_str();
-                output += " ";
-                for i in def.operand_withs
-                {
-                    match i
-                    {
-                        1 => {
-                            output += format!("{} ", cursor.read_u8().unwrap()).as_str();
-                            len += 1;
-                        },
-                        2 => {
-                            output += format!("{} ", cursor.read_u16::<BigEndian>().unwrap()).as_str();
-                            len += 2;
-                        },
-                        _ => {
-                            output += format!("Error operand with not supported {}", i).as_str();
-                        }
-                    }
+            match self.instruction_at(offset) {
+                Some((text, width)) => {
+                    writeln!(f, "{:0>4} {}", offset, text)?;
+                    offset += width;
+                }
+                None => {
+                    writeln!(f, "{:0>4} Opcode not supported {}", offset, self.content[offset])?;
+                    offset += 1;
                 }
-                output.pop();
-                output += "\n";
-            }
-            else {
-                output += format!("Opcode not supported {}", code as u8).as_str();
             }
         }
-        write!(f, "{}", output)
+        Ok(())
     }
 
 }
@@ -101,7 +192,21 @@ pub enum Opcode {
     OpGetLocal,
     OpGetBuiltin,
     OpClosure,
-    OpGetFree
+    OpGetFree,
+    OpTry,
+    OpEndTry,
+    // OpCall whose result the calling function returns as is. The callee
+    // takes over the caller's frame.
+    OpTailCall,
+    // Superinstructions, chosen by the optimizer for common sequences:
+    // OpGetLocal; OpConstant; OpAdd or OpSub,
+    OpAddLocalConstant,
+    OpSubLocalConstant,
+    // and OpEq or OpGreaterThan; OpJumpNotTrue.
+    OpJumpNotEq,
+    OpJumpNotGreater,
+    // Prefix: the operands of the next instruction are four bytes wide.
+    OpWide
 }
 
 pub struct Definition {
@@ -198,6 +303,30 @@ pub fn look_up(code: &Opcode) -> Option<Definition>
         },
         Opcode::OpGetFree => {
             Some(Definition{name:"OpGetFree".to_string(), operand_withs: vec![1]})
+        },
+        Opcode::OpTry => {
+            Some(Definition{name:"OpTry".to_string(), operand_withs: vec![2]})
+        },
+        Opcode::OpEndTry => {
+            Some(Definition{name:"OpEndTry".to_string(), operand_withs: vec![]})
+        }
+        Opcode::OpTailCall => {
+            Some(Definition{name:"OpTailCall".to_string(), operand_withs: vec![1]})
+        }
+        Opcode::OpAddLocalConstant => {
+            Some(Definition{name:"OpAddLocalConstant".to_string(), operand_withs: vec![1, 2]})
+        }
+        Opcode::OpSubLocalConstant => {
+            Some(Definition{name:"OpSubLocalConstant".to_string(), operand_withs: vec![1, 2]})
+        }
+        Opcode::OpJumpNotEq => {
+            Some(Definition{name:"OpJumpNotEq".to_string(), operand_withs: vec![2]})
+        }
+        Opcode::OpJumpNotGreater => {
+            Some(Definition{name:"OpJumpNotGreater".to_string(), operand_withs: vec![2]})
+        }
+        Opcode::OpWide => {
+            Some(Definition{name:"OpWide".to_string(), operand_withs: vec![]})
         }
         _ => {
             None
@@ -206,33 +335,44 @@ pub fn look_up(code: &Opcode) -> Option<Definition>
 }
 
 
+// Encodes an instruction. When an operand doesn't fit its usual width every
+// operand is written as four bytes behind an OpWide prefix. None for an unknown
+// opcode or an operand that doesn't fit in four bytes either.
 pub fn make(code: Opcode, operands: Vec<usize>) -> Option<Instructions>
 {
+    let content = look_up(&code)?;
+    let fits = |wide: bool| operands.iter().zip(&content.operand_withs).all(|(operand, width)| {
+        let width = if wide { 4 } else { *width };
+        (*operand as u64) < 1 << (8 * width)
+    });
+    let wide = !fits(false);
+    if wide && !fits(true) {
+        return None;
+    }
     let mut instructions = Vec::new();
+    if wide {
+        instructions.push(Opcode::OpWide as u8);
+    }
     instructions.push(code.clone() as u8);
-    if let Some(content) = look_up(&code)
+    for (i,o) in content.operand_withs.clone().into_iter().zip(0..content.operand_withs.len())
     {
-        for (i,o) in content.operand_withs.clone().into_iter().zip(0..content.operand_withs.len())
-        {
-            match i {
-                2 => {
-                    instructions.write_u16::<BigEndian>(operands[o] as u16).unwrap();
-                },
-                1 => {
-                    instructions.write_u8(operands[o] as u8).unwrap();
-                }
-                _ => {
-                    panic!("unsupported operand width: {}", i);
-                }
+        match (wide, i) {
+            (true, _) => {
+                instructions.write_u32::<BigEndian>(operands[o] as u32).unwrap();
+            }
+            (false, 2) => {
+                instructions.write_u16::<BigEndian>(operands[o] as u16).unwrap();
+            },
+            (false, 1) => {
+                instructions.write_u8(operands[o] as u8).unwrap();
+            }
+            _ => {
+                panic!("unsupported operand width: {}", i);
             }
-
         }
-        Some(Instructions {content:instructions})
-    }
-    else
-    {
-        None
+
     }
+    Some(Instructions {content:instructions})
 }
 
 pub fn join_instructions(instructions: Vec<Instructions>) -> Instructions {
@@ -1,18 +1,51 @@
+use std::fmt::{Display, Formatter};
 use std::vec;
 
-use num_traits::FromPrimitive;
 use crate::ast::{Expression, Node, Statement};
-use crate::code::{Instructions, make, Opcode};
+use crate::code::{Instructions, look_up, make, Opcode, SourceMap};
 use crate::code::Opcode::{OpAdd, OpArray, OpBang, OpConstant, OpDiv, OpEq, OpFalse, OpGetGlobal, OpGreaterThan, OpHash, OpIndex, OpJump, OpJumpNotTrue, OpMinus, OpMul, OpNotEq, OpNull, OpCall, OpPop, OpSetGlobal, OpSub, OpTrue};
 use crate::object::{CompiledFunctionStruct, Object};
 use crate::symbol_table::{self, SymbolScope, SymbolTable};
-use crate::token::Token;
-use crate::builtins::BUILT_INS;
+use crate::token::{Span, Token};
+use crate::builtins::Builtins;
+use crate::optimizer::{fold_constant, mark_tail_calls, optimize, widen_jumps};
+use crate::diagnostic::{Diagnostic, UNDEFINED_VARIABLE, UNSUPPORTED};
+use std::collections::HashMap;
+
+// Why a program couldn't be compiled, and the statement or identifier at fault.
+#[derive(PartialEq, Clone, Debug)]
+pub struct CompileError {
+    pub message: String,
+    pub code: &'static str,
+    pub span: Option<Span>,
+    pub help: Option<String>
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<&CompileError> for Diagnostic {
+    fn from(error: &CompileError) -> Self {
+        let diagnostic = Diagnostic::error(error.code, &error.message).with_span(error.span);
+        match &error.help {
+            Some(help) => diagnostic.with_help(help),
+            None => diagnostic
+        }
+    }
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub struct CompilationScope {
     pub instructions: Instructions,
     pub last_instruction: Option<EmittedInstruction>,
-    pub prev_instruction: Option<EmittedInstruction>
+    pub prev_instruction: Option<EmittedInstruction>,
+    pub source_map: SourceMap,
+    // Jumps whose target only fits a wide operand, as (offset, target). They
+    // are patched when the scope's code is finished, see finish_scope.
+    pub long_jumps: Vec<(usize, usize)>
 }
 
 #[derive(PartialEq, Clone, Debug)]
@@ -25,40 +58,54 @@ pub struct Compiler {
     pub constants: Vec<Object>,
     pub scopes: Vec<CompilationScope>,
     pub scope_index: usize,
-    pub symbol_table: SymbolTable
+    pub symbol_table: SymbolTable,
+    pub builtins: Builtins,
+    // Fold constant expressions, share equal constants and run the optimizer
+    // over the finished code. On by default.
+    pub optimize: bool,
+    // The statements being compiled, innermost last, with their scope index.
+    statements: Vec<(usize, Span)>,
+    // Where each integer and string constant is, for optimize. Only the first
+    // indexed_constants constants have been looked at so far.
+    constant_index: HashMap<Object, usize>,
+    indexed_constants: usize,
+    // Set by emit for an instruction whose operands can't be encoded, and
+    // returned by compile.
+    encoding_error: Option<CompileError>
 }
 
 
 impl Compiler {
     pub fn new() -> Self {
+        Compiler::new_with_builtins(Builtins::new())
+    }
+
+    pub fn new_with_builtins(builtins: Builtins) -> Self {
         let mut symbol_table = SymbolTable::new();
-        for (builtin, i) in BUILT_INS.iter().zip(0..BUILT_INS.len()) {
-            symbol_table.define_builtin(i, builtin.to_string());
-        }
-        Compiler {
-            constants: Vec::new(),
-            scopes: vec![CompilationScope{
-                instructions: Instructions::new(),
-                prev_instruction: None,
-                last_instruction: None
-            }],
-            scope_index: 0,
-            symbol_table: symbol_table
-        }
+        builtins.define_in(&mut symbol_table);
+        Compiler::new_with_state(Vec::new(), symbol_table, builtins)
     }
 
-    pub fn new_with_state(constants: Vec<Object>, symbol_table: SymbolTable) -> Self {
+    pub fn new_with_state(constants: Vec<Object>, symbol_table: SymbolTable, builtins: Builtins) -> Self {
         Compiler {
             constants,
             scopes: vec![
                 CompilationScope{
                     instructions: Instructions::new(),
                     prev_instruction: None,
-                    last_instruction: None
+                    last_instruction: None,
+                    source_map: SourceMap::new(),
+                    long_jumps: Vec::new()
                 }
             ],
             scope_index: 0,
-            symbol_table
+            symbol_table,
+            builtins,
+            optimize: true,
+            statements: Vec::new(),
+            constant_index: HashMap::new(),
+            indexed_constants: 0,
+            encoding_error: None
         }
     }
 
@@ -67,32 +114,33 @@ impl Compiler {
         self.scopes[self.scope_index].instructions.clone()
     }
 
-    pub fn compile(& mut self, node: Node)
+    pub fn compile(& mut self, node: Node) -> Result<(), CompileError>
     {
         match node {
             Node::Program(prog) =>
                 {
                     for stmt in prog.statements
                     {
-                        self.compile(Node::Statement(stmt))
+                        self.compile(Node::Statement(stmt))?;
                     }
                 },
             Node::StatementBlock(stmt_block) => {
                 for stmt in stmt_block
                 {
-                    self.compile(Node::Statement(stmt))
+                    self.compile(Node::Statement(stmt))?;
                 }
             }
             Node::Statement(stmt) =>
                 {
-                    self.compile_stmt(stmt)
+                    self.compile_stmt(stmt)?;
                 },
             Node::Expression(expr) => {
-                    self.compile_expr(&expr)
-                },
-            _ => {
-                panic!("Node not supported")
-            }
+                    self.compile_expr(&expr)?;
+                }
+        }
+        match self.encoding_error.take() {
+            Some(error) => Err(error),
+            None => Ok(())
         }
     }
 
@@ -119,7 +167,15 @@ impl Compiler {
 
     pub fn emit(& mut self, operation: Opcode,operands: Vec<usize>) -> usize
     {
-        let mut instruction = make(operation.clone(), operands).expect("couldn't make instruction");
+        let mut instruction = match make(operation.clone(), operands.clone()) {
+            Some(instruction) => instruction,
+            None => {
+                if self.encoding_error.is_none() {
+                    self.encoding_error = Some(self.error(UNSUPPORTED, encoding_error(&operation, &operands)));
+                }
+                Instructions::new()
+            }
+        };
         let pos = self.add_instructions(& mut instruction);
         self.set_last_instruction(operation, pos);
         pos
@@ -127,25 +183,69 @@ impl Compiler {
 
     fn add_instructions(&mut self, instruction: & mut Instructions) -> usize
     {
-        let pos = self.get_current_instructions().content.len();
-        let mut updated = self.get_current_instructions();
-        updated.content.append(&mut instruction.content);
-        self.scopes[self.scope_index].instructions = updated;
+        let content = &mut self.scopes[self.scope_index].instructions.content;
+        let pos = content.len();
+        content.append(&mut instruction.content);
         pos
     }
 
     fn add_constant(&mut self, constant: Object) -> usize
     {
+        if self.optimize && matches!(constant, Object::IntegerObject(_) | Object::StringObject(_)) {
+            // Constants can come from an earlier compilation, so catch up on
+            // any added since the last lookup.
+            for (index, known) in self.constants.iter().enumerate().skip(self.indexed_constants) {
+                if matches!(known, Object::IntegerObject(_) | Object::StringObject(_)) {
+                    self.constant_index.entry(known.clone()).or_insert(index);
+                }
+            }
+            self.indexed_constants = self.constants.len();
+            if let Some(index) = self.constant_index.get(&constant) {
+                return *index;
+            }
+        }
         self.constants.push(constant);
         self.constants.len() - 1
     }
 
-    fn compile_stmt(& mut self, stmt: Statement)
+    fn compile_stmt(& mut self, stmt: Statement) -> Result<(), CompileError>
+    {
+        let span = stmt.span();
+        let offset = self.scopes[self.scope_index].instructions.content.len();
+        self.scopes[self.scope_index].source_map.add(offset, span);
+        self.statements.push((self.scope_index, span));
+        let result = self.compile_stmt_body(stmt);
+        self.statements.pop();
+        result?;
+        self.resume_statement();
+        Ok(())
+    }
+
+    // Points the code that follows a nested block back at the statement that
+    // contains it, e.g. the OpSetGlobal of a let whose value is an if.
+    fn resume_statement(&mut self)
+    {
+        if let Some((scope, span)) = self.statements.last().copied() {
+            if scope == self.scope_index {
+                let offset = self.scopes[scope].instructions.content.len();
+                self.scopes[scope].source_map.resume(offset, span);
+            }
+        }
+    }
+
+    fn compile_stmt_body(& mut self, stmt: Statement) -> Result<(), CompileError>
     {
         match stmt
         {
-            Statement::LetStatement(id, expr) => {
-                self.compile_expr(&expr);
+            Statement::LetStatement(id, expr, _) => {
+                self.compile_expr(&expr)?;
+                // Functions remember the name they were bound to, for debuggers
+                // and stack traces.
+                if let Expression::FnExpression(_) = &expr {
+                    if let Some(Object::CompiledFunction(function)) = self.constants.last_mut() {
+                        function.name = Some(id.id.clone());
+                    }
+                }
                 let symbol = self.symbol_table.define(id.id);
 
                 if SymbolScope::Global == symbol.scope
@@ -158,20 +258,18 @@ impl Compiler {
                 }
                 
             }
-            Statement::ExpressionStatement(expr) =>
+            Statement::ExpressionStatement(expr, _) =>
                 {
-                    self.compile_expr(&expr);
+                    self.compile_expr(&expr)?;
                     self.emit(OpPop, vec![]);
                 },
-            Statement::ReturnStatement(expr) =>
+            Statement::ReturnStatement(expr, _) =>
             {
-                self.compile_expr(&expr);
+                self.compile_expr(&expr)?;
                 self.emit(Opcode::OpReturnValue, vec![]);
             }
-            _ => {
-                panic!("Statement not supported");
-            }
         }
+        Ok(())
     }
 
 
@@ -187,17 +285,46 @@ impl Compiler {
 
     fn change_operand(&mut self, pos: usize, operand: usize)
     {
-        let op = Opcode::from_u8(self.get_current_instructions().content[pos]).expect("Couldn't read opcode");
-        let instruction = make(op, vec![operand]).expect("Couldn't form instruction");
+        let (op, _, width) = self.scopes[self.scope_index].instructions.decode(pos).expect("Couldn't read opcode");
+        match make(op.clone(), vec![operand]) {
+            Some(instruction) if instruction.content.len() == width => self.replace_instruction(pos, instruction),
+            // Widening the jump here would move the code after it, and with it
+            // the targets of the jumps already patched.
+            Some(_) => self.scopes[self.scope_index].long_jumps.push((pos, operand)),
+            None => {
+                if self.encoding_error.is_none() {
+                    self.encoding_error = Some(self.error(UNSUPPORTED, encoding_error(&op, &[operand])));
+                }
+            }
+        }
+    }
 
-        self.replace_instruction(pos, instruction);
+    // The code of the current scope with its long jumps patched, optimized if
+    // enabled and its tail calls marked, with its source map.
+    fn finish_scope(&self) -> (Instructions, SourceMap)
+    {
+        let scope = &self.scopes[self.scope_index];
+        let (mut instructions, mut source_map) = match scope.long_jumps.is_empty() {
+            true => (scope.instructions.clone(), scope.source_map.clone()),
+            false => widen_jumps(&scope.instructions, &scope.source_map, &scope.long_jumps)
+        };
+        if self.optimize {
+            (instructions, source_map) = optimize(&instructions, &source_map);
+        }
+        // The main program has no caller to return to.
+        if self.scope_index > 0 {
+            instructions = mark_tail_calls(&instructions);
+        }
+        (instructions, source_map)
     }
     
     pub fn enter_scope(& mut self) {
         let scope = CompilationScope {
             instructions: Instructions::new(),
             last_instruction: None,
-            prev_instruction: None
+            prev_instruction: None,
+            source_map: SourceMap::new(),
+            long_jumps: Vec::new()
         };
 
         self.symbol_table = SymbolTable::new_enclosed(self.symbol_table.clone());
@@ -224,19 +351,63 @@ impl Compiler {
             index: last_pos
         })
     }
-    fn compile_expr(& mut self, expr: &Expression)
+
+    // Drops the OpPop of a block's trailing expression so its value stays on
+    // the stack as the value of the block.
+    fn remove_last_pop(&mut self)
+    {
+        self.scopes[self.scope_index].instructions.content.pop();
+        let len = self.scopes[self.scope_index].instructions.content.len();
+        self.scopes[self.scope_index].source_map.truncate(len);
+        self.resume_statement();
+    }
+
+    // Compiles a block so that it leaves exactly one value on the stack: the
+    // value of its trailing expression statement, or Null otherwise.
+    fn compile_block_value(&mut self, block: Vec<Statement>) -> Result<(), CompileError>
+    {
+        let ends_with_expression = matches!(block.last(), Some(Statement::ExpressionStatement(..)));
+        self.compile(Node::StatementBlock(block))?;
+        if ends_with_expression && self.is_last_instruction_pop() {
+            self.remove_last_pop();
+        }
+        else {
+            self.emit(OpNull, vec![]);
+        }
+        Ok(())
+    }
+
+    fn compile_expr(& mut self, expr: &Expression) -> Result<(), CompileError>
     {
+        if self.optimize {
+            match fold_constant(expr) {
+                Some(Object::BooleanObject(true)) => {
+                    self.emit(OpTrue, vec![]);
+                    return Ok(());
+                }
+                Some(Object::BooleanObject(false)) => {
+                    self.emit(OpFalse, vec![]);
+                    return Ok(());
+                }
+                Some(constant) => {
+                    let constant_id = self.add_constant(constant);
+                    self.emit(OpConstant, vec![constant_id]);
+                    return Ok(());
+                }
+                None => {}
+            }
+        }
         match expr
         {
             Expression::InfixExpression(left, operator, right) =>
                 {
                     if operator.clone() == Token::LT {
-                        self.compile_expr(right.as_ref());
-                        self.compile_expr(left.as_ref());
+                        self.compile_expr(right.as_ref())?;
+                        self.compile_expr(left.as_ref())?;
                     }
                     else {
-                        self.compile_expr(left.as_ref());
-                        self.compile_expr(right.as_ref());
+                        self.compile_expr(left.as_ref())?;
+                        self.compile_expr(right.as_ref())?;
                     }
 
                     match operator
@@ -267,16 +438,16 @@ impl Compiler {
                             self.emit(OpGreaterThan, vec![]);
                         }
                         _ => {
-                            panic!("operator not suported {}", operator.inspect())
+                            return Err(self.error(UNSUPPORTED, format!("operator not suported {}", operator.inspect())));
                         }
                     }
                 },
             Expression::IfExpression(content) => {
-                self.compile_expr(content.condition.as_ref());
+                self.compile_expr(content.condition.as_ref())?;
                 let jump_not_true_pos = self.emit(OpJumpNotTrue, vec![9999]);
-                self.compile(Node::StatementBlock(content.consequence.clone()));
+                self.compile(Node::StatementBlock(content.consequence.clone()))?;
                 if self.is_last_instruction_pop() {
-                    self.scopes[self.scope_index].instructions.content.pop();
+                    self.remove_last_pop();
                 }
 
                 let jump_pos = self.emit(OpJump, vec![9999]);
@@ -285,10 +456,10 @@ impl Compiler {
 
                 if let Some(content) = content.alternative.clone()
                 {
-                    self.compile(Node::StatementBlock(content));
+                    self.compile(Node::StatementBlock(content))?;
 
                     if self.is_last_instruction_pop(){
-                        self.scopes[self.scope_index].instructions.content.pop();
+                        self.remove_last_pop();
                     }
                 }
                 else {
@@ -306,10 +477,10 @@ impl Compiler {
                 },
             Expression::CallExpression(content) => 
                 {
-                    self.compile_expr(&content.function);
+                    self.compile_expr(&content.function)?;
                     for arg in &content.args
                     {
-                        self.compile_expr(arg);
+                        self.compile_expr(arg)?;
                     }
                     self.emit(OpCall, vec![content.args.len()]);
                 }
@@ -325,7 +496,7 @@ impl Compiler {
                 {
                     self.symbol_table.define(param.get_id());
                 }
-                self.compile(Node::StatementBlock(content.body.clone()));
+                self.compile(Node::StatementBlock(content.body.clone()))?;
                 
                 if self.is_last_instruction_pop() {
                     self.replace_pop_with_return();
@@ -335,9 +506,15 @@ impl Compiler {
                     self.emit(Opcode::OpReturn, vec![]);
                 }
                 let num_vars = self.symbol_table.num_definitions;
-                let instructions = self.leave_scope();
+                let local_names = self.symbol_table.symbols()
+                    .into_iter()
+                    .filter(|symbol| symbol.scope == SymbolScope::Local)
+                    .map(|symbol| symbol.name)
+                    .collect();
+                let (instructions, source_map) = self.finish_scope();
+                self.leave_scope();
                 
-                let constant = Object::CompiledFunction(CompiledFunctionStruct{instructions: instructions, num_vars, num_args: content.params.len()});
+                let constant = Object::CompiledFunction(CompiledFunctionStruct{instructions, num_vars, num_args: content.params.len(), name: None, local_names, source_map});
                 let pos = self.add_constant(constant);
 
                 self.emit(Opcode::OpClosure, vec![pos,0]);
@@ -358,7 +535,7 @@ impl Compiler {
                 },
             Expression::PrefixExpression(operator, inner_expr) =>
                 {
-                    self.compile_expr(inner_expr.as_ref());
+                    self.compile_expr(inner_expr.as_ref())?;
                     match operator {
                         Token::BANG => {
                             self.emit(OpBang, vec![]);
@@ -367,34 +544,62 @@ impl Compiler {
                             self.emit(OpMinus, vec![]);
                         }
                         _ => {
-                            panic!("Operator {} not supported", operator.inspect());
+                            return Err(self.error(UNSUPPORTED, format!("Operator {} not supported", operator.inspect())));
                         }
                     }
             },
             Expression::ArrayLiteral(content) => {
                 for expr in &content.elements
                 {
-                    self.compile_expr(expr)
+                    self.compile_expr(expr)?;
                 }
 
                 self.emit(OpArray, vec![content.elements.len()]);
             },
             Expression::HashExpression(content) => {
                 for (key, value) in &content.pairs{
-                    self.compile_expr(key);
-                    self.compile_expr(value);
+                    self.compile_expr(key)?;
+                    self.compile_expr(value)?;
                 }
 
                 self.emit(OpHash, vec![content.pairs.len()]);
             },
             Expression::IndexExpression(content) => {
-                self.compile_expr(content.left.as_ref());
-                self.compile_expr(content.index.as_ref());
+                self.compile_expr(content.left.as_ref())?;
+                self.compile_expr(content.index.as_ref())?;
 
                 self.emit(OpIndex, vec![]);
             }
+            Expression::TryExpression(content) => {
+                let try_pos = self.emit(Opcode::OpTry, vec![9999]);
+                self.compile_block_value(content.body.clone())?;
+                self.emit(Opcode::OpEndTry, vec![]);
+                let jump_pos = self.emit(OpJump, vec![9999]);
+
+                let catch_pos = self.get_current_instructions().content.len();
+                self.change_operand(try_pos, catch_pos);
+                let symbol = self.symbol_table.define(content.error.get_id());
+                if symbol.scope == SymbolScope::Global {
+                    self.emit(OpSetGlobal, vec![symbol.index]);
+                }
+                else {
+                    self.emit(Opcode::OpSetLocal, vec![symbol.index]);
+                }
+                self.compile_block_value(content.handler.clone())?;
+
+                let after_catch_pos = self.get_current_instructions().content.len();
+                self.change_operand(jump_pos, after_catch_pos);
+            }
             Expression::IdentifierExpression(id) => {
-                let symbol = self.symbol_table.resolve(id.id.clone()).expect(format!("undefined variable {}", id.id).as_str());
+                let symbol = match self.symbol_table.resolve(id.id.clone()) {
+                    Some(symbol) => symbol,
+                    None => {
+                        let mut error = self.error(UNDEFINED_VARIABLE, format!("undefined variable {}", id.id));
+                        error.span = Some(id.span);
+                        error.help = self.similar_name(&id.id).map(|name| format!("did you mean `{}`?", name));
+                        return Err(error);
+                    }
+                };
                 if symbol.scope == SymbolScope::Global {
                     self.emit(OpGetGlobal, vec![symbol.index]);
                 }
@@ -406,23 +611,79 @@ impl Compiler {
                 }
             }
             _=> {
-                panic!("Expression not supported");
+                return Err(self.error(UNSUPPORTED, format!("Expression not supported: {}", expr.to_string())));
             }
         }
+        Ok(())
+    }
+
+    // An error at the statement being compiled.
+    fn error(&self, code: &'static str, message: String) -> CompileError
+    {
+        CompileError {
+            message,
+            code,
+            span: self.statements.last().map(|(_, span)| *span),
+            help: None
+        }
+    }
+
+    // The visible name closest to a misspelt one, if any is close enough.
+    fn similar_name(&self, name: &str) -> Option<String>
+    {
+        let mut names = Vec::new();
+        let mut table = Some(&self.symbol_table);
+        while let Some(content) = table {
+            names.extend(content.symbols().into_iter().map(|symbol| symbol.name));
+            table = content.outer.as_deref();
+        }
+        names.into_iter()
+            .map(|candidate| (edit_distance(name, &candidate), candidate))
+            .filter(|(distance, _)| *distance <= (name.chars().count() / 3).max(1))
+            .min()
+            .map(|(_, candidate)| candidate)
     }
 
     pub fn get_bytecode(&self) -> ByteCode
     {
+        let (instructions, source_map) = self.finish_scope();
         ByteCode {
-            instructions: self.get_current_instructions(),
-            constants: self.constants.clone()
+            instructions,
+            constants: self.constants.clone(),
+            builtins: self.builtins.clone(),
+            source_map
         }
     }
 }
 
+fn encoding_error(code: &Opcode, operands: &[usize]) -> String
+{
+    let name = look_up(code).map(|definition| definition.name).unwrap_or_default();
+    format!("{} operands {:?} are too large to encode", name, operands)
+}
+
 pub struct ByteCode
 {
     pub instructions: Instructions,
-    pub constants: Vec<Object>
+    pub constants: Vec<Object>,
+    pub builtins: Builtins,
+    // Where the statements of the main program start, see SourceMap.
+    pub source_map: SourceMap
 }
 
+// How many single character insertions, deletions and substitutions turn one
+// string into the other.
+fn edit_distance(from: &str, to: &str) -> usize
+{
+    let to: Vec<char> = to.chars().collect();
+    let mut previous: Vec<usize> = (0..=to.len()).collect();
+    for (i, a) in from.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in to.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[to.len()]
+}
@@ -0,0 +1,262 @@
+use std::io::{BufRead, Write};
+use crate::ast::Node;
+use crate::compiler::Compiler;
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Lexer;
+use crate::object::Object;
+use crate::parser::Parser;
+use crate::symbol_table::SymbolScope;
+use crate::vm::{FrameInfo, HookAction, Vm, VmConfig, VmHook};
+
+const PROMPT: &str = "(debug) ";
+const HELP: &str = "stepi, si        run one instruction
+step, s          run until the next source line, entering calls
+next, n          run until the next source line in this function or its callers
+continue, c      run until a breakpoint or the end of the program
+break <line>     stop at the statements starting on <line>
+break <name>     stop when the function bound to <name> is called
+delete           remove every breakpoint
+stack            show the operand stack, top last
+frames, bt       show the call frames and their locals, innermost first
+globals          show the global bindings
+where, w         show the instruction about to run
+quit, q          stop the program
+help, h          show this message";
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Breakpoint {
+    Line(usize),
+    Function(String),
+}
+
+// How far to run before handing control back to the user.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Resume {
+    Instruction,
+    // Until a statement starts on another line or in another frame, or the
+    // function returns; with over set, functions called from depth are skipped.
+    Line { line: usize, depth: usize, over: bool },
+    Continue,
+}
+
+// An interactive VmHook: pauses before instructions and reads commands until
+// told to resume. Execution starts paused on the first instruction.
+pub struct Debugger {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    globals: Vec<(usize, String)>,
+    breakpoints: Vec<Breakpoint>,
+    resume: Resume,
+    quit: bool,
+}
+
+impl Debugger {
+    // globals names the global slots, as defined by the compiler's symbol table.
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>, globals: Vec<(usize, String)>) -> Self {
+        Debugger {
+            input,
+            output,
+            globals,
+            breakpoints: Vec::new(),
+            resume: Resume::Instruction,
+            quit: false,
+        }
+    }
+
+    fn print(&mut self, text: &str) {
+        // Nothing useful can be done when the terminal is gone; quit is the
+        // next command read anyway.
+        let _ = writeln!(self.output, "{}", text);
+    }
+
+    fn read_command(&mut self) -> Option<String> {
+        let _ = write!(self.output, "{}", PROMPT);
+        let _ = self.output.flush();
+        let mut line = String::new();
+        match self.input.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line.trim().to_string()),
+        }
+    }
+
+    fn should_pause(&self, vm: &Vm) -> bool {
+        let function = vm.current_function();
+        let offset = vm.current_offset();
+        let statement = function.source_map.starting_at(offset);
+        let at_breakpoint = self.breakpoints.iter().any(|breakpoint| match breakpoint {
+            Breakpoint::Line(line) => statement.is_some_and(|span| span.start.line == *line),
+            Breakpoint::Function(name) => offset == 0 && function.name.as_deref() == Some(name.as_str()),
+        });
+        match self.resume {
+            Resume::Instruction => true,
+            Resume::Line { line, depth, over } => {
+                at_breakpoint
+                    || vm.depth() < depth
+                    || statement.is_some_and(|span| span.start.line != line || vm.depth() != depth)
+                        && (!over || vm.depth() <= depth)
+            }
+            Resume::Continue => at_breakpoint,
+        }
+    }
+
+    fn location(frame: &FrameInfo) -> String {
+        let line = match frame.span {
+            Some(span) => format!(" line {}", span.start),
+            None => String::new(),
+        };
+        let instruction = frame.instruction.clone().unwrap_or_else(|| "<end>".to_string());
+        format!("{}{} {:0>4} {}", frame.name, line, frame.offset, instruction)
+    }
+
+    fn show_stack(&mut self, vm: &Vm) {
+        if vm.stack().is_empty() {
+            self.print("stack is empty");
+            return;
+        }
+        let lines: Vec<String> = vm.stack().iter().enumerate().map(|(i, value)| format!("{:>4} {}", i, value.inspect())).collect();
+        self.print(&lines.join("\n"));
+    }
+
+    fn show_frames(&mut self, vm: &Vm) {
+        let mut lines = Vec::new();
+        for (i, frame) in vm.frames().iter().enumerate() {
+            lines.push(format!("#{} {}", i, Debugger::location(frame)));
+            for (name, value) in &frame.locals {
+                lines.push(format!("    {} = {}", name, value.inspect()));
+            }
+        }
+        self.print(&lines.join("\n"));
+    }
+
+    fn show_globals(&mut self, vm: &Vm) {
+        let lines: Vec<String> = self.globals.iter().map(|(index, name)| match vm.globals.get(*index) {
+            Some(Some(value)) => format!("{} = {}", name, value.inspect()),
+            _ => format!("{} = <unset>", name),
+        }).collect();
+        if lines.is_empty() {
+            self.print("no globals defined");
+        }
+        else {
+            self.print(&lines.join("\n"));
+        }
+    }
+
+    fn add_breakpoint(&mut self, target: &str) {
+        let breakpoint = match target.parse::<usize>() {
+            Ok(line) => Breakpoint::Line(line),
+            Err(_) if !target.is_empty() => Breakpoint::Function(target.to_string()),
+            Err(_) => {
+                self.print("usage: break <line>|<name>");
+                return;
+            }
+        };
+        let description = match &breakpoint {
+            Breakpoint::Line(line) => format!("breakpoint {} at line {}", self.breakpoints.len() + 1, line),
+            Breakpoint::Function(name) => format!("breakpoint {} at fn {}", self.breakpoints.len() + 1, name),
+        };
+        self.breakpoints.push(breakpoint);
+        self.print(&description);
+    }
+}
+
+impl VmHook for Debugger {
+    fn before_instruction(&mut self, vm: &Vm) -> HookAction {
+        if self.quit {
+            return HookAction::Stop;
+        }
+        if !self.should_pause(vm) {
+            return HookAction::Continue;
+        }
+        let frames = vm.frames();
+        let here = Debugger::location(&frames[0]);
+        self.print(&here);
+        loop {
+            let command = match self.read_command() {
+                Some(command) => command,
+                None => {
+                    self.quit = true;
+                    return HookAction::Stop;
+                }
+            };
+            let (name, argument) = match command.split_once(char::is_whitespace) {
+                Some((name, argument)) => (name, argument.trim()),
+                None => (command.as_str(), "")
+            };
+            let line = frames[0].span.map(|span| span.start.line).unwrap_or(0);
+            match name {
+                "stepi" | "si" => self.resume = Resume::Instruction,
+                "step" | "s" => self.resume = Resume::Line { line, depth: vm.depth(), over: false },
+                "next" | "n" => self.resume = Resume::Line { line, depth: vm.depth(), over: true },
+                "continue" | "c" => self.resume = Resume::Continue,
+                "break" | "b" => {
+                    self.add_breakpoint(argument);
+                    continue;
+                }
+                "delete" => {
+                    self.breakpoints.clear();
+                    self.print("deleted all breakpoints");
+                    continue;
+                }
+                "stack" => {
+                    self.show_stack(vm);
+                    continue;
+                }
+                "frames" | "bt" => {
+                    self.show_frames(vm);
+                    continue;
+                }
+                "globals" => {
+                    self.show_globals(vm);
+                    continue;
+                }
+                "where" | "w" => {
+                    self.print(&here);
+                    continue;
+                }
+                "quit" | "q" => {
+                    self.quit = true;
+                    return HookAction::Stop;
+                }
+                "help" | "h" => {
+                    self.print(HELP);
+                    continue;
+                }
+                "" => continue,
+                _ => {
+                    self.print(&format!("unknown command {}, try help", name));
+                    continue;
+                }
+            }
+            return HookAction::Continue;
+        }
+    }
+}
+
+// Compiles source and runs it on the VM under a Debugger reading commands from
+// input. Returns the value of the last statement if it is an expression, Null
+// otherwise, or diagnostics for the errors that stopped it; quitting the
+// debugger ends the run with an "interrupted" error.
+pub fn debug(source: &str, input: Box<dyn BufRead>, output: Box<dyn Write>) -> Result<Object, Vec<Diagnostic>> {
+    let lexer = Lexer::new(source.to_string());
+    let mut parser = Parser::new(lexer);
+    let program = parser.parse_program();
+    if !parser.errors.is_empty() {
+        return Err(parser.errors.iter().map(Diagnostic::from).collect());
+    }
+    let returns_value = program.returns_value();
+    let mut compiler = Compiler::new();
+    compiler.compile(Node::Program(program)).map_err(|error| vec![Diagnostic::from(&error)])?;
+    let globals = compiler.symbol_table.symbols()
+        .into_iter()
+        .filter(|symbol| symbol.scope == SymbolScope::Global)
+        .map(|symbol| (symbol.index, symbol.name))
+        .collect();
+
+    let mut vm = Vm::new(compiler.get_bytecode(), VmConfig::default());
+    vm.set_hook(Box::new(Debugger::new(input, output, globals)));
+    match vm.run() {
+        Ok(()) if returns_value => Ok(vm.last_popped_stack_element()),
+        Ok(()) => Ok(Object::Null),
+        Err(error) => Err(vec![Diagnostic::from(&error)]),
+    }
+}
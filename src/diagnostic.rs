@@ -0,0 +1,199 @@
+use std::fmt::{Display, Formatter};
+use crate::token::Span;
+
+// Codes, grouped by the stage that reports them.
+pub const SYNTAX_ERROR: &str = "E0001";
+pub const UNDEFINED_VARIABLE: &str = "E0101";
+pub const UNSUPPORTED: &str = "E0102";
+pub const RUNTIME_ERROR: &str = "E0201";
+pub const LIMIT_EXCEEDED: &str = "E0202";
+pub const INTERRUPTED: &str = "E0203";
+pub const TYPE_ERROR: &str = "E0301";
+pub const UNUSED_BINDING: &str = "W0001";
+pub const SHADOWED_PARAMETER: &str = "W0002";
+pub const UNREACHABLE_CODE: &str = "W0003";
+pub const CONSTANT_CONDITION: &str = "W0004";
+pub const BUILTIN_ARITY: &str = "W0005";
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const BLUE: &str = "\x1b[1;34m";
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note
+}
+
+impl Severity {
+    fn name(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note"
+        }
+    }
+
+    fn colour(&self) -> &'static str {
+        match self {
+            Severity::Error => "\x1b[1;31m",
+            Severity::Warning => "\x1b[1;33m",
+            Severity::Note => "\x1b[1;32m"
+        }
+    }
+}
+
+// A span of source with a message shown next to its underline.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String
+}
+
+impl Label {
+    pub fn new(span: Span, message: &str) -> Self {
+        Label {
+            span,
+            message: message.to_string()
+        }
+    }
+}
+
+// A problem found in a program by any stage, from the parser to the VM. It is
+// built up with the with_* methods and shown with render.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    // Where the problem is, when it can be tied to the source.
+    pub span: Option<Span>,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub help: Vec<String>
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, code: &'static str, message: &str) -> Self {
+        Diagnostic {
+            severity,
+            code,
+            message: message.to_string(),
+            span: None,
+            labels: Vec::new(),
+            notes: Vec::new(),
+            help: Vec::new()
+        }
+    }
+
+    pub fn error(code: &'static str, message: &str) -> Self {
+        Diagnostic::new(Severity::Error, code, message)
+    }
+
+    pub fn with_span(mut self, span: Option<Span>) -> Self {
+        self.span = span;
+        self
+    }
+
+    pub fn with_label(mut self, label: Label) -> Self {
+        self.labels.push(label);
+        self
+    }
+
+    pub fn with_note(mut self, note: &str) -> Self {
+        self.notes.push(note.to_string());
+        self
+    }
+
+    pub fn with_help(mut self, help: &str) -> Self {
+        self.help.push(help.to_string());
+        self
+    }
+
+    // Shows the diagnostic with every source line it points at, the primary
+    // span underlined with ^ and labels with -. name is the file the source
+    // came from; colour adds ANSI escapes.
+    pub fn render(&self, name: &str, source: &str, colour: bool) -> String {
+        let paint = |style: &str, text: &str| {
+            if colour { format!("{}{}{}", style, text, RESET) } else { text.to_string() }
+        };
+        let mut output = format!(
+            "{}{}",
+            paint(self.severity.colour(), &format!("{}[{}]", self.severity.name(), self.code)),
+            paint(BOLD, &format!(": {}", self.message))
+        );
+
+        let mut marks: Vec<(Span, char, &str)> = Vec::new();
+        if let Some(span) = self.span {
+            marks.push((span, '^', ""));
+        }
+        marks.extend(self.labels.iter().map(|label| (label.span, '-', label.message.as_str())));
+        let lines: Vec<&str> = source.lines().collect();
+        let mut shown: Vec<usize> = marks.iter()
+            .map(|(span, _, _)| span.start.line)
+            // The end of input can be one line past the last.
+            .filter(|line| *line >= 1 && *line <= lines.len() + 1)
+            .collect();
+        shown.sort();
+        shown.dedup();
+        let width = shown.last().map_or(0, |line| line.to_string().len());
+        let gutter = paint(BLUE, &format!("{} |", " ".repeat(width)));
+
+        if let Some(span) = self.span.or(marks.first().map(|mark| mark.0)) {
+            output += &format!("\n{}{} {}:{}", " ".repeat(width), paint(BLUE, "-->"), name, span.start);
+        }
+        if !shown.is_empty() {
+            output += &format!("\n{}", gutter);
+        }
+        for line in shown {
+            let text = lines.get(line - 1).copied().unwrap_or("");
+            output += &format!("\n{} {}", paint(BLUE, &format!("{:>width$} |", line)), text);
+            for (span, mark, message) in marks.iter().filter(|(span, _, _)| span.start.line == line) {
+                // Spans running past the line are underlined to its end.
+                let length = text.chars().count();
+                let start = span.start.column.max(1);
+                let end = if span.end.line == line { span.end.column } else { length + 1 };
+                let underline = mark.to_string().repeat(end.saturating_sub(start).max(1));
+                let style = if *mark == '^' { self.severity.colour() } else { BLUE };
+                let mut row = format!("{}{}", " ".repeat(start - 1), underline);
+                if !message.is_empty() {
+                    row += &format!(" {}", message);
+                }
+                output += &format!("\n{} {}", gutter, paint(style, &row));
+            }
+        }
+        for note in &self.notes {
+            output += &format!("\n{} {} {}", " ".repeat(width), paint(BLUE, "="), paint(BOLD, "note:") + " " + note);
+        }
+        for help in &self.help {
+            output += &format!("\n{} {} {}", " ".repeat(width), paint(BLUE, "="), paint(BOLD, "help:") + " " + help);
+        }
+        output
+    }
+}
+
+// The one-line form, without the source.
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}[{}]: {}", self.severity.name(), self.code, self.message)?;
+        if let Some(span) = self.span {
+            write!(f, " at {}", span.start)?;
+        }
+        Ok(())
+    }
+}
+
+// Renders every diagnostic, separated by blank lines.
+pub fn render_all(diagnostics: &[Diagnostic], name: &str, source: &str, colour: bool) -> String {
+    diagnostics.iter()
+        .map(|diagnostic| diagnostic.render(name, source, colour))
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+// Whether output to a stream should be coloured: only on a terminal, and not
+// when NO_COLOR is set.
+pub fn colour_enabled(terminal: bool) -> bool {
+    terminal && std::env::var_os("NO_COLOR").is_none()
+}
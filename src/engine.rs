@@ -0,0 +1,149 @@
+use std::fmt::{Display, Formatter};
+use crate::ast::Node;
+use crate::compiler::{CompileError, Compiler};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Lexer;
+use crate::builtins::Builtins;
+use crate::object::{FunctionCaller, Object};
+use crate::parser::{ParseError, Parser};
+use crate::profiler::Profile;
+use crate::symbol_table::{SymbolScope, SymbolTable};
+use crate::vm::{RuntimeError, Vm, VmConfig};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum EngineError {
+    Parse(Vec<ParseError>),
+    Compile(CompileError),
+    Runtime(RuntimeError)
+}
+
+impl Display for EngineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineError::Parse(errors) => {
+                let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                write!(f, "parse error: {}", errors.join("\n"))
+            },
+            EngineError::Compile(error) => write!(f, "compile error: {}", error),
+            EngineError::Runtime(error) => write!(f, "runtime error: {}", error)
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+impl EngineError {
+    // The error as diagnostics to render against the source that was run.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            EngineError::Parse(errors) => errors.iter().map(Diagnostic::from).collect(),
+            EngineError::Compile(error) => vec![Diagnostic::from(error)],
+            EngineError::Runtime(error) => vec![Diagnostic::from(error)]
+        }
+    }
+}
+
+// Compiles and runs Monkey source on the VM, keeping globals, constants and
+// symbols alive between calls to run so a host can feed a script piece by piece.
+pub struct Engine {
+    constants: Vec<Object>,
+    symbol_table: SymbolTable,
+    globals: Vec<Option<Object>>,
+    builtins: Builtins,
+    config: VmConfig,
+    profile: Option<Profile>
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Engine::new_with_config(VmConfig::default())
+    }
+
+    pub fn new_with_config(config: VmConfig) -> Self {
+        let builtins = Builtins::new();
+        let mut symbol_table = SymbolTable::new();
+        builtins.define_in(&mut symbol_table);
+        Engine {
+            constants: Vec::new(),
+            symbol_table,
+            globals: Vec::new(),
+            builtins,
+            config,
+            profile: None
+        }
+    }
+
+    // The profile of the last call to run, failed or not, when the engine was
+    // created with VmConfig::profile set.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    // Returns the value of the last expression statement, or Null when the
+    // source ends with any other statement. State is only kept when the whole
+    // source compiles and runs without errors.
+    pub fn run(&mut self, source: &str) -> Result<Object, EngineError> {
+        let lexer = Lexer::new(source.to_string());
+        let mut parser = Parser::new(lexer);
+        let program = parser.parse_program();
+        if !parser.errors.is_empty() {
+            return Err(EngineError::Parse(parser.errors));
+        }
+        let returns_value = program.returns_value();
+
+        let mut compiler = Compiler::new_with_state(self.constants.clone(), self.symbol_table.clone(), self.builtins.clone());
+        compiler.compile(Node::Program(program)).map_err(EngineError::Compile)?;
+
+        let mut vm = Vm::new_with_state(compiler.get_bytecode(), self.globals.clone(), self.config.clone());
+        let outcome = vm.run();
+        self.profile = vm.take_profile();
+        outcome.map_err(EngineError::Runtime)?;
+
+        let result = if returns_value {
+            vm.last_popped_stack_element()
+        } else {
+            Object::Null
+        };
+        self.constants = compiler.constants;
+        self.symbol_table = compiler.symbol_table;
+        self.globals = vm.globals;
+        Ok(result)
+    }
+
+    // Adds a native function, or replaces the builtin with the same name.
+    // Code that was already compiled keeps calling the function by index, so
+    // replacing a builtin also affects functions defined in earlier runs.
+    pub fn register_fn<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(Vec<Object>, &mut dyn FunctionCaller) -> Object + 'static
+    {
+        let index = self.builtins.register(name, function);
+        self.symbol_table.define_builtin(index, name.to_string());
+    }
+
+    pub fn set_global<T: Into<Object>>(&mut self, name: &str, value: T) {
+        let index = match self.symbol_table.resolve(name.to_string()) {
+            Some(symbol) if symbol.scope == SymbolScope::Global => symbol.index,
+            _ => self.symbol_table.define(name.to_string()).index
+        };
+        while self.globals.len() <= index {
+            self.globals.push(None);
+        }
+        self.globals[index] = Some(value.into());
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Object> {
+        match self.symbol_table.resolve(name.to_string()) {
+            Some(symbol) if symbol.scope == SymbolScope::Global => {
+                self.globals.get(symbol.index).cloned().flatten()
+            }
+            _ => None
+        }
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine::new()
+    }
+}
@@ -45,4 +45,13 @@ impl Environment {
     pub fn set(& mut self, name:String, value:Object) {
         self.map.insert(name, value);
     }
+
+    // The bindings of this scope, sorted by name. Outer scopes are not included.
+    pub fn bindings(&self) -> Vec<(String, Object)> {
+        let mut bindings: Vec<(String, Object)> = self.map.iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        bindings.sort_by(|a, b| a.0.cmp(&b.0));
+        bindings
+    }
 }
\ No newline at end of file
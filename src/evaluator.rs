@@ -1,11 +1,64 @@
-use crate::ast::{Expression, HashStruct, Identifier, IfStruct, IndexStruct, Node, Statement};
+use crate::ast::{Expression, HashStruct, Identifier, IfStruct, IndexStruct, Node, Statement, TryStruct};
+use crate::budget::{Budget, LimitExceeded, Meter};
 use crate::builtins::get_built_in;
 use crate::environment::Environment;
-use crate::object::{FunctionStruct, Object};
+use crate::object::{ArrayStruct, FunctionCaller, FunctionStruct, Object};
 use crate::object::Object::{IntegerObject, Null, ReturnValue};
-use crate::token::Token;
+use crate::token::{Span, Token};
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 
+thread_local! {
+    // The meter of the innermost eval_with_budget call, if any. The evaluator
+    // is a set of free functions, so this avoids threading it through all of them.
+    static METER: RefCell<Option<Meter>> = const { RefCell::new(None) };
+    // The innermost statement that raised the error being returned, if any.
+    static ERROR_SPAN: Cell<Option<Span>> = const { Cell::new(None) };
+}
+
+// Where the error that ended the last program run by eval was raised.
+pub fn error_span() -> Option<Span> {
+    ERROR_SPAN.with(|span| span.get())
+}
+
+// Like eval, but stops once the budget is used up. Hitting a limit is reported
+// as Err, so callers can tell it apart from errors raised by the program.
+pub fn eval_with_budget(node: Node, env: & mut Environment, budget: &Budget) -> Result<Object, LimitExceeded> {
+    let outer = METER.with(|meter| meter.replace(Some(Meter::new(budget))));
+    let result = eval(node, env);
+    let meter = METER.with(|meter| meter.replace(outer));
+    match meter.and_then(|meter| meter.exceeded()) {
+        Some(limit) => Err(limit),
+        None => Ok(result)
+    }
+}
+
+fn tick() -> Result<(), LimitExceeded> {
+    METER.with(|meter| match meter.borrow_mut().as_mut() {
+        Some(meter) => meter.tick(),
+        None => Ok(())
+    })
+}
+
+fn allocate(bytes: usize) -> Result<(), LimitExceeded> {
+    METER.with(|meter| match meter.borrow_mut().as_mut() {
+        Some(meter) => meter.allocate(bytes),
+        None => Ok(())
+    })
+}
+
+// Charges a value created by the evaluator itself to the memory budget.
+fn allocated(object: Object) -> Object {
+    match allocate(object.approximate_size()) {
+        Ok(()) => object,
+        Err(limit) => Object::Error(limit.to_string())
+    }
+}
+
+fn limit_exceeded() -> bool {
+    METER.with(|meter| meter.borrow().as_ref().is_some_and(|meter| meter.exceeded().is_some()))
+}
+
 pub fn eval(node: Node, env: & mut Environment) -> Object {
     match node {
         Node::Program(prog) => {
@@ -25,7 +78,7 @@ pub fn eval(node: Node, env: & mut Environment) -> Object {
 
 fn eval_program(block: Vec<Statement>, env: & mut Environment) -> Object
 {
-
+    ERROR_SPAN.with(|span| span.set(None));
     let mut result = Null;
     for stmt in block {
         result = eval_statement(stmt, env);
@@ -56,16 +109,34 @@ fn eval_block_statement(block: Vec<Statement>, env: & mut Environment) -> Object
 }
 
 fn eval_statement(stmt: Statement, env: & mut Environment) -> Object
+{
+    let span = stmt.span();
+    let result = eval_statement_body(stmt, env);
+    let failed = match &result {
+        ReturnValue(value) => value.is_error(),
+        result => result.is_error()
+    };
+    if failed {
+        ERROR_SPAN.with(|error_span| {
+            if error_span.get().is_none() {
+                error_span.set(Some(span));
+            }
+        });
+    }
+    result
+}
+
+fn eval_statement_body(stmt: Statement, env: & mut Environment) -> Object
 {
     match stmt {
-        Statement::ExpressionStatement(expr) => {
+        Statement::ExpressionStatement(expr, _) => {
             eval(Node::Expression(expr), env)
         },
-        Statement::ReturnStatement(expr) => {
+        Statement::ReturnStatement(expr, _) => {
             let inner_value = eval(Node::Expression(expr), env);
             ReturnValue(Box::new(inner_value))
         },
-        Statement::LetStatement(id, expr) => {
+        Statement::LetStatement(id, expr, _) => {
             let val = eval(Node::Expression(expr), env);
             if val.is_error() {
                 return val;
@@ -98,16 +169,16 @@ fn eval_hash_expr(hash: &HashStruct, env: & mut Environment) -> Object
         }
         map.insert(key, value);
     }
-    Object::HashMap(map)
+    allocated(Object::HashMap(map))
 }
 
-fn eval_array_index_expression(left: Vec<Box<Object>>, right: &Expression, env: & mut Environment) -> Object
+fn eval_array_index_expression(left: ArrayStruct, right: &Expression, env: & mut Environment) -> Object
 {
     let inner = eval_expr(&right, env);
     if let IntegerObject(i) = inner {
         if let Some(object_value) = left.get(i as usize)
         {
-            *object_value.clone()
+            object_value
         }
         else
         {
@@ -156,6 +227,9 @@ fn eval_index_expression(index: &IndexStruct, env: & mut Environment) -> Object
 }
 
 fn eval_expr(expr: &Expression, env: & mut Environment) -> Object {
+    if let Err(limit) = tick() {
+        return Object::Error(limit.to_string());
+    }
     match expr {
         Expression::IntegerExpression(content) =>
             {
@@ -219,9 +293,9 @@ fn eval_expr(expr: &Expression, env: & mut Environment) -> Object {
                 {
                     return object;
                 }
-                element_vec.push(Box::new(object));
+                element_vec.push(object);
             }
-            Object::Array(element_vec)
+            allocated(Object::from(element_vec))
         },
         Expression::IndexExpression(content) =>
             {
@@ -231,6 +305,10 @@ fn eval_expr(expr: &Expression, env: & mut Environment) -> Object {
             {
                 eval_hash_expr(content, env)
             }
+        Expression::TryExpression(content) =>
+            {
+                eval_try_expr(content, env)
+            }
         _ => {
             Object::Error(format!("Expression not suported: {}", expr.to_string()))
         }
@@ -248,6 +326,9 @@ fn unwrap_return_value(return_object: Object) -> Object {
 fn apply_function(function: Object, args: Vec<Object>) -> Object
 {
     if let Object::Function(content) = function {
+        if args.len() != content.parameters.len() {
+            return Object::Error(format!("wrong number of arguments: want={}, got={}", content.parameters.len(), args.len()));
+        }
         let mut exp_env = extend_function_env(&content, args);
         let evaluated = eval(Node::StatementBlock(content.body), & mut exp_env);
         if evaluated.is_error()
@@ -257,11 +338,23 @@ fn apply_function(function: Object, args: Vec<Object>) -> Object
         return unwrap_return_value(evaluated);
     }
     else if let Object::BuiltIn(content) = function {
-        return content(args);
+        return content.call(args, &mut EvalCaller);
     }
     Object::Error(format!("Not a function {}", function.get_type()))
 }
 
+struct EvalCaller;
+
+impl FunctionCaller for EvalCaller {
+    fn call_function(&mut self, function: Object, args: Vec<Object>) -> Object {
+        apply_function(function, args)
+    }
+
+    fn allocate(&mut self, bytes: usize) -> bool {
+        allocate(bytes).is_ok()
+    }
+}
+
 fn extend_function_env(function_struct: &FunctionStruct, args: Vec<Object>) -> Environment {
     let mut env = Environment::new_with_superior(Box::new(function_struct.env.clone()));
     for i in 0..args.len() {
@@ -336,6 +429,22 @@ fn eval_if_expr(if_struct: IfStruct, env: & mut Environment) -> Object
     }
 }
 
+fn eval_try_expr(try_struct: &TryStruct, env: & mut Environment) -> Object
+{
+    let result = eval(Node::StatementBlock(try_struct.body.clone()), env);
+    if limit_exceeded() {
+        return result;
+    }
+    if let Object::Error(message) = result {
+        ERROR_SPAN.with(|span| span.set(None));
+        env.set(try_struct.error.get_id(), Object::StringObject(message));
+        eval(Node::StatementBlock(try_struct.handler.clone()), env)
+    }
+    else {
+        result
+    }
+}
+
 fn is_true(object: Object) -> bool {
     match object {
         Object::IntegerObject(content) =>
@@ -389,7 +498,7 @@ fn eval_bang_operator(inner_object: Object) -> Object
 fn eval_minus_operator(inner_object: Object) -> Object
 {
     if let Object::IntegerObject(content) = inner_object {
-        Object::IntegerObject(-content)
+        checked_integer(content.checked_neg())
     }
     else {
         Object::Error(format!("unknown operator: {} {}", Token::MINUS.get_type(), inner_object.get_type()))
@@ -431,6 +540,9 @@ fn eval_string_infix_expr(right: String, left: String, operator: &Token) -> Obje
     match operator
     {
         Token::PLUS => {
+            if let Err(limit) = allocate(std::mem::size_of::<Object>() + right.len() + left.len()) {
+                return Object::Error(limit.to_string());
+            }
             Object::StringObject(right + left.as_str())
         },
         _ => {
@@ -444,19 +556,22 @@ fn eval_integer_infix_expr(right:i64, left:i64, operator: &Token) -> Object
     {
         Token::PLUS =>
             {
-                Object::IntegerObject(right + left)
+                checked_integer(right.checked_add(left))
             },
         Token::MINUS =>
             {
-                Object::IntegerObject(right - left)
+                checked_integer(right.checked_sub(left))
             },
         Token::ASTERISK =>
             {
-                Object::IntegerObject(right * left)
+                checked_integer(right.checked_mul(left))
             },
         Token::SLASH =>
             {
-                Object::IntegerObject(right / left)
+                if left == 0 {
+                    return Object::Error("division by zero".to_string());
+                }
+                checked_integer(right.checked_div(left))
             },
         Token::LT =>
             {
@@ -480,6 +595,14 @@ fn eval_integer_infix_expr(right:i64, left:i64, operator: &Token) -> Object
     }
 }
 
+fn checked_integer(result: Option<i64>) -> Object
+{
+    match result {
+        Some(content) => Object::IntegerObject(content),
+        None => Object::Error("integer overflow".to_string())
+    }
+}
+
 fn eval_bool_infix_expr(right: bool, left: bool, tok: &Token) -> Object
 {
     match tok {
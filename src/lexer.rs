@@ -1,21 +1,31 @@
-use crate::token::Token;
+use crate::token::{Position, Span, Token};
 use crate::token;
 
 pub struct Lexer{
-    input: String, 
+    input: Vec<char>,
     position: usize,
     next_position:usize,
-    ch: char
+    ch: char,
+    // Index in input of the first character of every line.
+    line_starts: Vec<usize>,
+    token_start: usize,
+    token_end: usize
 }
 
 impl Lexer {
     pub fn new(input: String) -> Self
     {
+        let input: Vec<char> = input.chars().collect();
+        let mut line_starts = vec![0];
+        line_starts.extend(input.iter().enumerate().filter(|(_, c)| **c == '\n').map(|(i, _)| i + 1));
         let mut l = Lexer {
             input,
             position : 0,
             next_position: 0,
-            ch: '\0'
+            ch: '\0',
+            line_starts,
+            token_start: 0,
+            token_end: 0
         };
         l.read_char();
         return l;
@@ -24,7 +34,7 @@ impl Lexer {
 
     fn read_char(&mut self)
     {
-        let aux = self.input.chars().nth(self.next_position);
+        let aux = self.input.get(self.next_position).copied();
         if let None = aux{
             self.ch = '\0';
         }
@@ -38,10 +48,10 @@ impl Lexer {
     fn read_identifier(& mut self) -> String
     {
         let position = self.position;
-        while self.ch.is_ascii_alphabetic(){
+        while is_letter(self.ch){
             self.read_char();
         }
-        self.input[position..self.position].to_string()
+        self.input[position..self.position].iter().collect()
     }
     fn read_number(& mut self) -> String
     {
@@ -50,7 +60,7 @@ impl Lexer {
         {
             self.read_char();
         }
-        self.input[position..self.position].to_string()
+        self.input[position..self.position].iter().collect()
     }
     fn skip_withespace(& mut self){
         while self.ch == ' ' || self.ch == '\t' || self.ch == '\n' || self.ch == '\r'
@@ -61,7 +71,7 @@ impl Lexer {
 
     fn peek_char(&self) -> char
     {
-        let aux = self.input.chars().nth(self.next_position);
+        let aux = self.input.get(self.next_position).copied();
         if let None = aux {
             '\0'
         }
@@ -79,15 +89,35 @@ impl Lexer {
                 break
             }
         }
-        Token::STRING(self.input[position..self.position].to_string())
+        Token::STRING(self.input[position..self.position].iter().collect())
     }
 
-    pub fn next_token(& mut self) -> Token
+    fn position_at(&self, index: usize) -> Position
     {
+        let line = self.line_starts.partition_point(|start| *start <= index);
+        Position {
+            line,
+            column: index - self.line_starts[line - 1] + 1
+        }
+    }
 
+    // The span of the token last returned by next_token.
+    pub fn span(&self) -> Span
+    {
+        Span::new(self.position_at(self.token_start), self.position_at(self.token_end))
+    }
 
+    pub fn next_token(& mut self) -> Token
+    {
         self.skip_withespace();
+        self.token_start = self.position;
+        let tok = self.read_token();
+        self.token_end = self.position;
+        tok
+    }
 
+    fn read_token(& mut self) -> Token
+    {
         let tok = match self.ch
         {
             '=' => {
@@ -166,7 +196,7 @@ impl Lexer {
             }
             _ =>
             {
-                if self.ch.is_ascii_alphabetic() {
+                if is_letter(self.ch) {
                     let tok = token::look_up_token(self.read_identifier());
                     return tok;
                 }
@@ -188,3 +218,7 @@ impl Lexer {
     }
 }
 
+fn is_letter(ch: char) -> bool
+{
+    ch.is_ascii_alphabetic() || ch == '_'
+}
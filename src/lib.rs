@@ -0,0 +1,58 @@
+pub mod token;
+pub mod lexer;
+#[cfg(test)]
+mod test_parser;
+pub mod repl;
+#[cfg(test)]
+mod test_repl;
+pub mod ast;
+pub mod parser;
+pub mod diagnostic;
+#[cfg(test)]
+mod test_diagnostic;
+pub mod object;
+#[cfg(test)]
+mod test_evaluator;
+pub mod evaluator;
+pub mod environment;
+pub mod builtins;
+pub mod code;
+#[cfg(test)]
+mod test_code;
+pub mod compiler;
+pub mod optimizer;
+#[cfg(test)]
+mod test_compiler;
+#[cfg(test)]
+mod test_vm;
+pub mod vm;
+pub mod budget;
+pub mod debugger;
+pub mod profiler;
+#[cfg(test)]
+mod test_debugger;
+pub mod symbol_table;
+#[cfg(test)]
+mod test_symbol_table;
+pub mod engine;
+pub mod analysis;
+#[cfg(test)]
+mod test_analysis;
+pub mod lsp;
+#[cfg(test)]
+mod test_lsp;
+pub mod lint;
+#[cfg(test)]
+mod test_lint;
+pub mod types;
+#[cfg(test)]
+mod test_types;
+#[cfg(test)]
+mod test_engine;
+#[cfg(test)]
+mod test_support;
+
+pub use engine::{Engine, EngineError};
+pub use object::Object;
+pub use budget::Budget;
+pub use vm::VmConfig;
@@ -0,0 +1,232 @@
+use std::collections::HashSet;
+use crate::analysis::{Analysis, DefinitionKind};
+use crate::ast::{Expression, Statement};
+use crate::builtins::{arity, describe_arity};
+use crate::diagnostic::{Diagnostic, Label, Severity, BUILTIN_ARITY, CONSTANT_CONDITION, SHADOWED_PARAMETER, UNREACHABLE_CODE, UNUSED_BINDING};
+use crate::object::Object;
+use crate::optimizer::fold_constant;
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum Rule {
+    UnusedBinding,
+    ShadowedParameter,
+    UnreachableCode,
+    ConstantCondition,
+    BuiltinArity
+}
+
+impl Rule {
+    pub const ALL: [Rule; 5] = [
+        Rule::UnusedBinding,
+        Rule::ShadowedParameter,
+        Rule::UnreachableCode,
+        Rule::ConstantCondition,
+        Rule::BuiltinArity
+    ];
+
+    // The name used to turn the rule on and off.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rule::UnusedBinding => "unused-binding",
+            Rule::ShadowedParameter => "shadowed-parameter",
+            Rule::UnreachableCode => "unreachable-code",
+            Rule::ConstantCondition => "constant-condition",
+            Rule::BuiltinArity => "builtin-arity"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Rule> {
+        Rule::ALL.into_iter().find(|rule| rule.name() == name)
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            Rule::UnusedBinding => UNUSED_BINDING,
+            Rule::ShadowedParameter => SHADOWED_PARAMETER,
+            Rule::UnreachableCode => UNREACHABLE_CODE,
+            Rule::ConstantCondition => CONSTANT_CONDITION,
+            Rule::BuiltinArity => BUILTIN_ARITY
+        }
+    }
+}
+
+// Which rules run. Every rule is on by default.
+#[derive(Clone, Debug, Default)]
+pub struct LintConfig {
+    disabled: HashSet<Rule>
+}
+
+impl LintConfig {
+    pub fn enable(&mut self, rule: Rule) {
+        self.disabled.remove(&rule);
+    }
+
+    pub fn disable(&mut self, rule: Rule) {
+        self.disabled.insert(rule);
+    }
+
+    pub fn is_enabled(&self, rule: Rule) -> bool {
+        !self.disabled.contains(&rule)
+    }
+}
+
+// Checks a program that parsed, returning a warning for every problem found by
+// the enabled rules, in source order.
+pub fn lint(analysis: &Analysis, config: &LintConfig) -> Vec<Diagnostic> {
+    let mut linter = Linter { analysis, config, warnings: Vec::new() };
+    linter.bindings();
+    linter.block(&analysis.program.statements);
+    let mut warnings = linter.warnings;
+    warnings.sort_by_key(|warning| warning.span.map(|span| span.start));
+    warnings
+}
+
+struct Linter<'a> {
+    analysis: &'a Analysis,
+    config: &'a LintConfig,
+    warnings: Vec<Diagnostic>
+}
+
+impl Linter<'_> {
+    fn warn(&mut self, rule: Rule, message: &str, diagnostic: impl FnOnce(Diagnostic) -> Diagnostic) {
+        if self.config.is_enabled(rule) {
+            let warning = Diagnostic::new(Severity::Warning, rule.code(), message);
+            self.warnings.push(diagnostic(warning).with_note(&format!("from the {} rule", rule.name())));
+        }
+    }
+
+    // The rules about names, which only need the resolved definitions.
+    fn bindings(&mut self) {
+        let definitions = &self.analysis.definitions;
+        for (index, definition) in definitions.iter().enumerate() {
+            if definition.kind != DefinitionKind::Let {
+                continue;
+            }
+            if !definition.name.starts_with('_') && self.analysis.references_to(index).is_empty() {
+                self.warn(Rule::UnusedBinding, &format!("`{}` is never used", definition.name), |warning| {
+                    warning.with_span(definition.span)
+                        .with_help(&format!("remove it, or name it `_{}` if it is meant to be unused", definition.name))
+                });
+            }
+            let parameter = definitions.iter().find(|other| {
+                other.kind == DefinitionKind::Parameter && other.scope == definition.scope && other.name == definition.name
+            });
+            if let Some(parameter) = parameter.and_then(|parameter| parameter.span) {
+                self.warn(Rule::ShadowedParameter, &format!("`{}` shadows a parameter", definition.name), |warning| {
+                    warning.with_span(definition.span)
+                        .with_label(Label::new(parameter, "the parameter is defined here"))
+                });
+            }
+        }
+    }
+
+    fn block(&mut self, block: &[Statement]) {
+        let returns = block.iter().position(|stmt| matches!(stmt, Statement::ReturnStatement(..)));
+        if let (Some(index), Some(last)) = (returns, block.last()) {
+            if index + 1 < block.len() {
+                let unreachable = block[index + 1].span().to(last.span());
+                self.warn(Rule::UnreachableCode, "unreachable code", |warning| {
+                    warning.with_span(Some(unreachable))
+                        .with_label(Label::new(block[index].span(), "any code after this return never runs"))
+                });
+            }
+        }
+        for stmt in block {
+            match stmt {
+                Statement::LetStatement(_, expr, _)
+                | Statement::ReturnStatement(expr, _)
+                | Statement::ExpressionStatement(expr, _) => self.expression(expr)
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression) {
+        match expr {
+            Expression::PrefixExpression(_, right) => self.expression(right),
+            Expression::InfixExpression(left, _, right) => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Expression::IfExpression(content) => {
+                if let Some(value) = constant_truth(&content.condition) {
+                    let never = match (value, &content.alternative) {
+                        (true, Some(_)) => Some("the else branch"),
+                        (true, None) => None,
+                        (false, _) => Some("the body")
+                    };
+                    self.warn(Rule::ConstantCondition, &format!("this condition is always {}", value), |warning| {
+                        let warning = warning.with_span(Some(content.condition_span));
+                        match never {
+                            Some(never) => warning.with_help(&format!("{} never runs", never)),
+                            None => warning
+                        }
+                    });
+                }
+                self.expression(&content.condition);
+                self.block(&content.consequence);
+                if let Some(alternative) = &content.alternative {
+                    self.block(alternative);
+                }
+            }
+            Expression::FnExpression(content) => self.block(&content.body),
+            Expression::CallExpression(content) => {
+                if let Expression::IdentifierExpression(id) = content.function.as_ref() {
+                    self.builtin_call(id.span, &id.id, content.args.len());
+                }
+                self.expression(&content.function);
+                for arg in &content.args {
+                    self.expression(arg);
+                }
+            }
+            Expression::ArrayLiteral(content) => {
+                for element in &content.elements {
+                    self.expression(element);
+                }
+            }
+            Expression::IndexExpression(content) => {
+                self.expression(&content.left);
+                self.expression(&content.index);
+            }
+            Expression::HashExpression(content) => {
+                for (key, value) in &content.pairs {
+                    self.expression(key);
+                    self.expression(value);
+                }
+            }
+            Expression::TryExpression(content) => {
+                self.block(&content.body);
+                self.block(&content.handler);
+            }
+            Expression::IdentifierExpression(_) | Expression::IntegerExpression(_) | Expression::BoolExpression(_)
+            | Expression::StringExpression(_) | Expression::Error(_) => {}
+        }
+    }
+
+    fn builtin_call(&mut self, span: crate::token::Span, name: &str, given: usize) {
+        let builtin = self.analysis.definition_at(span.start)
+            .is_some_and(|index| self.analysis.definitions[index].kind == DefinitionKind::Builtin);
+        let Some((least, most)) = arity(name).filter(|_| builtin) else {
+            return;
+        };
+        if given >= least && given <= most {
+            return;
+        }
+        let were = if given == 1 { "was" } else { "were" };
+        let message = format!("`{}` takes {} but {} {} given", name, describe_arity(least, most), given, were);
+        self.warn(Rule::BuiltinArity, &message, |warning| {
+            warning.with_span(Some(span))
+        });
+    }
+}
+
+// Whether a condition is always truthy or always falsy, when that is known
+// without running it. This follows is_true in the engines: other types, such as
+// strings, are not conditions they agree on.
+fn constant_truth(condition: &Expression) -> Option<bool> {
+    match fold_constant(condition)? {
+        Object::IntegerObject(value) => Some(value != 0),
+        Object::BooleanObject(value) => Some(value),
+        Object::Null => Some(false),
+        _ => None
+    }
+}
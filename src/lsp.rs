@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use serde_json::{json, Value};
+use crate::analysis::{Analysis, DefinitionKind};
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::token::{Position, Span};
+
+// JSON-RPC error codes used by the protocol.
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+// LSP values for the text document sync kind and completion item kinds.
+const FULL_SYNC: u32 = 1;
+const COMPLETION_FUNCTION: u32 = 3;
+const COMPLETION_VARIABLE: u32 = 6;
+
+struct Document {
+    text: String,
+    analysis: Analysis
+}
+
+// A language server speaking LSP over a pair of streams. Documents are synced
+// in full on every change and analysed again.
+struct Server<W: Write> {
+    output: W,
+    documents: HashMap<String, Document>,
+    shut_down: bool
+}
+
+// Serves requests from input until the client sends exit. Returns whether the
+// client asked for a shutdown first, which decides the exit code.
+pub fn serve(input: impl BufRead, output: impl Write) -> std::io::Result<bool> {
+    let mut server = Server::new(output);
+    let mut input = input;
+    while let Some(message) = read_message(&mut input)? {
+        let message = match serde_json::from_str::<Value>(&message) {
+            Ok(message) => message,
+            Err(error) => {
+                server.send(json!({"jsonrpc": "2.0", "id": null, "error": {"code": PARSE_ERROR, "message": error.to_string()}}))?;
+                continue;
+            }
+        };
+        if message["method"] == "exit" {
+            break;
+        }
+        server.handle(message)?;
+    }
+    Ok(server.shut_down)
+}
+
+// Reads one message framed by a Content-Length header, or None at the end of
+// input.
+fn read_message(input: &mut impl BufRead) -> std::io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let Some(length) = length else {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "message without a Content-Length"));
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))
+}
+
+impl<W: Write> Server<W> {
+    fn new(output: W) -> Self {
+        Server {
+            output,
+            documents: HashMap::new(),
+            shut_down: false
+        }
+    }
+
+    fn send(&mut self, message: Value) -> std::io::Result<()> {
+        let body = message.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.output.flush()
+    }
+
+    // Answers a request, or acts on a notification, which has no id.
+    fn handle(&mut self, message: Value) -> std::io::Result<()> {
+        let method = message["method"].as_str().unwrap_or_default().to_string();
+        let params = &message["params"];
+        let result = match method.as_str() {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": FULL_SYNC,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {}
+                },
+                "serverInfo": {"name": "monkey"}
+            })),
+            "shutdown" => {
+                self.shut_down = true;
+                Ok(Value::Null)
+            }
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+                self.update(document["uri"].as_str(), document["text"].as_str())?;
+                return Ok(());
+            }
+            "textDocument/didChange" => {
+                let text = params["contentChanges"].as_array().and_then(|changes| changes.last());
+                self.update(params["textDocument"]["uri"].as_str(), text.and_then(|change| change["text"].as_str()))?;
+                return Ok(());
+            }
+            "textDocument/didClose" => {
+                if let Some(uri) = params["textDocument"]["uri"].as_str() {
+                    self.documents.remove(uri);
+                    self.publish(uri, Vec::new())?;
+                }
+                return Ok(());
+            }
+            "textDocument/definition" => self.at_position(params, |document, uri, position| {
+                let definition = &document.analysis.definitions[document.analysis.definition_at(position)?];
+                Some(location(&document.text, uri, definition.span?))
+            }),
+            "textDocument/references" => self.at_position(params, |document, uri, position| {
+                let definition = document.analysis.definition_at(position)?;
+                let mut spans = document.analysis.references_to(definition);
+                if params["context"]["includeDeclaration"].as_bool().unwrap_or(true) {
+                    spans.extend(document.analysis.definitions[definition].span);
+                }
+                spans.sort_by_key(|span| span.start);
+                let locations: Vec<Value> = spans.into_iter().map(|span| location(&document.text, uri, span)).collect();
+                Some(json!(locations))
+            }),
+            "textDocument/hover" => self.at_position(params, |document, _, position| {
+                let definition = document.analysis.definition_at(position)?;
+                Some(json!({"contents": {"kind": "markdown", "value": hover(document, definition)}}))
+            }),
+            "textDocument/completion" => self.at_position(params, |document, _, position| {
+                let items: Vec<Value> = document.analysis.visible_at(position)
+                    .into_iter()
+                    .map(|index| completion(document, index))
+                    .collect();
+                Some(json!(items))
+            }),
+            _ => Err((METHOD_NOT_FOUND, format!("method {} not supported", method)))
+        };
+        if message["id"].is_null() {
+            return Ok(());
+        }
+        let response = match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": message["id"], "result": result}),
+            Err((code, error)) => json!({"jsonrpc": "2.0", "id": message["id"], "error": {"code": code, "message": error}})
+        };
+        self.send(response)
+    }
+
+    fn update(&mut self, uri: Option<&str>, text: Option<&str>) -> std::io::Result<()> {
+        let (Some(uri), Some(text)) = (uri, text) else {
+            return Ok(());
+        };
+        let document = Document { text: text.to_string(), analysis: Analysis::new(text) };
+        let diagnostics = document.analysis.diagnostics()
+            .iter()
+            .map(|diagnostic| lsp_diagnostic(&document.text, uri, diagnostic))
+            .collect();
+        self.documents.insert(uri.to_string(), document);
+        self.publish(uri, diagnostics)
+    }
+
+    fn publish(&mut self, uri: &str, diagnostics: Vec<Value>) -> std::io::Result<()> {
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {"uri": uri, "diagnostics": diagnostics}
+        }))
+    }
+
+    // Runs a request about a position in an open document. Its result is null
+    // when answer finds nothing there.
+    fn at_position(
+        &self,
+        params: &Value,
+        answer: impl Fn(&Document, &str, Position) -> Option<Value>
+    ) -> Result<Value, (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let Some(document) = self.documents.get(uri) else {
+            return Err((INVALID_PARAMS, format!("{} is not open", uri)));
+        };
+        let line = params["position"]["line"].as_u64().unwrap_or_default() as usize;
+        let character = params["position"]["character"].as_u64().unwrap_or_default() as usize;
+        let position = from_lsp(&document.text, line, character);
+        Ok(answer(document, uri, position).unwrap_or(Value::Null))
+    }
+}
+
+// LSP counts lines from 0 and characters in UTF-16 code units; Position counts
+// both from 1, and columns in characters.
+fn to_lsp(text: &str, position: Position) -> Value {
+    let line = text.lines().nth(position.line.saturating_sub(1)).unwrap_or("");
+    let character: usize = line.chars().take(position.column.saturating_sub(1)).map(char::len_utf16).sum();
+    json!({"line": position.line.saturating_sub(1), "character": character})
+}
+
+fn from_lsp(text: &str, line: usize, character: usize) -> Position {
+    let mut column = 1;
+    let mut units = 0;
+    for c in text.lines().nth(line).unwrap_or("").chars() {
+        if units >= character {
+            break;
+        }
+        units += c.len_utf16();
+        column += 1;
+    }
+    Position { line: line + 1, column }
+}
+
+fn range(text: &str, span: Span) -> Value {
+    json!({"start": to_lsp(text, span.start), "end": to_lsp(text, span.end)})
+}
+
+fn location(text: &str, uri: &str, span: Span) -> Value {
+    json!({"uri": uri, "range": range(text, span)})
+}
+
+fn lsp_diagnostic(text: &str, uri: &str, diagnostic: &Diagnostic) -> Value {
+    let severity = match diagnostic.severity {
+        Severity::Error => 1,
+        Severity::Warning => 2,
+        Severity::Note => 3
+    };
+    let start = Position { line: 1, column: 1 };
+    let mut message = diagnostic.message.clone();
+    for help in &diagnostic.help {
+        message += &format!("\nhelp: {}", help);
+    }
+    json!({
+        "range": range(text, diagnostic.span.unwrap_or(Span::new(start, start))),
+        "severity": severity,
+        "code": diagnostic.code,
+        "source": "monkey",
+        "message": message,
+        "relatedInformation": diagnostic.labels.iter().map(|label| json!({
+            "location": location(text, uri, label.span),
+            "message": label.message
+        })).collect::<Vec<Value>>()
+    })
+}
+
+// The source a span covers.
+fn source_of(text: &str, span: Span) -> String {
+    let mut result = String::new();
+    for (index, line) in text.lines().enumerate().skip(span.start.line - 1).take(span.end.line + 1 - span.start.line) {
+        let first = if index + 1 == span.start.line { span.start.column - 1 } else { 0 };
+        let last = if index + 1 == span.end.line { span.end.column - 1 } else { line.chars().count() };
+        if !result.is_empty() {
+            result.push('\n');
+        }
+        result.extend(line.chars().skip(first).take(last.saturating_sub(first)));
+    }
+    result
+}
+
+fn hover(document: &Document, index: usize) -> String {
+    let definition = &document.analysis.definitions[index];
+    match (definition.kind, definition.statement) {
+        (DefinitionKind::Let, Some(statement)) => format!("```monkey\n{}\n```", source_of(&document.text, statement)),
+        (DefinitionKind::Parameter, _) => format!("parameter `{}`", definition.name),
+        (DefinitionKind::CatchBinding, _) => format!("error caught as `{}`", definition.name),
+        _ => format!("builtin function `{}`", definition.name)
+    }
+}
+
+fn completion(document: &Document, index: usize) -> Value {
+    let definition = &document.analysis.definitions[index];
+    let (kind, detail) = match definition.kind {
+        DefinitionKind::Builtin => (COMPLETION_FUNCTION, "builtin".to_string()),
+        DefinitionKind::Let => {
+            let statement = definition.statement.map(|span| source_of(&document.text, span)).unwrap_or_default();
+            let first_line = statement.lines().next().unwrap_or_default().to_string();
+            (COMPLETION_VARIABLE, first_line)
+        }
+        DefinitionKind::Parameter => (COMPLETION_VARIABLE, "parameter".to_string()),
+        DefinitionKind::CatchBinding => (COMPLETION_VARIABLE, "caught error".to_string())
+    };
+    json!({"label": definition.name, "kind": kind, "detail": detail})
+}
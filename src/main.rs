@@ -1,30 +1,10 @@
-use crate::repl::ReplMode::{CompilerMode, InterpreterMode};
-
-mod token;
-mod lexer;
-#[cfg(test)]
-mod test_parser;
-mod repl;
-mod ast;
-mod parser;
-mod object;
-#[cfg(test)]
-mod test_evaluator;
-mod evaluator;
-mod environment;
-mod builtins;
-mod code;
-#[cfg(test)]
-mod test_code;
-mod compiler;
-#[cfg(test)]
-mod test_compiler;
-#[cfg(test)]
-mod test_vm;
-mod vm;
-mod symbol_table;
-#[cfg(test)]
-mod test_symbol_table;
+use std::io::{BufReader, IsTerminal};
+use monkey_interpreter::diagnostic::{colour_enabled, render_all};
+use monkey_interpreter::analysis::Analysis;
+use monkey_interpreter::lint::{LintConfig, Rule};
+use monkey_interpreter::diagnostic::Diagnostic;
+use monkey_interpreter::{debugger, lint, lsp, repl, types, Engine, VmConfig};
+use monkey_interpreter::repl::ReplMode::{CompilerMode, InterpreterMode};
 
 fn main() {
     let arg = std::env::args().nth(1);
@@ -39,6 +19,21 @@ fn main() {
                     "-i" => {
                         repl::start(InterpreterMode)
                     }
+                    "debug" => {
+                        debug(std::env::args().nth(2))
+                    }
+                    "run" => {
+                        run(std::env::args().skip(2).collect())
+                    }
+                    "lsp" => {
+                        lsp()
+                    }
+                    "lint" => {
+                        lint(std::env::args().skip(2).collect())
+                    }
+                    "check" => {
+                        check(std::env::args().skip(2).collect())
+                    }
                     _ =>
                         {
                             panic!("parameter: {} not supported!", content);
@@ -51,3 +46,194 @@ fn main() {
             }
     }
 }
+
+// monkey run [--profile] [--folded <output>] <file>
+fn run(args: Vec<String>) {
+    let usage = "usage: monkey run [--profile] [--folded <output>] <file>";
+    let mut profile = false;
+    let mut folded = None;
+    let mut path = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--profile" => profile = true,
+            "--folded" => match args.next() {
+                Some(output) => folded = Some(output),
+                None => {
+                    eprintln!("{}", usage);
+                    std::process::exit(2);
+                }
+            },
+            _ if path.is_none() => path = Some(arg),
+            _ => {
+                eprintln!("{}", usage);
+                std::process::exit(2);
+            }
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("{}", usage);
+        std::process::exit(2);
+    };
+    let source = match std::fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("could not read {}: {}", path, error);
+            std::process::exit(1);
+        }
+    };
+
+    let mut engine = Engine::new_with_config(VmConfig { profile: profile || folded.is_some(), ..VmConfig::default() });
+    let result = engine.run(&source);
+    if let Some(report) = engine.profile() {
+        if profile {
+            eprintln!("{}", report);
+        }
+        if let Some(output) = &folded {
+            if let Err(error) = std::fs::write(output, report.folded() + "\n") {
+                eprintln!("could not write {}: {}", output, error);
+            }
+        }
+    }
+    if let Err(error) = result {
+        let colour = colour_enabled(std::io::stderr().is_terminal());
+        eprintln!("{}", render_all(&error.diagnostics(), &path, &source, colour));
+        std::process::exit(1);
+    }
+}
+
+fn debug(path: Option<String>) {
+    let path = match path {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: monkey debug <file>");
+            std::process::exit(2);
+        }
+    };
+    let source = match std::fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("could not read {}: {}", path, error);
+            std::process::exit(1);
+        }
+    };
+    let input = Box::new(BufReader::new(std::io::stdin()));
+    match debugger::debug(&source, input, Box::new(std::io::stdout())) {
+        Ok(value) => println!("program finished: {}", value.inspect()),
+        Err(diagnostics) => {
+            let colour = colour_enabled(std::io::stderr().is_terminal());
+            eprintln!("{}", render_all(&diagnostics, &path, &source, colour));
+            std::process::exit(1);
+        }
+    }
+}
+
+// monkey lsp: a language server on stdin and stdout, for editors.
+fn lsp() {
+    match lsp::serve(std::io::stdin().lock(), std::io::stdout()) {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(error) => {
+            eprintln!("language server stopped: {}", error);
+            std::process::exit(1);
+        }
+    }
+}
+
+// monkey lint [--disable <rule>]... [--enable <rule>]... <file>
+fn lint(args: Vec<String>) {
+    let usage = "usage: monkey lint [--disable <rule>]... [--enable <rule>]... <file>";
+    let mut config = LintConfig::default();
+    let mut path = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--disable" | "--enable" => match args.next().as_deref().map(Rule::from_name) {
+                Some(Some(rule)) if arg == "--disable" => config.disable(rule),
+                Some(Some(rule)) => config.enable(rule),
+                Some(None) => {
+                    let rules: Vec<&str> = Rule::ALL.iter().map(Rule::name).collect();
+                    eprintln!("unknown rule; the rules are {}", rules.join(", "));
+                    std::process::exit(2);
+                }
+                None => {
+                    eprintln!("{}", usage);
+                    std::process::exit(2);
+                }
+            },
+            _ if path.is_none() => path = Some(arg),
+            _ => {
+                eprintln!("{}", usage);
+                std::process::exit(2);
+            }
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("{}", usage);
+        std::process::exit(2);
+    };
+    let source = match std::fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("could not read {}: {}", path, error);
+            std::process::exit(1);
+        }
+    };
+
+    let analysis = Analysis::new(&source);
+    let diagnostics = if analysis.errors.is_empty() {
+        lint::lint(&analysis, &config)
+    } else {
+        analysis.diagnostics()
+    };
+    if !diagnostics.is_empty() {
+        let colour = colour_enabled(std::io::stderr().is_terminal());
+        eprintln!("{}", render_all(&diagnostics, &path, &source, colour));
+        std::process::exit(1);
+    }
+}
+
+// monkey check [--types] <file>
+fn check(args: Vec<String>) {
+    let usage = "usage: monkey check [--types] <file>";
+    let mut show_types = false;
+    let mut path = None;
+    for arg in args {
+        match arg.as_str() {
+            "--types" => show_types = true,
+            _ if path.is_none() => path = Some(arg),
+            _ => {
+                eprintln!("{}", usage);
+                std::process::exit(2);
+            }
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("{}", usage);
+        std::process::exit(2);
+    };
+    let source = match std::fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("could not read {}: {}", path, error);
+            std::process::exit(1);
+        }
+    };
+
+    let analysis = Analysis::new(&source);
+    let mut diagnostics = analysis.diagnostics();
+    if diagnostics.is_empty() {
+        let checked = types::check(&analysis.program);
+        if show_types {
+            for (name, ty) in &checked.bindings {
+                println!("{}: {}", name, ty);
+            }
+        }
+        diagnostics = checked.errors.iter().map(Diagnostic::from).collect();
+    }
+    if !diagnostics.is_empty() {
+        let colour = colour_enabled(std::io::stderr().is_terminal());
+        eprintln!("{}", render_all(&diagnostics, &path, &source, colour));
+        std::process::exit(1);
+    }
+}
@@ -1,9 +1,12 @@
 use strum_macros::IntoStaticStr;
 use crate::ast::{Identifier, Statement};
 use crate::environment::Environment;
+use std::cell::{Ref, RefCell};
 use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
 use std::hash::{Hash, Hasher};
-use crate::code::Instructions;
+use std::rc::Rc;
+use crate::code::{Instructions, SourceMap};
 
 #[derive(Debug, PartialEq, Clone,IntoStaticStr)]
 pub enum Object {
@@ -14,8 +17,8 @@ pub enum Object {
     Error(String),
     Function(FunctionStruct),
     CompiledFunction(CompiledFunctionStruct),
-    BuiltIn(BuiltInFn),
-    Array(Vec<Box<Object>>),
+    BuiltIn(BuiltInFunction),
+    Array(ArrayStruct),
     HashMap(HashMap<Object, Object>),
     Closure(ClosureStruct),
     Null
@@ -58,7 +61,7 @@ impl Object {
             },
             Object::Array(content) => {
                 let mut result = "[".to_string();
-                result = result + content.clone().into_iter().map(| arg | arg.as_ref().inspect()).collect::<Vec<String>>().join(",").as_str() + "]";
+                result = result + content.elements().iter().map(| arg | arg.inspect()).collect::<Vec<String>>().join(",").as_str() + "]";
                 result
             }
             Object::ReturnValue(content) => {
@@ -147,6 +150,25 @@ impl Object {
             _ => false
         }
     }
+
+    // Rough number of bytes owned by this value. Arrays and hashes are counted
+    // with all of their elements, even where rest and push share them with
+    // another array. Functions are counted as a single object since their code
+    // belongs to the program.
+    pub fn approximate_size(&self) -> usize {
+        let own = std::mem::size_of::<Object>();
+        match self {
+            Object::StringObject(content) | Object::Error(content) => own + content.len(),
+            Object::ReturnValue(content) => own + content.approximate_size(),
+            Object::Array(content) => {
+                own + content.elements().iter().map(|element| element.approximate_size()).sum::<usize>()
+            }
+            Object::HashMap(content) => {
+                own + content.iter().map(|(key, value)| key.approximate_size() + value.approximate_size()).sum::<usize>()
+            }
+            _ => own
+        }
+    }
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -179,13 +201,167 @@ impl FunctionStruct {
     }
 }
 
-pub type BuiltInFn = fn(Vec<Object>) -> Object;
+pub type BuiltInFn = fn(Vec<Object>, &mut dyn FunctionCaller) -> Object;
 
-#[derive(Debug, PartialEq, Clone)]
+pub type NativeFn = dyn Fn(Vec<Object>, &mut dyn FunctionCaller) -> Object;
+
+// A named native function. Two values are equal only when they share the
+// same underlying function, which keeps Object comparable.
+#[derive(Clone)]
+pub struct BuiltInFunction {
+    pub name: String,
+    function: Rc<NativeFn>
+}
+
+impl BuiltInFunction {
+    pub fn new<F>(name: &str, function: F) -> Self
+    where
+        F: Fn(Vec<Object>, &mut dyn FunctionCaller) -> Object + 'static
+    {
+        BuiltInFunction {
+            name: name.to_string(),
+            function: Rc::new(function)
+        }
+    }
+
+    pub fn call(&self, args: Vec<Object>, caller: &mut dyn FunctionCaller) -> Object {
+        (self.function)(args, caller)
+    }
+}
+
+impl PartialEq for BuiltInFunction {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && Rc::ptr_eq(&self.function, &other.function)
+    }
+}
+
+impl Debug for BuiltInFunction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BuiltInFunction({})", self.name)
+    }
+}
+
+// An array, which is never changed once made. It is a window onto a buffer
+// that rest and push share with the array they came from, so that walking or
+// building a list one element at a time doesn't copy it each time. push only
+// appends to the buffer when the window reaches its end, since no other array
+// can see past its own end.
+#[derive(Clone)]
+pub struct ArrayStruct {
+    buffer: Rc<RefCell<Vec<Object>>>,
+    start: usize,
+    end: usize
+}
+
+impl ArrayStruct {
+    pub fn new(elements: Vec<Object>) -> Self {
+        ArrayStruct {
+            start: 0,
+            end: elements.len(),
+            buffer: Rc::new(RefCell::new(elements))
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn get(&self, index: usize) -> Option<Object> {
+        self.elements().get(index).cloned()
+    }
+
+    pub fn to_vec(&self) -> Vec<Object> {
+        self.elements().to_vec()
+    }
+
+    // Every element but the first, or an empty array if there are none.
+    pub fn rest(&self) -> ArrayStruct {
+        ArrayStruct { buffer: self.buffer.clone(), start: usize::min(self.start + 1, self.end), end: self.end }
+    }
+
+    pub fn push(&self, element: Object) -> ArrayStruct {
+        // An array pushed onto itself would leave the buffer holding itself.
+        let contains_self = matches!(&element, Object::Array(other) if other.shares_elements(self));
+        let mut buffer = self.buffer.borrow_mut();
+        if buffer.len() == self.end && !contains_self {
+            buffer.push(element);
+            return ArrayStruct { buffer: self.buffer.clone(), start: self.start, end: self.end + 1 };
+        }
+        let mut elements = buffer[self.start..self.end].to_vec();
+        elements.push(element);
+        ArrayStruct::new(elements)
+    }
+
+    // Whether the two arrays are windows onto the same buffer.
+    pub fn shares_elements(&self, other: &ArrayStruct) -> bool {
+        Rc::ptr_eq(&self.buffer, &other.buffer)
+    }
+
+    // Callers must not run Monkey code while holding this, since a push from
+    // that code could need the buffer.
+    fn elements(&self) -> Ref<'_, [Object]> {
+        Ref::map(self.buffer.borrow(), |buffer| &buffer[self.start..self.end])
+    }
+}
+
+impl PartialEq for ArrayStruct {
+    fn eq(&self, other: &Self) -> bool {
+        *self.elements() == *other.elements()
+    }
+}
+
+impl Debug for ArrayStruct {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.elements().iter()).finish()
+    }
+}
+
+impl From<Vec<Object>> for ArrayStruct {
+    fn from(elements: Vec<Object>) -> Self {
+        ArrayStruct::new(elements)
+    }
+}
+
+impl FromIterator<Object> for ArrayStruct {
+    fn from_iter<I: IntoIterator<Item = Object>>(elements: I) -> Self {
+        ArrayStruct::new(elements.into_iter().collect())
+    }
+}
+
+// Implemented by each engine so that builtins can call back into Monkey
+// functions and closures, e.g. the callback passed to map or filter.
+pub trait FunctionCaller {
+    fn call_function(&mut self, function: Object, args: Vec<Object>) -> Object;
+
+    // Builtins report the bytes of every value they create. Returns false once
+    // the run is out of memory, in which case the builtin should return an
+    // error without building the value.
+    fn allocate(&mut self, _bytes: usize) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct CompiledFunctionStruct {
     pub instructions: Instructions,
     pub num_vars: usize,
-    pub num_args: usize
+    pub num_args: usize,
+    // Debug information: the name the function was bound to with let, if any,
+    // the names of its locals by slot, and where each of its statements came from.
+    pub name: Option<String>,
+    pub local_names: Vec<String>,
+    pub source_map: SourceMap
+}
+
+// Two functions are the same if they run the same code; where it came from doesn't matter.
+impl PartialEq for CompiledFunctionStruct {
+    fn eq(&self, other: &Self) -> bool {
+        self.instructions == other.instructions && self.num_vars == other.num_vars && self.num_args == other.num_args
+    }
 }
 
 impl CompiledFunctionStruct
@@ -198,7 +374,8 @@ impl CompiledFunctionStruct
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct ClosureStruct {
-    pub function: CompiledFunctionStruct,
+    // Shared, so that calls don't copy the function's code.
+    pub function: Rc<CompiledFunctionStruct>,
     pub free_vars: Vec<Box<Object>>
 }
 
@@ -207,3 +384,96 @@ impl ClosureStruct {
         format!("Closure[{}]", self.function.inspect())
     }
 }
+
+impl From<i64> for Object {
+    fn from(value: i64) -> Self {
+        Object::IntegerObject(value)
+    }
+}
+
+impl From<bool> for Object {
+    fn from(value: bool) -> Self {
+        Object::BooleanObject(value)
+    }
+}
+
+impl From<&str> for Object {
+    fn from(value: &str) -> Self {
+        Object::StringObject(value.to_string())
+    }
+}
+
+impl From<String> for Object {
+    fn from(value: String) -> Self {
+        Object::StringObject(value)
+    }
+}
+
+impl<T: Into<Object>> From<Vec<T>> for Object {
+    fn from(value: Vec<T>) -> Self {
+        Object::Array(value.into_iter().map(|element| element.into()).collect())
+    }
+}
+
+impl<K: Into<Object>, V: Into<Object>> From<HashMap<K, V>> for Object {
+    fn from(value: HashMap<K, V>) -> Self {
+        Object::HashMap(value.into_iter().map(|(key, value)| (key.into(), value.into())).collect())
+    }
+}
+
+impl<T: Into<Object>> From<Option<T>> for Object {
+    fn from(value: Option<T>) -> Self {
+        match value {
+            Some(content) => content.into(),
+            None => Object::Null
+        }
+    }
+}
+
+fn conversion_error(expected: &str, found: &Object) -> String {
+    format!("expected {}, found {}", expected, found.get_type())
+}
+
+impl TryFrom<Object> for i64 {
+    type Error = String;
+
+    fn try_from(value: Object) -> Result<Self, Self::Error> {
+        match value {
+            Object::IntegerObject(content) => Ok(content),
+            other => Err(conversion_error("INTEGER", &other))
+        }
+    }
+}
+
+impl TryFrom<Object> for bool {
+    type Error = String;
+
+    fn try_from(value: Object) -> Result<Self, Self::Error> {
+        match value {
+            Object::BooleanObject(content) => Ok(content),
+            other => Err(conversion_error("BOOLEAN", &other))
+        }
+    }
+}
+
+impl TryFrom<Object> for String {
+    type Error = String;
+
+    fn try_from(value: Object) -> Result<Self, Self::Error> {
+        match value {
+            Object::StringObject(content) => Ok(content),
+            other => Err(conversion_error("STRING", &other))
+        }
+    }
+}
+
+impl<T: TryFrom<Object, Error = String>> TryFrom<Object> for Vec<T> {
+    type Error = String;
+
+    fn try_from(value: Object) -> Result<Self, Self::Error> {
+        match value {
+            Object::Array(content) => content.to_vec().into_iter().map(T::try_from).collect(),
+            other => Err(conversion_error("ARRAY", &other))
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use crate::ast::Expression;
+use crate::code::{join_instructions, make, Instructions, Opcode, SourceMap};
+use crate::object::Object;
+use crate::token::Token;
+
+// Evaluates an expression made only of integer and boolean literals, following
+// the VM's rules. Returns None for anything else, and for operations the VM
+// would reject, such as a division by zero or an overflow, so that those still
+// fail at run time.
+pub fn fold_constant(expr: &Expression) -> Option<Object> {
+    match expr {
+        Expression::IntegerExpression(value) => Some(Object::IntegerObject(*value)),
+        Expression::BoolExpression(value) => Some(Object::BooleanObject(*value)),
+        Expression::PrefixExpression(operator, inner) => match (operator, fold_constant(inner)?) {
+            (Token::MINUS, Object::IntegerObject(value)) => value.checked_neg().map(Object::IntegerObject),
+            (Token::BANG, Object::BooleanObject(value)) => Some(Object::BooleanObject(!value)),
+            _ => None
+        },
+        Expression::InfixExpression(left, operator, right) => {
+            match (fold_constant(left)?, fold_constant(right)?) {
+                (Object::IntegerObject(left), Object::IntegerObject(right)) => match operator {
+                    Token::PLUS => left.checked_add(right).map(Object::IntegerObject),
+                    Token::MINUS => left.checked_sub(right).map(Object::IntegerObject),
+                    Token::ASTERISK => left.checked_mul(right).map(Object::IntegerObject),
+                    Token::SLASH if right != 0 => left.checked_div(right).map(Object::IntegerObject),
+                    Token::EQ => Some(Object::BooleanObject(left == right)),
+                    Token::NotEq => Some(Object::BooleanObject(left != right)),
+                    Token::GT => Some(Object::BooleanObject(left > right)),
+                    Token::LT => Some(Object::BooleanObject(left < right)),
+                    _ => None
+                },
+                (Object::BooleanObject(left), Object::BooleanObject(right)) => match operator {
+                    Token::EQ => Some(Object::BooleanObject(left == right)),
+                    Token::NotEq => Some(Object::BooleanObject(left != right)),
+                    _ => None
+                },
+                _ => None
+            }
+        }
+        _ => None
+    }
+}
+
+// One decoded instruction. offset is where it was in the unoptimised code and
+// jump operands keep pointing at those original offsets until layout.
+#[derive(Debug, Clone)]
+struct Instruction {
+    offset: usize,
+    code: Opcode,
+    operands: Vec<usize>
+}
+
+fn is_jump(code: &Opcode) -> bool {
+    matches!(code, Opcode::OpJump | Opcode::OpJumpNotTrue | Opcode::OpTry | Opcode::OpJumpNotEq | Opcode::OpJumpNotGreater)
+}
+
+// Rewrites the instructions of one function or of the main program:
+// - OpTrue; OpJumpNotTrue is dropped and OpFalse or OpNull; OpJumpNotTrue
+//   becomes OpJump,
+// - code that can't be reached after OpJump, OpReturnValue or OpReturn is
+//   removed,
+// - jumps to the next instruction are removed,
+// - common sequences are fused into superinstructions.
+// Jump targets and the source map are moved to match.
+pub fn optimize(instructions: &Instructions, source_map: &SourceMap) -> (Instructions, SourceMap) {
+    let Some(mut code) = decode(instructions) else {
+        // Not something the compiler produced; leave it alone.
+        return (instructions.clone(), source_map.clone());
+    };
+
+    while simplify_constant_jumps(&mut code) | remove_unreachable(&mut code) | remove_jumps_to_next(&mut code) {}
+    fuse(&mut code);
+
+    layout(&code, source_map)
+}
+
+// Points the jump at each offset in jumps to its target, both as offsets into
+// instructions, and lays the code out again with wide operands where the new
+// targets need them. The compiler uses this for jumps it couldn't patch in
+// place.
+pub fn widen_jumps(instructions: &Instructions, source_map: &SourceMap, jumps: &[(usize, usize)]) -> (Instructions, SourceMap) {
+    let mut code = decode(instructions).expect("couldn't decode instructions");
+    for (offset, target) in jumps {
+        let index = resolve(&code, *offset);
+        code[index].operands[0] = *target;
+    }
+    layout(&code, source_map)
+}
+
+// Turns every OpCall whose value is returned straight away, because the next
+// instruction is OpReturnValue or a jump to one, into OpTailCall. Both take the
+// same operands, so nothing moves.
+pub fn mark_tail_calls(instructions: &Instructions) -> Instructions {
+    let mut marked = instructions.clone();
+    let returns_at = |offset: usize| match instructions.decode(offset) {
+        Some((Opcode::OpReturnValue, _, _)) => true,
+        Some((Opcode::OpJump, operands, _)) => matches!(instructions.decode(operands[0]), Some((Opcode::OpReturnValue, _, _))),
+        _ => false
+    };
+    let mut offset = 0;
+    while let Some((op, _, width)) = instructions.decode(offset) {
+        if op == Opcode::OpCall && returns_at(offset + width) {
+            let wide = instructions.content[offset] == Opcode::OpWide as u8;
+            marked.content[offset + wide as usize] = Opcode::OpTailCall as u8;
+        }
+        offset += width;
+    }
+    marked
+}
+
+fn decode(instructions: &Instructions) -> Option<Vec<Instruction>> {
+    let mut code = Vec::new();
+    let mut offset = 0;
+    while let Some((op, operands, width)) = instructions.decode(offset) {
+        code.push(Instruction { offset, code: op, operands });
+        offset += width;
+    }
+    (offset == instructions.content.len()).then_some(code)
+}
+
+// The index of the instruction a jump to the original offset target now lands
+// on, which is code.len() for the end of the code.
+fn resolve(code: &[Instruction], target: usize) -> usize {
+    code.partition_point(|instruction| instruction.offset < target)
+}
+
+fn targets(code: &[Instruction]) -> Vec<bool> {
+    let mut targets = vec![false; code.len() + 1];
+    for instruction in code.iter().filter(|instruction| is_jump(&instruction.code)) {
+        targets[resolve(code, instruction.operands[0])] = true;
+    }
+    targets
+}
+
+fn simplify_constant_jumps(code: &mut Vec<Instruction>) -> bool {
+    let targets = targets(code);
+    for i in 0..code.len().saturating_sub(1) {
+        if code[i + 1].code != Opcode::OpJumpNotTrue || targets[i + 1] {
+            continue;
+        }
+        match code[i].code {
+            Opcode::OpTrue => {
+                code.drain(i..i + 2);
+                return true;
+            }
+            Opcode::OpFalse | Opcode::OpNull => {
+                let jump = code.remove(i + 1);
+                code[i].code = Opcode::OpJump;
+                code[i].operands = jump.operands;
+                return true;
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+fn remove_unreachable(code: &mut Vec<Instruction>) -> bool {
+    let targets = targets(code);
+    let mut keep = Vec::with_capacity(code.len());
+    let mut reachable = true;
+    for (i, instruction) in code.iter().enumerate() {
+        reachable |= targets[i];
+        keep.push(reachable);
+        if matches!(instruction.code, Opcode::OpJump | Opcode::OpReturnValue | Opcode::OpReturn) {
+            reachable = false;
+        }
+    }
+    let before = code.len();
+    let mut flags = keep.into_iter();
+    code.retain(|_| flags.next().unwrap_or(true));
+    code.len() != before
+}
+
+// A jump removed here is only ever the target of other jumps that now land
+// on the instruction after it, which is where it would have sent them. A
+// conditional jump also checks that its condition can be one, so it is only
+// dropped when the condition comes from an instruction that always pushes an
+// integer, a boolean or null.
+fn remove_jumps_to_next(code: &mut Vec<Instruction>) -> bool {
+    let targets = targets(code);
+    let mut changed = false;
+    let mut i = 0;
+    while i < code.len() {
+        let lands_next = matches!(code[i].code, Opcode::OpJump | Opcode::OpJumpNotTrue)
+            && resolve(code, code[i].operands[0]) == i + 1;
+        if lands_next && code[i].code == Opcode::OpJump {
+            code.remove(i);
+            return true;
+        }
+        if lands_next && i > 0 && !targets[i] && pushes_condition(&code[i - 1].code) {
+            // Either way the condition is popped and execution carries on.
+            code[i].code = Opcode::OpPop;
+            code[i].operands = vec![];
+            changed = true;
+        }
+        i += 1;
+    }
+    changed
+}
+
+fn pushes_condition(code: &Opcode) -> bool {
+    matches!(code, Opcode::OpTrue | Opcode::OpFalse | Opcode::OpNull | Opcode::OpEq | Opcode::OpNotEq
+        | Opcode::OpGreaterThan | Opcode::OpBang | Opcode::OpMinus)
+}
+
+// Only instructions that no jump lands on are fused into the one before them.
+fn fuse(code: &mut Vec<Instruction>) {
+    let targets = targets(code);
+    let mut fused = Vec::with_capacity(code.len());
+    let mut i = 0;
+    while i < code.len() {
+        let free = |count: usize| i + count < code.len() && (1..=count).all(|next| !targets[i + next]);
+        let arithmetic = match code.get(i + 2).map(|instruction| &instruction.code) {
+            Some(Opcode::OpAdd) => Some(Opcode::OpAddLocalConstant),
+            Some(Opcode::OpSub) => Some(Opcode::OpSubLocalConstant),
+            _ => None
+        };
+        let comparison = match (&code[i].code, code.get(i + 1).map(|instruction| &instruction.code)) {
+            (Opcode::OpEq, Some(Opcode::OpJumpNotTrue)) => Some(Opcode::OpJumpNotEq),
+            (Opcode::OpGreaterThan, Some(Opcode::OpJumpNotTrue)) => Some(Opcode::OpJumpNotGreater),
+            _ => None
+        };
+        match (arithmetic, comparison) {
+            (Some(superinstruction), _) if code[i].code == Opcode::OpGetLocal && code[i + 1].code == Opcode::OpConstant && free(2) => {
+                fused.push(Instruction {
+                    offset: code[i].offset,
+                    code: superinstruction,
+                    operands: vec![code[i].operands[0], code[i + 1].operands[0]]
+                });
+                i += 3;
+            }
+            (_, Some(superinstruction)) if free(1) => {
+                fused.push(Instruction { offset: code[i].offset, code: superinstruction, operands: code[i + 1].operands.clone() });
+                i += 2;
+            }
+            _ => {
+                fused.push(code[i].clone());
+                i += 1;
+            }
+        }
+    }
+    *code = fused;
+}
+
+// A jump is only wide if its new target needs it, and the targets move with
+// the widths of the jumps before them. Widths only grow from one round to the
+// next, so starting from nothing this settles on the narrowest layout.
+fn layout(code: &[Instruction], source_map: &SourceMap) -> (Instructions, SourceMap) {
+    let mut widths = vec![0; code.len()];
+    loop {
+        let mut new_offsets = Vec::with_capacity(code.len() + 1);
+        let mut offset = 0;
+        for width in &widths {
+            new_offsets.push(offset);
+            offset += width;
+        }
+        new_offsets.push(offset);
+        let new_offset = |original: usize| new_offsets[resolve(code, original)];
+
+        let encoded: Vec<Instructions> = code.iter().map(|instruction| {
+            let mut operands = instruction.operands.clone();
+            if is_jump(&instruction.code) {
+                operands[0] = new_offset(operands[0]);
+            }
+            make(instruction.code.clone(), operands).expect("couldn't make instruction")
+        }).collect();
+        let new_widths: Vec<usize> = encoded.iter().map(|instruction| instruction.content.len()).collect();
+        if new_widths == widths {
+            return (join_instructions(encoded), source_map.remap(new_offset));
+        }
+        widths = new_widths;
+    }
+}
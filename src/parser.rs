@@ -1,7 +1,9 @@
-use crate::ast::{Identifier, Program, Statement, Expression, IfStruct, FnStruct, CallStruct, ArrayStruct, IndexStruct, HashStruct};
+use std::fmt::{Display, Formatter};
+use crate::diagnostic::{Diagnostic, Label, SYNTAX_ERROR};
+use crate::ast::{Identifier, Program, Statement, Expression, IfStruct, FnStruct, CallStruct, ArrayStruct, IndexStruct, HashStruct, TryStruct};
 use crate::lexer::Lexer;
 use crate::parser::Precedence::Lowest;
-use crate::token::Token;
+use crate::token::{Span, Token};
 use crate::token::Token::{COMMA, RBRACE};
 
 #[derive(Copy, Clone, Debug)]
@@ -59,11 +61,53 @@ impl Precedence {
         }
     }
 }
+// A syntax error and the source it was found at.
+#[derive(PartialEq, Debug, Clone)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+    // Other places that explain the error, such as an unclosed delimiter.
+    pub labels: Vec<Label>
+}
+
+impl From<&ParseError> for Diagnostic {
+    fn from(error: &ParseError) -> Self {
+        let mut diagnostic = Diagnostic::error(SYNTAX_ERROR, &error.message).with_span(Some(error.span));
+        diagnostic.labels = error.labels.clone();
+        diagnostic
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.span.start, self.message)
+    }
+}
+
+// How a token reads in an error message.
+fn describe(tok: &Token) -> String {
+    match tok {
+        Token::IDENTIFIER(content) if content.is_empty() => "an identifier".to_string(),
+        Token::STRING(content) => format!("\"{}\"", content),
+        Token::EOF => "end of input".to_string(),
+        Token::ILLEGAL => "an illegal character".to_string(),
+        _ => format!("`{}`", tok.inspect())
+    }
+}
+
 pub struct Parser{
     lexer: Lexer,
     cur_token: Token,
     peek_token: Token,
-    pub errors: Vec<String>
+    cur_span: Span,
+    peek_span: Span,
+    // How many braces are open at cur_token, so recovery knows which block
+    // it is in.
+    depth: usize,
+    // Set from an error until the parser resynchronises at the end of the
+    // statement, so one mistake is only reported once.
+    recovering: bool,
+    pub errors: Vec<ParseError>
 }
 
 impl Parser{
@@ -74,6 +118,10 @@ impl Parser{
             lexer,
             cur_token: Token::ILLEGAL,
             peek_token: Token::ILLEGAL,
+            cur_span: Span::default(),
+            peek_span: Span::default(),
+            depth: 0,
+            recovering: false,
             errors
         };
 
@@ -85,11 +133,67 @@ impl Parser{
 
     fn next_token(&mut self) {
         self.cur_token = self.peek_token.clone();
+        self.cur_span = self.peek_span;
         self.peek_token = self.lexer.next_token();
+        self.peek_span = self.lexer.span();
+        match self.cur_token {
+            Token::LBRACE => self.depth += 1,
+            Token::RBRACE => self.depth = self.depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    fn error(&mut self, message: String, span: Span) {
+        if !self.recovering {
+            self.errors.push(ParseError { message, span, labels: Vec::new() });
+            self.recovering = true;
+        }
     }
 
-    fn peek_error(&mut self, token: Token) {
-        self.errors.push(format!("Expected {} and found {}", self.peek_token.get_type(), token.get_type()));
+    fn peek_error(&mut self, expected: &str) {
+        let message = format!("expected {}, found {}", expected, describe(&self.peek_token));
+        self.error(message, self.peek_span);
+    }
+
+    // An error for a missing delimiter, pointing back at the one it would
+    // close.
+    fn unclosed(&mut self, message: String, span: Span, opened: Span) {
+        let reported = !self.recovering;
+        self.error(message, span);
+        if let (true, Some(error)) = (reported, self.errors.last_mut()) {
+            error.labels.push(Label::new(opened, "opened here"));
+        }
+    }
+
+    fn unclosed_error(&mut self, expected: &str, opened: Span) {
+        let message = format!("expected {}, found {}", expected, describe(&self.peek_token));
+        self.unclosed(message, self.peek_span, opened);
+    }
+
+    // Consumes the delimiter closing the one at opened.
+    fn close(&mut self, token: Token, opened: Span) -> bool {
+        if self.peek_token == token {
+            self.next_token();
+            return true;
+        }
+        self.unclosed_error(&describe(&token), opened);
+        false
+    }
+
+    // After an error, skips what is left of the statement: up to its `;`, the
+    // start of the next statement, or the brace that closes the block at depth.
+    fn synchronize(&mut self, depth: usize) {
+        if !self.recovering {
+            return;
+        }
+        self.recovering = false;
+        while self.cur_token != Token::EOF && self.depth >= depth {
+            if self.depth == depth && (self.cur_token == Token::SEMICOLON
+                || matches!(self.peek_token, Token::LET | Token::RETURN | Token::RBRACE | Token::EOF)) {
+                return;
+            }
+            self.next_token();
+        }
     }
 
     fn peek_precedence(&self) -> Precedence{
@@ -102,32 +206,32 @@ impl Parser{
             self.next_token();
             true
         } else {
-            self.peek_error(token);
+            self.peek_error(&describe(&token));
             false
         }
 
     }
 
     fn parse_let_statement(&mut self) -> Option<Statement>{
+        let start = self.cur_span;
         if self.peek_token(Token::IDENTIFIER("".to_string()))
         {
             if let Token::IDENTIFIER(content) = self.cur_token.clone()
             {
-                let identifier = Identifier::new(content);
+                let identifier = Identifier::new_at(content, self.cur_span);
                 let expr = if self.peek_token(Token::ASSIGN)
                 {
                     self.next_token();
                     self.parse_expr(Lowest)
                 }
                 else {
-                    self.next_token();
                     return None;
                 };
 
                 if self.peek_token == Token::SEMICOLON {
                     self.next_token();
                 }
-                return Some(Statement::LetStatement(identifier, expr));
+                return Some(Statement::LetStatement(identifier, expr, start.to(self.cur_span)));
             }
         }
         None
@@ -135,22 +239,24 @@ impl Parser{
 
     fn parse_return_statement(&mut self) -> Option<Statement>
     {
+        let start = self.cur_span;
         self.next_token();
         let expr = self.parse_expr(Lowest);
         if self.peek_token == Token::SEMICOLON {
             self.next_token();
         }
-        Some(Statement::ReturnStatement(expr))
+        Some(Statement::ReturnStatement(expr, start.to(self.cur_span)))
     }
 
     fn parse_expr_statement(&mut self) -> Option<Statement>
     {
-        let stmt = Statement::ExpressionStatement(self.parse_expr(Precedence::Lowest));
+        let start = self.cur_span;
+        let expr = self.parse_expr(Precedence::Lowest);
         if self.peek_token == Token::SEMICOLON
         {
             self.next_token();
         }
-        Some(stmt)
+        Some(Statement::ExpressionStatement(expr, start.to(self.cur_span)))
     }
 
     fn parse_prefix_expr(& mut self) -> Expression {
@@ -168,11 +274,12 @@ impl Parser{
     }
 
     fn parse_group_expression(& mut self) -> Option<Expression> {
+        let opened = self.cur_span;
         self.next_token();
 
         let exp = self.parse_expr(Lowest);
 
-        if !self.peek_token(Token::RPAREN)
+        if !self.close(Token::RPAREN, opened)
         {
             return None;
         }
@@ -185,10 +292,13 @@ impl Parser{
             return None;
         }
 
+        let opened = self.cur_span;
         self.next_token();
+        let condition_start = self.cur_span;
         let condition = self.parse_expr(Lowest);
+        let condition_span = condition_start.to(self.cur_span);
 
-        if !self.peek_token(Token::RPAREN)
+        if !self.close(Token::RPAREN, opened)
         {
             return None;
         }
@@ -215,13 +325,49 @@ impl Parser{
         else {
             None
         };
-        Some(Expression::IfExpression(IfStruct{condition: Box::new(condition), consequence: consequence, alternative}))
+        Some(Expression::IfExpression(IfStruct{condition: Box::new(condition), condition_span, consequence, alternative}))
 
 
     }
 
+    fn parse_try_expression(&mut self) -> Option<Expression> {
+        if !self.peek_token(Token::LBRACE)
+        {
+            return None;
+        }
+
+        let body = self.parse_block_statement();
+
+        if !self.peek_token(Token::CATCH) || !self.peek_token(Token::LPAREN)
+        {
+            return None;
+        }
+
+        if !self.peek_token(Token::IDENTIFIER("".to_string()))
+        {
+            return None;
+        }
+        let error = if let Token::IDENTIFIER(content) = &self.cur_token {
+            Identifier::new_at(content.clone(), self.cur_span)
+        }
+        else {
+            return None;
+        };
+
+        if !self.peek_token(Token::RPAREN) || !self.peek_token(Token::LBRACE)
+        {
+            return None;
+        }
+
+        let handler = self.parse_block_statement();
+
+        Some(Expression::TryExpression(TryStruct{body, error, handler}))
+    }
+
     fn parse_block_statement(&mut self) -> Vec<Statement>
     {
+        let depth = self.depth;
+        let opened = self.cur_span;
         let mut block_statement = Vec::new();
         self.next_token();
         while self.cur_token != Token::RBRACE && self.cur_token != Token::EOF {
@@ -229,8 +375,16 @@ impl Parser{
             if let Some(content) = stmt {
                 block_statement.push(content)
             }
+            self.synchronize(depth);
+            // Recovering stopped on the closing brace itself.
+            if self.depth < depth {
+                break;
+            }
             self.next_token();
         }
+        if self.cur_token == Token::EOF {
+            self.unclosed("expected `}`, found end of input".to_string(), self.cur_span, opened);
+        }
         block_statement
     }
 
@@ -245,21 +399,21 @@ impl Parser{
         self.next_token();
 
         if let Token::IDENTIFIER(content) = &self.cur_token {
-            params.push(Identifier::new(content.clone()));
+            params.push(Identifier::new_at(content.clone(), self.cur_span));
         }
         else {
+            self.error(format!("expected a parameter name, found {}", describe(&self.cur_token)), self.cur_span);
             return None;
         }
 
         while self.peek_token == Token::COMMA {
             self.next_token();
-            self.next_token();
-            if let Token::IDENTIFIER(content) = &self.cur_token {
-                params.push(Identifier::new(content.clone()));
-            }
-            else{
+            if !self.peek_token(Token::IDENTIFIER("".to_string())) {
                 return None;
             }
+            if let Token::IDENTIFIER(content) = &self.cur_token {
+                params.push(Identifier::new_at(content.clone(), self.cur_span));
+            }
         }
 
         if !self.peek_token(Token::RPAREN) {
@@ -270,11 +424,12 @@ impl Parser{
     }
     
     fn parse_fn_literal(& mut self) -> Option<Expression> {
+        let start = self.cur_span;
         if !self.peek_token(Token::LPAREN){
             return None;
         }
 
-        let params = self.parse_params().expect("Error reading params");
+        let params = self.parse_params()?;
 
         if !self.peek_token(Token::LBRACE){
             return None;
@@ -282,10 +437,11 @@ impl Parser{
 
         let body = self.parse_block_statement();
 
-        Some(Expression::FnExpression(FnStruct{params, body}))
+        Some(Expression::FnExpression(FnStruct{params, body, span: start.to(self.cur_span)}))
     }
     
     fn parse_call_args_expr(& mut self) -> Option<Vec<Expression>> {
+        let opened = self.cur_span;
         let mut args = Vec::new();
 
         if self.peek_token == Token::RPAREN {
@@ -302,10 +458,12 @@ impl Parser{
             args.push(self.parse_expr(Lowest));
         }
 
-        if !self.peek_token(Token::RPAREN)
+        if self.peek_token != Token::RPAREN
         {
+            self.unclosed_error("`,` or `)`", opened);
             return None;
         }
+        self.next_token();
         Some(args)
     }
     
@@ -325,6 +483,7 @@ impl Parser{
 
     fn parse_expression_list(&mut self) -> Option<Vec<Expression>>
     {
+        let opened = self.cur_span;
         let mut args = Vec::new();
 
         if self.peek_token == Token::RBRACKET {
@@ -341,10 +500,12 @@ impl Parser{
             args.push(self.parse_expr(Lowest));
         }
 
-        if !self.peek_token(Token::RBRACKET)
+        if self.peek_token != Token::RBRACKET
         {
+            self.unclosed_error("`,` or `]`", opened);
             return None;
         }
+        self.next_token();
         Some(args)
     }
     fn parse_array_literal(&mut self) -> Option<Expression>
@@ -360,10 +521,11 @@ impl Parser{
 
     fn parse_index_expr(&mut self, left: Expression) -> Option<Expression>
     {
+        let opened = self.cur_span;
         self.next_token();
         let index = self.parse_expr(Lowest);
 
-        if !self.peek_token(Token::RBRACKET)
+        if !self.close(Token::RBRACKET, opened)
         {
             None
         }
@@ -374,6 +536,7 @@ impl Parser{
 
     fn parse_hash_expr(&mut self) -> Option<Expression>
     {
+        let opened = self.cur_span;
         let mut result = HashStruct::new();
         while self.peek_token != Token::RBRACE
         {
@@ -386,8 +549,11 @@ impl Parser{
             self.next_token();
             let value = self.parse_expr(Lowest);
             result.pairs.push((key.clone(), value.clone()));
-            if self.peek_token != RBRACE && !self.peek_token(COMMA)
-            {
+            if self.peek_token == COMMA {
+                self.next_token();
+            }
+            else if self.peek_token != RBRACE {
+                self.unclosed_error("`,` or `}`", opened);
                 return None;
             }
         }
@@ -400,111 +566,97 @@ impl Parser{
 
     fn parse_expr(& mut self, prec: Precedence) -> Expression
     {
-
-        let mut expr = match &self.cur_token
+        let start = self.cur_span;
+        let prefix = match &self.cur_token
         {
             Token::IDENTIFIER(_) =>
                 {
-                    self.parse_identifier().unwrap()
+                    self.parse_identifier()
                 },
             Token::TRUE =>
                 {
-                Expression::BoolExpression(true)
+                Some(Expression::BoolExpression(true))
                 },
             Token::STRING(content) =>
                 {
-                    Expression::StringExpression(content.clone())
+                    Some(Expression::StringExpression(content.clone()))
                 }
             Token::FUNCTION =>
                 {
-                    self.parse_fn_literal().expect("Couldn't parse function expression")
+                    self.parse_fn_literal()
                 }
             Token::IF => {
-                self.parse_if_expression().expect("Couldn't parse if expression")
+                self.parse_if_expression()
+            }
+            Token::TRY => {
+                self.parse_try_expression()
             }
             Token::FALSE =>
                 {
-                Expression::BoolExpression(false)
+                Some(Expression::BoolExpression(false))
                 },
             Token::LPAREN =>
                 {
-                    self.parse_group_expression().expect("No ) found to match (")
+                    self.parse_group_expression()
                 }
             Token::INT(content) =>
                 {
-                    self.parse_integer(content).expect("The content of the token couldn't be parse to an integer")
+                    let content = content.clone();
+                    self.parse_integer(&content)
                 },
             Token::BANG =>
                 {
-                    self.parse_prefix_expr()
+                    Some(self.parse_prefix_expr())
                 },
             Token::MINUS =>
                 {
-                    self.parse_prefix_expr()
+                    Some(self.parse_prefix_expr())
             },
             Token::LBRACKET =>
                 {
-                    self.parse_array_literal().expect("Couldn't parse array literal")
+                    self.parse_array_literal()
                 }
             Token::LBRACE =>
                 {
-                    self.parse_hash_expr().expect("Couldn't parse hash literal")
+                    self.parse_hash_expr()
                 }
             _ => {
-                return Expression::None;
+                self.error(format!("expected an expression, found {}", describe(&self.cur_token)), self.cur_span);
+                None
             }
         };
+        let mut expr = match prefix {
+            Some(expr) => expr,
+            None => return Expression::Error(start.to(self.cur_span))
+        };
         while self.peek_token != Token::SEMICOLON && (prec as u32) < (self.peek_precedence() as u32) {
-            expr = match self.peek_token {
-                Token::PLUS => {
-                    self.next_token();
-                    self.parse_infix_expr(expr)
-                },
-                Token::MINUS => {
-                    self.next_token();
-                    self.parse_infix_expr(expr)
-                },
+            let infix = match self.peek_token {
                 Token::LPAREN => {
                     self.next_token();
-                    self.parse_call_expr(expr).expect("Couldn't parse call expression")
+                    self.parse_call_expr(expr)
                 },
                 Token::LBRACKET => {
                     self.next_token();
-                    self.parse_index_expr(expr).expect("Couldn't parse index expression")
+                    self.parse_index_expr(expr)
                 }
-                Token::SLASH => {
-                    self.next_token();
-                    self.parse_infix_expr(expr)
-                },
-                Token::ASTERISK => {
-                    self.next_token();
-                    self.parse_infix_expr(expr)
-                },
-                Token::EQ => {
-                    self.next_token();
-                    self.parse_infix_expr(expr)
-                },
-                Token::NotEq => {
+                Token::PLUS | Token::MINUS | Token::SLASH | Token::ASTERISK
+                | Token::EQ | Token::NotEq | Token::LT | Token::GT => {
                     self.next_token();
-                    self.parse_infix_expr(expr)
+                    Some(self.parse_infix_expr(expr))
                 },
-                Token::LT => {
-                    self.next_token();
-                    self.parse_infix_expr(expr)
-                },
-                Token::GT => {
-                    self.next_token();
-                    self.parse_infix_expr(expr)
-                }
                 _ => {
                     return expr;
                 }
             };
+            expr = match infix {
+                Some(expr) => expr,
+                None => return Expression::Error(start.to(self.cur_span))
+            };
         }
         expr
     }
 
-    fn parse_integer(&self, content: &String) -> Option<Expression> {
+    fn parse_integer(&mut self, content: &String) -> Option<Expression> {
         match content.parse::<i64>() {
             Ok(content) =>
                 {
@@ -512,6 +664,7 @@ impl Parser{
                 },
             _  =>
                 {
+                    self.error(format!("{} doesn't fit in an integer", content), self.cur_span);
                     None
                 }
         }
@@ -519,13 +672,17 @@ impl Parser{
     fn parse_identifier(&mut self) -> Option<Expression> {
 
         if let Token::IDENTIFIER(content) = &self.cur_token {
-            return Some(Expression::IdentifierExpression(Identifier::new(content.clone())))
+            return Some(Expression::IdentifierExpression(Identifier::new_at(content.clone(), self.cur_span)))
         }
         None
     }
     fn parse_statement(&mut self) -> Option<Statement>
     {
         match self.cur_token {
+            Token::SEMICOLON =>
+                {
+                    None
+                },
             Token::LET =>
                 {
                     self.parse_let_statement()
@@ -549,6 +706,7 @@ impl Parser{
             if let Some(content) = stmt {
                 program.statements.push(content);
             }
+            self.synchronize(0);
             self.next_token();
         }
         program
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+use num_traits::FromPrimitive;
+use crate::code::{look_up, Opcode};
+use crate::object::CompiledFunctionStruct;
+
+// How many rows each table of the report shows.
+const REPORT_ROWS: usize = 10;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FunctionProfile {
+    // The let binding name followed by where the function starts, since
+    // anonymous functions all share the same name.
+    pub name: String,
+    pub calls: u64,
+    pub instructions: u64,
+    // Time spent running the function's own instructions, including the
+    // builtins it called.
+    pub self_time: Duration,
+    // Time from entering the function until it returned, counting recursive
+    // calls once.
+    pub total_time: Duration,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct HotInstruction {
+    pub function: String,
+    pub offset: usize,
+    pub instruction: String,
+    pub count: u64,
+}
+
+// A function the VM is running, outermost first.
+#[derive(Debug, Clone)]
+struct Active {
+    function: usize,
+    // The folded-stack key: every function from the outermost in, joined by ';'.
+    stack: String,
+    entered: Instant,
+}
+
+// Counters collected by a Vm running with VmConfig::profile set. Time is
+// measured between instructions, so it includes the overhead of profiling.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    started: Instant,
+    last: Instant,
+    elapsed: Duration,
+    instructions: u64,
+    opcodes: Vec<u64>,
+    functions: Vec<FunctionProfile>,
+    function_index: HashMap<String, usize>,
+    offsets: HashMap<(usize, usize), HotInstruction>,
+    folded: HashMap<String, u128>,
+    active: Vec<Active>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Profile {
+            started: now,
+            last: now,
+            elapsed: Duration::ZERO,
+            instructions: 0,
+            opcodes: vec![0; 256],
+            functions: Vec::new(),
+            function_index: HashMap::new(),
+            offsets: HashMap::new(),
+            folded: HashMap::new(),
+            active: Vec::new(),
+        }
+    }
+
+    // Called by the VM before each instruction with the number of frames and
+    // the function and offset of the instruction about to run. Returns are
+    // seen here as the number of frames going down; calls are reported by
+    // call, since callbacks a builtin runs one after another never leave the
+    // number of frames where it was.
+    pub(crate) fn record(&mut self, depth: usize, function: &CompiledFunctionStruct, offset: usize) {
+        let now = Instant::now();
+        self.charge(now);
+        while self.active.len() > depth {
+            self.leave(now);
+        }
+        // The main frame is the only one the VM starts without a call.
+        if self.active.is_empty() {
+            self.enter(function, now);
+        }
+        let current = self.active[self.active.len() - 1].function;
+
+        self.instructions += 1;
+        self.functions[current].instructions += 1;
+        let content = &function.instructions.content;
+        // A widened instruction counts as the instruction behind the prefix.
+        let opcode = match content.get(offset).copied() {
+            Some(code) if code == Opcode::OpWide as u8 => content.get(offset + 1).copied().unwrap_or(code),
+            code => code.unwrap_or(0)
+        };
+        self.opcodes[opcode as usize] += 1;
+        self.offsets
+            .entry((current, offset))
+            .or_insert_with(|| HotInstruction {
+                function: self.functions[current].name.clone(),
+                offset,
+                instruction: function.instructions.instruction_at(offset).map(|(text, _)| text).unwrap_or_default(),
+                count: 0,
+            })
+            .count += 1;
+    }
+
+    // Called by the VM when it pushes a frame for function on top of depth
+    // others. A frame left behind by a callback that already returned is
+    // closed first.
+    pub(crate) fn call(&mut self, depth: usize, function: &CompiledFunctionStruct) {
+        let now = Instant::now();
+        self.charge(now);
+        while self.active.len() > depth {
+            self.leave(now);
+        }
+        self.enter(function, now);
+    }
+
+    // Called by the VM when a tail call replaces the current frame, which
+    // leaves the number of frames as it was.
+    pub(crate) fn tail_call(&mut self) {
+        let now = Instant::now();
+        self.charge(now);
+        self.leave(now);
+    }
+
+    // Closes the profile once the run is over.
+    pub(crate) fn finish(&mut self) {
+        let now = Instant::now();
+        self.charge(now);
+        while !self.active.is_empty() {
+            self.leave(now);
+        }
+        self.elapsed = now - self.started;
+    }
+
+    // Gives the time since the last instruction to the function that ran it.
+    fn charge(&mut self, now: Instant) {
+        let spent = now - self.last;
+        self.last = now;
+        if let Some(active) = self.active.last() {
+            self.functions[active.function].self_time += spent;
+            match self.folded.get_mut(&active.stack) {
+                Some(total) => *total += spent.as_nanos(),
+                None => {
+                    self.folded.insert(active.stack.clone(), spent.as_nanos());
+                }
+            }
+        }
+    }
+
+    fn enter(&mut self, function: &CompiledFunctionStruct, now: Instant) {
+        let name = function_name(function);
+        let index = match self.function_index.get(&name) {
+            Some(index) => *index,
+            None => {
+                self.functions.push(FunctionProfile {
+                    name: name.clone(),
+                    calls: 0,
+                    instructions: 0,
+                    self_time: Duration::ZERO,
+                    total_time: Duration::ZERO,
+                });
+                self.function_index.insert(name.clone(), self.functions.len() - 1);
+                self.functions.len() - 1
+            }
+        };
+        self.functions[index].calls += 1;
+        let stack = match self.active.last() {
+            Some(caller) => format!("{};{}", caller.stack, name),
+            None => name,
+        };
+        self.active.push(Active { function: index, stack, entered: now });
+    }
+
+    fn leave(&mut self, now: Instant) {
+        let Some(active) = self.active.pop() else { return };
+        if self.active.iter().all(|outer| outer.function != active.function) {
+            self.functions[active.function].total_time += now - active.entered;
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    // Executed instructions per opcode, most frequent first.
+    pub fn opcodes(&self) -> Vec<(Opcode, u64)> {
+        let mut opcodes: Vec<(Opcode, u64)> = self.opcodes
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .filter_map(|(code, count)| Opcode::from_u8(code as u8).map(|opcode| (opcode, *count)))
+            .collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then((a.0.clone() as u8).cmp(&(b.0.clone() as u8))));
+        opcodes
+    }
+
+    // Every function that ran, the one with the most self time first.
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        let mut functions = self.functions.clone();
+        functions.sort_by(|a, b| b.self_time.cmp(&a.self_time).then(a.name.cmp(&b.name)));
+        functions
+    }
+
+    // The count most executed instructions, hottest first.
+    pub fn hot_instructions(&self, count: usize) -> Vec<HotInstruction> {
+        let mut offsets: Vec<HotInstruction> = self.offsets.values().cloned().collect();
+        offsets.sort_by(|a, b| b.count.cmp(&a.count).then(a.function.cmp(&b.function)).then(a.offset.cmp(&b.offset)));
+        offsets.truncate(count);
+        offsets
+    }
+
+    // One line per call stack with the nanoseconds spent at its top, in the
+    // folded format read by flamegraph.pl and inferno.
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self.folded
+            .iter()
+            .map(|(stack, nanos)| format!("{} {}", stack, nanos))
+            .collect();
+        lines.sort();
+        lines.join("\n")
+    }
+}
+
+fn function_name(function: &CompiledFunctionStruct) -> String {
+    let name = function.name.clone().unwrap_or_else(|| "<fn>".to_string());
+    match function.source_map.entries().first() {
+        Some(entry) if name != "<main>" => format!("{} ({})", name, entry.span.start),
+        _ => name,
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.3}ms", duration.as_secs_f64() * 1000.0)
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} instructions in {}", self.instructions, millis(self.elapsed))?;
+
+        writeln!(f, "\nopcodes:")?;
+        for (opcode, count) in self.opcodes().iter().take(REPORT_ROWS) {
+            let name = look_up(opcode).map(|definition| definition.name).unwrap_or_default();
+            let share = *count as f64 * 100.0 / self.instructions.max(1) as f64;
+            writeln!(f, "  {:<16} {:>10} {:>6.1}%", name, count, share)?;
+        }
+
+        writeln!(f, "\nfunctions:")?;
+        writeln!(f, "  {:<24} {:>8} {:>12} {:>12} {:>12}", "name", "calls", "instructions", "self", "total")?;
+        for function in self.functions().iter().take(REPORT_ROWS) {
+            writeln!(
+                f,
+                "  {:<24} {:>8} {:>12} {:>12} {:>12}",
+                function.name,
+                function.calls,
+                function.instructions,
+                millis(function.self_time),
+                millis(function.total_time)
+            )?;
+        }
+
+        writeln!(f, "\nhot instructions:")?;
+        for hot in self.hot_instructions(REPORT_ROWS) {
+            writeln!(f, "  {:<24} {:0>4} {:<20} {:>10}", hot.function, hot.offset, hot.instruction, hot.count)?;
+        }
+        Ok(())
+    }
+}
@@ -1,80 +1,350 @@
-use std::io::Write;
-use crate::ast::Node;
-use crate::builtins::BUILT_INS;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use crate::ast::{Node, Program};
+use crate::builtins::Builtins;
 use crate::compiler::Compiler;
+use crate::diagnostic::{colour_enabled, render_all, Diagnostic, RUNTIME_ERROR};
 use crate::environment::Environment;
-use crate::evaluator::eval;
+use crate::evaluator::{error_span, eval};
 use crate::lexer::Lexer;
 use crate::object::Object;
 use crate::parser::Parser;
-use crate::symbol_table::SymbolTable;
-use crate::vm::Vm;
+use crate::symbol_table::{SymbolScope, SymbolTable};
+use crate::vm::{Vm, VmConfig};
 
 const PROMPT: &str = ">>";
+const CONTINUATION_PROMPT: &str = "..";
+const HISTORY_FILE: &str = ".monkey_history";
+// The file name diagnostics give for code typed at the prompt.
+const SOURCE_NAME: &str = "<repl>";
+const HELP: &str = ":help          show this message
+:quit          leave the REPL
+:reset         forget every binding made in this session
+:globals       list the global bindings and their values
+:type <expr>   evaluate <expr> without keeping any state and show its type
+:disasm <expr> show the bytecode compiled for <expr>
+:ast <expr>    show the parsed tree of <expr>
+:load <file>   run a source file in the current session
+:mode vm|eval  switch between the bytecode VM and the tree-walking evaluator";
 
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum ReplMode
 {
     InterpreterMode,
     CompilerMode
 }
 
-pub fn start(mode: ReplMode) {
-    let mut line = String::new();
-    let mut env = Environment::new();
-    let mut constants = Vec::new();
-    let mut globals = Vec::new();
-    let mut symbol_table = SymbolTable::new();
-    for (i, o) in BUILT_INS.into_iter().zip(0..BUILT_INS.len())
-    {
-        symbol_table.define_builtin(o, i.to_string());
-    }
-    loop{
-        line.clear();
-        print!("{}", PROMPT);
-        std::io::stdout().flush().expect("Error while printing");
-        let read_len = std::io::stdin().read_line(& mut line);
-        if let Err(content) = read_len
-        {
-            println!("Error while reading, details: {}", content);
-            return;
+// True while the input still has an open string or more opening than closing
+// braces, brackets or parentheses, so the REPL should read another line.
+pub fn is_incomplete(input: &str) -> bool {
+    let mut depth: i64 = 0;
+    let mut in_string = false;
+    for c in input.chars() {
+        match c {
+            '"' => in_string = !in_string,
+            '(' | '[' | '{' if !in_string => depth += 1,
+            ')' | ']' | '}' if !in_string => depth -= 1,
+            _ => {}
         }
-        let lexer = Lexer::new(line.clone());
-        let mut parser = Parser::new(lexer);
-        let program = parser.parse_program();
-        if parser.errors.len() > 0 {
-            for error in parser.errors
-            {
-                println!("{}", error);
+    }
+    in_string || depth > 0
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+// Reads lines until they form a complete entry. Returns None once the user
+// closes the input; Ctrl-C drops the entry being typed and starts over.
+fn read_entry(editor: &mut DefaultEditor) -> Option<String> {
+    let mut entry = String::new();
+    loop {
+        let prompt = if entry.is_empty() { PROMPT } else { CONTINUATION_PROMPT };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                entry.push_str(&line);
+                entry.push('\n');
+                if !is_incomplete(&entry) {
+                    return Some(entry);
+                }
             }
-            continue;
+            Err(ReadlineError::Interrupted) => entry.clear(),
+            Err(ReadlineError::Eof) => return None,
+            Err(content) => {
+                println!("Error while reading, details: {}", content);
+                return None;
+            }
+        }
+    }
+}
+
+pub enum Reply
+{
+    Output(String),
+    Quit
+}
+
+// Everything a REPL keeps between entries. Each engine has its own bindings,
+// so switching modes leaves the other engine's state untouched.
+pub struct Session
+{
+    mode: ReplMode,
+    env: Environment,
+    constants: Vec<Object>,
+    globals: Vec<Option<Object>>,
+    builtins: Builtins,
+    symbol_table: SymbolTable,
+    // Colour the diagnostics shown for failed entries.
+    pub colour: bool
+}
+
+impl Session {
+    pub fn new(mode: ReplMode) -> Self {
+        let builtins = Builtins::new();
+        let mut symbol_table = SymbolTable::new();
+        builtins.define_in(&mut symbol_table);
+        Session {
+            mode,
+            env: Environment::new(),
+            constants: Vec::new(),
+            globals: Vec::new(),
+            builtins,
+            symbol_table,
+            colour: false
+        }
+    }
+
+    // Runs one entry, either source code or a meta-command starting with ':'.
+    pub fn handle(&mut self, entry: &str) -> Reply {
+        let entry = entry.trim();
+        match entry.strip_prefix(':') {
+            Some(command) => self.command(command),
+            None => Reply::Output(self.run(SOURCE_NAME, entry))
         }
+    }
 
-        match mode
+    fn command(&mut self, command: &str) -> Reply {
+        let (name, argument) = match command.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (command, "")
+        };
+        let output = match name {
+            "help" => HELP.to_string(),
+            "quit" | "q" => return Reply::Quit,
+            "reset" => {
+                let colour = self.colour;
+                *self = Session::new(self.mode);
+                self.colour = colour;
+                "session reset".to_string()
+            }
+            "globals" => self.globals(),
+            "type" => match self.evaluate(argument) {
+                Ok(value) => value.get_type().to_string(),
+                Err(error) => error
+            },
+            "disasm" => self.disassemble(argument),
+            "ast" => match parse(argument) {
+                Ok(program) => format!("{:#?}", program.statements),
+                Err(errors) => self.report(&errors, argument)
+            },
+            "load" => match std::fs::read_to_string(argument) {
+                Ok(source) => self.run(argument, &source),
+                Err(error) => format!("ERROR: could not read {}: {}", argument, error)
+            },
+            "mode" => match argument {
+                "vm" => {
+                    self.mode = ReplMode::CompilerMode;
+                    "using the bytecode VM".to_string()
+                }
+                "eval" => {
+                    self.mode = ReplMode::InterpreterMode;
+                    "using the evaluator".to_string()
+                }
+                _ => "usage: :mode vm|eval".to_string()
+            },
+            _ => format!("unknown command :{}, try :help", name)
+        };
+        Reply::Output(output)
+    }
+
+    // Runs source, reporting errors in it under name.
+    fn run(&mut self, name: &str, source: &str) -> String {
+        let program = match parse(source) {
+            Ok(program) => program,
+            Err(errors) => return self.report_in(name, &errors, source)
+        };
+
+        match self.mode
         {
             ReplMode::InterpreterMode => {
-                let evaluated = eval(Node::Program(program),& mut env);
+                let evaluated = eval(Node::Program(program),& mut self.env);
                 if let Object::Null = &evaluated
                 {
-                    continue;
+                    return String::new();
+                }
+                if let Object::Error(message) = &evaluated
+                {
+                    return self.report_in(name, &[evaluation_error(message)], source);
                 }
-                println!("{}", evaluated.inspect());
+                evaluated.inspect()
             },
             ReplMode::CompilerMode => {
-                let mut compiler = Compiler::new_with_state(constants.clone(), symbol_table.clone());
-                compiler.compile(Node::Program(program));
-                symbol_table = compiler.symbol_table.clone();
-                constants = compiler.constants.clone();
-                let mut vm = Vm::new_with_state(compiler.get_bytecode(), globals.clone());
-                vm.run();
+                // The compiler and the VM work on copies of the session state,
+                // which only replace it once the whole entry has run, so a failing
+                // entry can't leave a symbol behind without a value.
+                let returns_value = program.returns_value();
+                let mut compiler = Compiler::new_with_state(self.constants.clone(), self.symbol_table.clone(), self.builtins.clone());
+                if let Err(error) = compiler.compile(Node::Program(program))
+                {
+                    return self.report_in(name, &[Diagnostic::from(&error)], source);
+                }
+                let mut vm = Vm::new_with_state(compiler.get_bytecode(), self.globals.clone(), VmConfig::default());
+                if let Err(error) = vm.run()
+                {
+                    return self.report_in(name, &[Diagnostic::from(&error)], source);
+                }
+                self.symbol_table = compiler.symbol_table;
+                self.constants = compiler.constants;
+                self.globals = vm.globals.clone();
+                if !returns_value
+                {
+                    return String::new();
+                }
                 let value = vm.last_popped_stack_element();
                 if let Object::Null = &value
                 {
-                    continue;
+                    return String::new();
                 }
-                println!("{}", value.inspect());
+                value.inspect()
+            }
+        }
+    }
+
+    // Evaluates source against a copy of the session, so nothing it defines is kept.
+    fn evaluate(&self, source: &str) -> Result<Object, String> {
+        let program = parse(source).map_err(|errors| self.report(&errors, source))?;
+        match self.mode {
+            ReplMode::InterpreterMode => {
+                let mut env = self.env.clone();
+                match eval(Node::Program(program), & mut env) {
+                    Object::Error(error) => Err(self.report(&[evaluation_error(&error)], source)),
+                    value => Ok(value)
+                }
+            },
+            ReplMode::CompilerMode => {
+                let returns_value = program.returns_value();
+                let mut compiler = Compiler::new_with_state(self.constants.clone(), self.symbol_table.clone(), self.builtins.clone());
+                compiler.compile(Node::Program(program)).map_err(|error| self.report(&[Diagnostic::from(&error)], source))?;
+                let mut vm = Vm::new_with_state(compiler.get_bytecode(), self.globals.clone(), VmConfig::default());
+                vm.run().map_err(|error| self.report(&[Diagnostic::from(&error)], source))?;
+                Ok(if returns_value { vm.last_popped_stack_element() } else { Object::Null })
+            }
+        }
+    }
+
+    fn report(&self, diagnostics: &[Diagnostic], source: &str) -> String {
+        self.report_in(SOURCE_NAME, diagnostics, source)
+    }
+
+    fn report_in(&self, name: &str, diagnostics: &[Diagnostic], source: &str) -> String {
+        render_all(diagnostics, name, source, self.colour)
+    }
+
+    // Shows the main instructions followed by every function compiled for the source.
+    fn disassemble(&self, source: &str) -> String {
+        let program = match parse(source) {
+            Ok(program) => program,
+            Err(errors) => return self.report(&errors, source)
+        };
+        let mut compiler = Compiler::new_with_state(self.constants.clone(), self.symbol_table.clone(), self.builtins.clone());
+        if let Err(error) = compiler.compile(Node::Program(program)) {
+            return self.report(&[Diagnostic::from(&error)], source);
+        }
+        let bytecode = compiler.get_bytecode();
+        let mut output = bytecode.instructions.to_string();
+        for (index, constant) in bytecode.constants.iter().enumerate().skip(self.constants.len()) {
+            if let Object::CompiledFunction(function) = constant {
+                output += format!("\nconstant {}:\n{}", index, function.instructions).as_str();
+            }
+        }
+        output.trim_end().to_string()
+    }
+
+    fn globals(&self) -> String {
+        let bindings: Vec<String> = match self.mode {
+            ReplMode::InterpreterMode => self.env.bindings()
+                .into_iter()
+                .map(|(name, value)| format!("{} = {}", name, value.inspect()))
+                .collect(),
+            ReplMode::CompilerMode => self.symbol_table.symbols()
+                .into_iter()
+                .filter(|symbol| symbol.scope == SymbolScope::Global)
+                .map(|symbol| match self.globals.get(symbol.index) {
+                    Some(Some(value)) => format!("{} = {}", symbol.name, value.inspect()),
+                    _ => format!("{} = <unset>", symbol.name)
+                })
+                .collect()
+        };
+        if bindings.is_empty() {
+            "no globals defined".to_string()
+        }
+        else {
+            bindings.join("\n")
+        }
+    }
+}
+
+fn parse(source: &str) -> Result<Program, Vec<Diagnostic>> {
+    let lexer = Lexer::new(source.to_string());
+    let mut parser = Parser::new(lexer);
+    let program = parser.parse_program();
+    if !parser.errors.is_empty() {
+        return Err(parser.errors.iter().map(Diagnostic::from).collect());
+    }
+    Ok(program)
+}
+
+// An error value the evaluator ended a program with.
+fn evaluation_error(message: &str) -> Diagnostic {
+    Diagnostic::error(RUNTIME_ERROR, message).with_span(error_span())
+}
 
-                globals = vm.globals;
+pub fn start(mode: ReplMode) {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(content) => {
+            println!("Error while starting the editor, details: {}", content);
+            return;
+        }
+    };
+    let history = history_path();
+    if let Some(path) = &history {
+        // A missing history file just means this is the first session.
+        let _ = editor.load_history(path);
+    }
+    let mut session = Session::new(mode);
+    session.colour = colour_enabled(std::io::stdout().is_terminal());
+    while let Some(line) = read_entry(&mut editor) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.trim_end());
+        if let Some(path) = &history {
+            if let Err(content) = editor.save_history(path) {
+                println!("Error while saving history, details: {}", content);
             }
         }
+        match session.handle(&line) {
+            Reply::Output(output) => {
+                if !output.is_empty() {
+                    println!("{}", output);
+                }
+            }
+            Reply::Quit => return
+        }
     }
-}
\ No newline at end of file
+}
@@ -65,6 +65,25 @@ impl SymbolTable {
         symbol
 
     }
+    // The symbols defined directly in this table, ordered by scope and index.
+    pub fn symbols(&self) -> Vec<Symbol> {
+        let mut symbols: Vec<Symbol> = self.store.values().cloned().collect();
+        symbols.sort_by_key(|symbol| (symbol.scope != SymbolScope::BuiltIn, symbol.index));
+        symbols
+    }
+
+    // Like resolve, also counting how many tables out the symbol was found.
+    pub fn resolve_with_depth(&self, name: &str) -> Option<(Symbol, usize)>
+    {
+        match self.store.get(name) {
+            Some(symbol) => Some((symbol.clone(), 0)),
+            None => {
+                let (symbol, depth) = self.outer.as_ref()?.resolve_with_depth(name)?;
+                Some((symbol, depth + 1))
+            }
+        }
+    }
+
     pub fn resolve(&self, name: String) -> Option<Symbol>
     {
         match self.store.get(name.as_str())
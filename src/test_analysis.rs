@@ -0,0 +1,66 @@
+use crate::analysis::{Analysis, DefinitionKind};
+use crate::token::Position;
+
+fn at(line: usize, column: usize) -> Position {
+    Position { line, column }
+}
+
+const SOURCE: &str = "let total = 10;
+let add = fn(a, b) {
+  let sum = a + b;
+  sum + total
+};
+let total = add(total, 1);
+try { len(total) } catch (err) { err }";
+
+#[test]
+fn test_resolves_through_scopes() {
+    let analysis = Analysis::new(SOURCE);
+    assert!(analysis.errors.is_empty());
+
+    let name = |position| analysis.definition_at(position).map(|index| &analysis.definitions[index]);
+    let sum = name(at(4, 3)).unwrap();
+    assert_eq!((sum.name.as_str(), sum.kind, sum.span.unwrap().start), ("sum", DefinitionKind::Let, at(3, 7)));
+    let param = name(at(3, 13)).unwrap();
+    assert_eq!((param.kind, param.span.unwrap().start), (DefinitionKind::Parameter, at(2, 14)));
+    // Inside add, total is the first one; a let only takes effect after its
+    // own value is computed.
+    assert_eq!(name(at(4, 9)).unwrap().span.unwrap().start, at(1, 5));
+    assert_eq!(name(at(6, 17)).unwrap().span.unwrap().start, at(1, 5));
+    assert_eq!(name(at(7, 11)).unwrap().span.unwrap().start, at(6, 5));
+    assert_eq!(name(at(7, 7)).unwrap().kind, DefinitionKind::Builtin);
+    assert_eq!(name(at(7, 34)).unwrap().kind, DefinitionKind::CatchBinding);
+
+    let first = analysis.definition_at(at(1, 6)).unwrap();
+    let uses: Vec<Position> = analysis.references_to(first).iter().map(|span| span.start).collect();
+    assert_eq!(uses, vec![at(4, 9), at(6, 17)]);
+}
+
+#[test]
+fn test_visible_at() {
+    let analysis = Analysis::new(SOURCE);
+    let names = |position| -> Vec<String> {
+        analysis.visible_at(position)
+            .into_iter()
+            .map(|index| &analysis.definitions[index])
+            .filter(|definition| definition.kind != DefinitionKind::Builtin)
+            .map(|definition| definition.name.clone())
+            .collect()
+    };
+    assert_eq!(names(at(4, 3)), vec!["sum", "b", "a", "total"]);
+    assert_eq!(names(at(3, 3)), vec!["b", "a", "total"]);
+    assert_eq!(names(at(7, 1)), vec!["total", "add"]);
+    assert!(analysis.visible_at(at(1, 1)).iter().any(|index| analysis.definitions[*index].name == "len"));
+}
+
+#[test]
+fn test_undefined_and_diagnostics() {
+    let analysis = Analysis::new("let a = 1;\nmissing + a");
+    assert_eq!(analysis.references[0].definition, None);
+    let diagnostics = analysis.diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "undefined variable missing");
+
+    let analysis = Analysis::new("let = 1;\nlet b = (2;");
+    assert_eq!(analysis.diagnostics().len(), 2);
+}
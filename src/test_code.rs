@@ -1,5 +1,6 @@
 use crate::code::Opcode::{OpAdd, OpConstant};
-use crate::code::{make, Opcode};
+use crate::code::{make, Opcode, SourceMap};
+use crate::token::{Position, Span};
 use crate::test_compiler::concat_instructions;
 
 struct MakeTest {
@@ -42,5 +43,59 @@ fn test_print_code() {
     assert_eq!(input.to_string(), expected);
 }
 
+#[test]
+fn test_instruction_at() {
+    let input = concat_instructions(vec![
+        make(OpConstant, vec![87]).unwrap(),
+        make(Opcode::OpClosure, vec![3, 1]).unwrap(),
+        make(OpAdd, vec![]).unwrap(),
+    ]);
+    assert_eq!(input.instruction_at(0), Some(("OpConstant 87".to_string(), 3)));
+    assert_eq!(input.instruction_at(3), Some(("OpClosure 3 1".to_string(), 4)));
+    assert_eq!(input.instruction_at(7), Some(("OpAdd".to_string(), 1)));
+    assert_eq!(input.instruction_at(8), None);
+}
+
+#[test]
+fn test_make_wide() {
+    let constant = make(OpConstant, vec![70000]).unwrap();
+    assert_eq!(constant.content, vec![Opcode::OpWide as u8, OpConstant as u8, 0, 1, 17, 112]);
+    let closure = make(Opcode::OpClosure, vec![3, 256]).unwrap();
+    assert_eq!(closure.content, vec![Opcode::OpWide as u8, Opcode::OpClosure as u8, 0, 0, 0, 3, 0, 0, 1, 0]);
+    assert_eq!(make(OpConstant, vec![u32::MAX as usize + 1]), None);
+
+    let input = concat_instructions(vec![constant, closure, make(OpAdd, vec![]).unwrap()]);
+    assert_eq!(input.decode(0), Some((OpConstant, vec![70000], 6)));
+    assert_eq!(input.decode(6), Some((Opcode::OpClosure, vec![3, 256], 10)));
+    assert_eq!(input.to_string(), "0000 OpWide OpConstant 70000\n0006 OpWide OpClosure 3 256\n0016 OpAdd\n");
+}
+
+#[test]
+fn test_source_map() {
+    let line = |line| Span::new(Position { line, column: 1 }, Position { line, column: 10 });
+    let mut map = SourceMap::new();
+    map.add(0, line(1));
+    map.add(4, line(2));
+    map.add(4, line(3));
+    map.add(9, line(4));
+
+    assert_eq!(map.entries().len(), 3);
+    assert_eq!(map.lookup(0), Some(line(1)));
+    assert_eq!(map.lookup(3), Some(line(1)));
+    assert_eq!(map.lookup(4), Some(line(3)));
+    assert_eq!(map.lookup(100), Some(line(4)));
+    assert_eq!(map.starting_at(9), Some(line(4)));
+    assert_eq!(map.starting_at(5), None);
+    assert_eq!(SourceMap::new().lookup(0), None);
+
+    map.resume(12, line(1));
+    map.resume(12, line(1));
+    assert_eq!(map.entries().len(), 4);
+    assert_eq!(map.lookup(12), Some(line(1)));
+    assert_eq!(map.starting_at(12), None);
+    map.truncate(12);
+    assert_eq!(map.lookup(12), Some(line(4)));
+}
+
 #[test]
 fn test_op_add() {}
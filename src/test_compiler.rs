@@ -3,7 +3,8 @@ use std::vec;
 use crate::ast::{Node, Program};
 use crate::code::Opcode::{
     OpAdd, OpArray, OpBang, OpCall, OpConstant, OpDiv, OpEq, OpGreaterThan, OpHash, OpIndex,
-    OpJump, OpJumpNotTrue, OpMinus, OpMul, OpNotEq, OpNull, OpPop, OpSetGlobal, OpSub, OpTrue,
+    OpFalse, OpJump, OpJumpNotTrue, OpMinus, OpMul, OpNotEq, OpNull, OpPop, OpSetGlobal, OpSub,
+    OpTrue,
 };
 use crate::code::{self, join_instructions, make, Instructions};
 use crate::compiler::Compiler;
@@ -28,13 +29,27 @@ fn run_compiler_tests(tests: Vec<CompilerTestCase>) {
     for test in tests {
         let program = parse(test.input.clone());
         let mut compiler = Compiler::new();
-        compiler.compile(Node::Program(program));
+        compiler.optimize = false;
+        compiler.compile(Node::Program(program)).expect("Couldn't compile program");
         let bytecode = compiler.get_bytecode();
         test_instructions(bytecode.instructions, test.expected_instructions);
         test_constants(bytecode.constants, test.expected_constants);
     }
 }
 
+// Like run_compiler_tests with the optimizer on, as it is by default, and the
+// constant pool has to match exactly.
+fn run_optimized_compiler_tests(tests: Vec<CompilerTestCase>) {
+    for test in tests {
+        let program = parse(test.input.clone());
+        let mut compiler = Compiler::new();
+        compiler.compile(Node::Program(program)).expect("Couldn't compile program");
+        let bytecode = compiler.get_bytecode();
+        test_instructions(bytecode.instructions, test.expected_instructions);
+        assert_eq!(bytecode.constants, test.expected_constants, "{}", test.input);
+    }
+}
+
 pub fn concat_instructions(instructions_vec: Vec<Instructions>) -> Instructions {
     let mut output = Vec::new();
     for mut instruction in instructions_vec {
@@ -470,6 +485,7 @@ fn test_functions() {
                     ]),
                     num_vars: 0,
                     num_args: 0,
+                    ..Default::default()
                 }),
             ],
             expected_instructions: vec![
@@ -491,6 +507,7 @@ fn test_functions() {
                     ]),
                     num_vars: 0,
                     num_args: 0,
+                    ..Default::default()
                 }),
             ],
             expected_instructions: vec![
@@ -512,6 +529,7 @@ fn test_functions() {
                     ]),
                     num_vars: 0,
                     num_args: 0,
+                    ..Default::default()
                 }),
             ],
             expected_instructions: vec![
@@ -528,6 +546,7 @@ fn test_functions() {
                     ]),
                     num_vars: 0,
                     num_args: 0,
+                    ..Default::default()
                 })]
             },
             expected_instructions: {
@@ -556,6 +575,7 @@ fn test_function_calls() {
                     ]),
                     num_vars: 0,
                     num_args: 0,
+                    ..Default::default()
                 }),
             ],
             expected_instructions: vec![
@@ -573,6 +593,7 @@ fn test_function_calls() {
                 ]),
                 num_vars: 1,
                 num_args: 1,
+                ..Default::default()
             })],
             expected_instructions: vec![
                 make(code::Opcode::OpClosure, vec![0, 0]).unwrap(),
@@ -604,6 +625,7 @@ fn test_let_statement_scopes() {
                     ]),
                     num_vars: 0,
                     num_args: 0,
+                    ..Default::default()
                 }),
             ],
             expected_instructions: vec![
@@ -630,6 +652,7 @@ fn test_let_statement_scopes() {
                     ]),
                     num_vars: 1,
                     num_args: 0,
+                    ..Default::default()
                 }),
             ],
             expected_instructions: vec![
@@ -668,11 +691,12 @@ fn test_builtins() {
                 instructions: join_instructions(vec![
                     make(code::Opcode::OpGetBuiltin, vec![0]).unwrap(),
                     make(OpArray, vec![0]).unwrap(),
-                    make(OpCall, vec![1]).unwrap(),
+                    make(code::Opcode::OpTailCall, vec![1]).unwrap(),
                     make(code::Opcode::OpReturnValue, vec![]).unwrap(),
                 ]),
                 num_vars: 0,
                 num_args: 0,
+                ..Default::default()
             })],
             expected_instructions: vec![
                 make(code::Opcode::OpClosure, vec![0, 0]).unwrap(),
@@ -684,6 +708,67 @@ fn test_builtins() {
     run_compiler_tests(tests);
 }
 
+#[test]
+fn test_tail_calls() {
+    let tests = vec![
+        CompilerTestCase {
+            input: "fn(f) { if (f) { f(1) } else { return f(2); } };".to_string(),
+            expected_constants: vec![
+                IntegerObject(1),
+                IntegerObject(2),
+                Object::CompiledFunction(CompiledFunctionStruct {
+                    instructions: join_instructions(vec![
+                        make(code::Opcode::OpGetLocal, vec![0]).unwrap(),
+                        make(OpJumpNotTrue, vec![15]).unwrap(),
+                        make(code::Opcode::OpGetLocal, vec![0]).unwrap(),
+                        make(OpConstant, vec![0]).unwrap(),
+                        make(code::Opcode::OpTailCall, vec![1]).unwrap(),
+                        make(OpJump, vec![23]).unwrap(),
+                        make(code::Opcode::OpGetLocal, vec![0]).unwrap(),
+                        make(OpConstant, vec![1]).unwrap(),
+                        make(code::Opcode::OpTailCall, vec![1]).unwrap(),
+                        make(code::Opcode::OpReturnValue, vec![]).unwrap(),
+                        make(code::Opcode::OpReturnValue, vec![]).unwrap(),
+                    ]),
+                    num_vars: 1,
+                    num_args: 1,
+                    ..Default::default()
+                }),
+            ],
+            expected_instructions: vec![
+                make(code::Opcode::OpClosure, vec![2, 0]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+            ],
+        },
+        CompilerTestCase {
+            input: "fn(f) { f(1) + 1 };".to_string(),
+            expected_constants: vec![
+                IntegerObject(1),
+                IntegerObject(1),
+                Object::CompiledFunction(CompiledFunctionStruct {
+                    instructions: join_instructions(vec![
+                        make(code::Opcode::OpGetLocal, vec![0]).unwrap(),
+                        make(OpConstant, vec![0]).unwrap(),
+                        make(OpCall, vec![1]).unwrap(),
+                        make(OpConstant, vec![1]).unwrap(),
+                        make(OpAdd, vec![]).unwrap(),
+                        make(code::Opcode::OpReturnValue, vec![]).unwrap(),
+                    ]),
+                    num_vars: 1,
+                    num_args: 1,
+                    ..Default::default()
+                }),
+            ],
+            expected_instructions: vec![
+                make(code::Opcode::OpClosure, vec![2, 0]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+            ],
+        },
+    ];
+
+    run_compiler_tests(tests);
+}
+
 #[test]
 fn test_closures() {
     let tests = vec![
@@ -705,7 +790,8 @@ fn test_closures() {
                             ]
                         ),
                         num_vars:1,
-                        num_args:1
+                        num_args:1,
+                        ..Default::default()
                     }
                 ),
                 Object::CompiledFunction(
@@ -718,7 +804,8 @@ fn test_closures() {
                             ]
                         ),
                         num_vars:1,
-                        num_args:1
+                        num_args:1,
+                        ..Default::default()
                     }
                 )
             ],
@@ -749,7 +836,8 @@ fn test_closures() {
                             ]
                         ),
                         num_args:1,
-                        num_vars:2
+                        num_vars:2,
+                        ..Default::default()
                     }
                 ),
                 Object::CompiledFunction(
@@ -761,7 +849,8 @@ fn test_closures() {
                             make(code::Opcode::OpReturnValue, vec![]).unwrap()  
                         ]),
                         num_args: 1,
-                        num_vars: 2
+                        num_vars: 2,
+                        ..Default::default()
                     }
                 ),
                 Object::CompiledFunction(
@@ -772,7 +861,8 @@ fn test_closures() {
                             make(code::Opcode::OpReturnValue, vec![]).unwrap()
                         ]),
                         num_args:1,
-                        num_vars:2
+                        num_vars:2,
+                        ..Default::default()
                     }
                 )
             ],
@@ -784,4 +874,182 @@ fn test_closures() {
     ];
 
     run_compiler_tests(tests);
-}
\ No newline at end of file
+}
+#[test]
+fn test_try_catch() {
+    let tests = vec![CompilerTestCase {
+        input: "try { 1 } catch (e) { 2 }".to_string(),
+        expected_constants: vec![IntegerObject(1), IntegerObject(2)],
+        expected_instructions: vec![
+            make(code::Opcode::OpTry, vec![10]).unwrap(),
+            make(OpConstant, vec![0]).unwrap(),
+            make(code::Opcode::OpEndTry, vec![]).unwrap(),
+            make(OpJump, vec![16]).unwrap(),
+            make(OpSetGlobal, vec![0]).unwrap(),
+            make(OpConstant, vec![1]).unwrap(),
+            make(OpPop, vec![]).unwrap(),
+        ],
+    }];
+
+    run_compiler_tests(tests);
+}
+
+#[test]
+fn test_optimizations() {
+    let tests = vec![
+        CompilerTestCase {
+            input: "1 + 2 * 3".to_string(),
+            expected_constants: vec![IntegerObject(7)],
+            expected_instructions: vec![
+                make(OpConstant, vec![0]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+            ],
+        },
+        CompilerTestCase {
+            input: "(1 + 2) * 3 > 8 == !false; -(4 - 6)".to_string(),
+            expected_constants: vec![IntegerObject(2)],
+            expected_instructions: vec![
+                make(OpTrue, vec![]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+                make(OpConstant, vec![0]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+            ],
+        },
+        CompilerTestCase {
+            input: "!(1 == 1) != true".to_string(),
+            expected_constants: vec![],
+            expected_instructions: vec![
+                make(OpTrue, vec![]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+            ],
+        },
+        CompilerTestCase {
+            input: "!(1 == 1)".to_string(),
+            expected_constants: vec![],
+            expected_instructions: vec![
+                make(OpFalse, vec![]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+            ],
+        },
+        CompilerTestCase {
+            // Errors are left for the VM to raise.
+            input: "1 / 0".to_string(),
+            expected_constants: vec![IntegerObject(1), IntegerObject(0)],
+            expected_instructions: vec![
+                make(OpConstant, vec![0]).unwrap(),
+                make(OpConstant, vec![1]).unwrap(),
+                make(OpDiv, vec![]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+            ],
+        },
+        CompilerTestCase {
+            input: "\"a\"; 1; \"a\"; 1; 2 - 1".to_string(),
+            expected_constants: vec![Object::StringObject("a".to_string()), IntegerObject(1)],
+            expected_instructions: vec![
+                make(OpConstant, vec![0]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+                make(OpConstant, vec![1]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+                make(OpConstant, vec![0]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+                make(OpConstant, vec![1]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+                make(OpConstant, vec![1]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+            ],
+        },
+        CompilerTestCase {
+            input: "if (1 < 2) { 10 } else { 20 }; 3333;".to_string(),
+            expected_constants: vec![IntegerObject(10), IntegerObject(20), IntegerObject(3333)],
+            expected_instructions: vec![
+                make(OpConstant, vec![0]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+                make(OpConstant, vec![2]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+            ],
+        },
+        CompilerTestCase {
+            input: "if (false) { 10 }; 3333;".to_string(),
+            expected_constants: vec![IntegerObject(10), IntegerObject(3333)],
+            expected_instructions: vec![
+                make(OpNull, vec![]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+                make(OpConstant, vec![1]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+            ],
+        },
+        CompilerTestCase {
+            // The jump over the else branch is dead once the consequence returns.
+            input: "fn(x) { if (x) { return 1; } else { 2 } }".to_string(),
+            expected_constants: vec![
+                IntegerObject(1),
+                IntegerObject(2),
+                Object::CompiledFunction(CompiledFunctionStruct {
+                    instructions: join_instructions(vec![
+                        make(code::Opcode::OpGetLocal, vec![0]).unwrap(),
+                        make(OpJumpNotTrue, vec![9]).unwrap(),
+                        make(OpConstant, vec![0]).unwrap(),
+                        make(code::Opcode::OpReturnValue, vec![]).unwrap(),
+                        make(OpConstant, vec![1]).unwrap(),
+                        make(code::Opcode::OpReturnValue, vec![]).unwrap(),
+                    ]),
+                    num_vars: 1,
+                    num_args: 1,
+                    ..Default::default()
+                }),
+            ],
+            expected_instructions: vec![
+                make(code::Opcode::OpClosure, vec![2, 0]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+            ],
+        },
+        CompilerTestCase {
+            input: "fn() { return 1; 2 }".to_string(),
+            expected_constants: vec![
+                IntegerObject(1),
+                IntegerObject(2),
+                Object::CompiledFunction(CompiledFunctionStruct {
+                    instructions: join_instructions(vec![
+                        make(OpConstant, vec![0]).unwrap(),
+                        make(code::Opcode::OpReturnValue, vec![]).unwrap(),
+                    ]),
+                    num_vars: 0,
+                    num_args: 0,
+                    ..Default::default()
+                }),
+            ],
+            expected_instructions: vec![
+                make(code::Opcode::OpClosure, vec![2, 0]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+            ],
+        },
+        CompilerTestCase {
+            input: "fn(n) { if (n > 0) { n - 1 } else { n + 2 } }".to_string(),
+            expected_constants: vec![
+                IntegerObject(0),
+                IntegerObject(1),
+                IntegerObject(2),
+                Object::CompiledFunction(CompiledFunctionStruct {
+                    instructions: join_instructions(vec![
+                        make(code::Opcode::OpGetLocal, vec![0]).unwrap(),
+                        make(OpConstant, vec![0]).unwrap(),
+                        make(code::Opcode::OpJumpNotGreater, vec![15]).unwrap(),
+                        make(code::Opcode::OpSubLocalConstant, vec![0, 1]).unwrap(),
+                        make(OpJump, vec![19]).unwrap(),
+                        make(code::Opcode::OpAddLocalConstant, vec![0, 2]).unwrap(),
+                        make(code::Opcode::OpReturnValue, vec![]).unwrap(),
+                    ]),
+                    num_vars: 1,
+                    num_args: 1,
+                    ..Default::default()
+                }),
+            ],
+            expected_instructions: vec![
+                make(code::Opcode::OpClosure, vec![3, 0]).unwrap(),
+                make(OpPop, vec![]).unwrap(),
+            ],
+        },
+    ];
+
+    run_optimized_compiler_tests(tests);
+}
@@ -0,0 +1,129 @@
+use std::cell::RefCell;
+use std::io::{Cursor, Write};
+use std::rc::Rc;
+use crate::debugger::debug;
+use crate::diagnostic::render_all;
+use crate::object::Object;
+
+// Collects what the debugger prints, shared with the test after the hook is gone.
+#[derive(Clone, Default)]
+struct Transcript(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Transcript {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Errors are reduced to their messages.
+fn run_debugger(source: &str, commands: &str) -> (Result<Object, String>, String) {
+    let transcript = Transcript::default();
+    let input = Box::new(Cursor::new(commands.to_string()));
+    let result = debug(source, input, Box::new(transcript.clone()))
+        .map_err(|diagnostics| diagnostics.iter().map(|diagnostic| diagnostic.message.clone()).collect::<Vec<_>>().join("\n"));
+    let output = String::from_utf8(transcript.0.borrow().clone()).unwrap();
+    (result, output)
+}
+
+const PROGRAM: &str = "let add = fn(a, b) {
+    let sum = a + b;
+    sum
+};
+let x = 1;
+add(x, 2);";
+
+#[test]
+fn test_step_instructions() {
+    let (result, output) = run_debugger(PROGRAM, "si\nstack\nsi\nsi\nstack\nq\n");
+    assert_eq!(result, Err("interrupted".to_string()));
+    assert_eq!(output, "<main> line 1:1 0000 OpClosure 0 0
+(debug) <main> line 1:1 0004 OpSetGlobal 0
+(debug)    0 Closure[CompiledFunction[0000 OpGetLocal 0
+0002 OpGetLocal 1
+0004 OpAdd
+0005 OpSetLocal 2
+0007 OpGetLocal 2
+0009 OpReturnValue
+]]
+(debug) <main> line 5:1 0007 OpConstant 1
+(debug) <main> line 5:1 0010 OpSetGlobal 1
+(debug)    0 1
+(debug) ");
+}
+
+#[test]
+fn test_step_lines() {
+    let (result, output) = run_debugger(PROGRAM, "s\ns\ns\nframes\nn\nn\nglobals\nc\n");
+    assert_eq!(result, Ok(Object::IntegerObject(3)));
+    assert_eq!(output, "<main> line 1:1 0000 OpClosure 0 0
+(debug) <main> line 5:1 0007 OpConstant 1
+(debug) <main> line 6:1 0013 OpGetGlobal 0
+(debug) add line 2:5 0000 OpGetLocal 0
+(debug) #0 add line 2:5 0000 OpGetLocal 0
+    a = 1
+    b = 2
+    sum = Null
+#1 <main> line 6:1 0022 OpCall 2
+(debug) add line 3:5 0007 OpGetLocal 2
+(debug) <main> line 6:1 0024 OpPop
+(debug) add = Closure[CompiledFunction[0000 OpGetLocal 0
+0002 OpGetLocal 1
+0004 OpAdd
+0005 OpSetLocal 2
+0007 OpGetLocal 2
+0009 OpReturnValue
+]]
+x = 1
+(debug) ");
+}
+
+#[test]
+fn test_breakpoints() {
+    let (result, output) = run_debugger(PROGRAM, "b add\nb 6\nc\nc\nn\nc\n");
+    assert_eq!(result, Ok(Object::IntegerObject(3)));
+    assert_eq!(output, "<main> line 1:1 0000 OpClosure 0 0
+(debug) breakpoint 1 at fn add
+(debug) breakpoint 2 at line 6
+(debug) <main> line 6:1 0013 OpGetGlobal 0
+(debug) add line 2:5 0000 OpGetLocal 0
+(debug) add line 3:5 0007 OpGetLocal 2
+(debug) ");
+}
+
+#[test]
+fn test_end_of_input_quits() {
+    let (result, output) = run_debugger("1 + 2", "stack\n");
+    assert_eq!(result, Err("interrupted".to_string()));
+    assert_eq!(output, "<main> line 1:1 0000 OpConstant 0\n(debug) stack is empty\n(debug) ");
+}
+
+#[test]
+fn test_empty_program() {
+    assert_eq!(run_debugger(";", "c\n"), (Ok(Object::Null), String::new()));
+    let (result, _) = run_debugger("let x = 1;", "c\n");
+    assert_eq!(result, Ok(Object::Null));
+}
+
+#[test]
+fn test_errors_are_diagnostics() {
+    let input = Box::new(Cursor::new(String::new()));
+    let diagnostics = debug("let x = ;\nlet y = z;", input, Box::new(Transcript::default())).unwrap_err();
+    assert_eq!(render_all(&diagnostics, "bad.monkey", "let x = ;\nlet y = z;", false), "error[E0001]: expected an expression, found `;`
+ --> bad.monkey:1:9
+  |
+1 | let x = ;
+  |         ^");
+
+    let input = Box::new(Cursor::new("c\n".to_string()));
+    let diagnostics = debug("let a = 1;\na / 0", input, Box::new(Transcript::default())).unwrap_err();
+    assert_eq!(render_all(&diagnostics, "div.monkey", "let a = 1;\na / 0", false), "error[E0201]: division by zero
+ --> div.monkey:2:1
+  |
+2 | a / 0
+  | ^^^^^");
+}
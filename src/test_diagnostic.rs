@@ -0,0 +1,74 @@
+use crate::diagnostic::{render_all, Diagnostic, Label, Severity};
+use crate::engine::Engine;
+use crate::token::{Position, Span};
+
+fn span(line: usize, start: usize, end: usize) -> Span {
+    Span::new(Position { line, column: start }, Position { line, column: end })
+}
+
+#[test]
+fn test_render() {
+    let source = "let a = 1;\nlet b = a + \"two\";\n";
+    let diagnostic = Diagnostic::error("E0201", "type mismatch")
+        .with_span(Some(span(2, 9, 18)))
+        .with_label(Label::new(span(1, 5, 6), "a is an INTEGER"))
+        .with_note("strings can only be added to strings")
+        .with_help("convert one of them first");
+    assert_eq!(diagnostic.render("main.mk", source, false), "error[E0201]: type mismatch
+ --> main.mk:2:9
+  |
+1 | let a = 1;
+  |     - a is an INTEGER
+2 | let b = a + \"two\";
+  |         ^^^^^^^^^
+  = note: strings can only be added to strings
+  = help: convert one of them first");
+    assert_eq!(diagnostic.to_string(), "error[E0201]: type mismatch at 2:9");
+
+    let coloured = diagnostic.render("main.mk", source, true);
+    assert!(coloured.starts_with("\x1b[1;31merror[E0201]\x1b[0m"));
+    assert!(coloured.contains("\x1b[1;34m-->\x1b[0m main.mk:2:9"));
+}
+
+#[test]
+fn test_render_without_span() {
+    let diagnostic = Diagnostic::new(Severity::Warning, "W0001", "something odd");
+    assert_eq!(diagnostic.render("main.mk", "", false), "warning[W0001]: something odd");
+
+    // A span over several lines is underlined to the end of its first line.
+    let multiline = Diagnostic::error("E0001", "bad").with_span(Some(Span::new(
+        Position { line: 1, column: 4 },
+        Position { line: 2, column: 2 }
+    )));
+    assert_eq!(multiline.render("main.mk", "if (x) {\n}", false), "error[E0001]: bad
+ --> main.mk:1:4
+  |
+1 | if (x) {
+  |    ^^^^^");
+}
+
+#[test]
+fn test_engine_diagnostics() {
+    let source = "let = 1;\nlet b = (2;";
+    let error = Engine::new().run(source).unwrap_err();
+    let diagnostics = error.diagnostics();
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(render_all(&diagnostics, "main.mk", source, false), "error[E0001]: expected an identifier, found `=`
+ --> main.mk:1:5
+  |
+1 | let = 1;
+  |     ^
+
+error[E0001]: expected `)`, found `;`
+ --> main.mk:2:11
+  |
+2 | let b = (2;
+  |           ^
+  |         - opened here");
+
+    let source = "let f = fn(x) {\n  x / 0\n};\nf(1)";
+    let diagnostic = &Engine::new().run(source).unwrap_err().diagnostics()[0];
+    assert_eq!(diagnostic.code, "E0201");
+    assert_eq!(diagnostic.span, Some(span(2, 3, 8)));
+    assert_eq!(diagnostic.notes, vec!["at f (2:3)", "at <main> (4:1)"]);
+}
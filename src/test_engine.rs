@@ -0,0 +1,93 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use crate::builtins::Builtins;
+use crate::engine::{Engine, EngineError};
+use crate::object::{FunctionCaller, Object};
+
+fn square(args: Vec<Object>, _caller: &mut dyn FunctionCaller) -> Object {
+    match args.first() {
+        Some(Object::IntegerObject(content)) => Object::IntegerObject(content * content),
+        _ => Object::Error("square expects an INTEGER".to_string())
+    }
+}
+
+#[test]
+fn test_run_keeps_state() {
+    let mut engine = Engine::new();
+    assert_eq!(engine.run("let a = 5;"), Ok(Object::Null));
+    assert_eq!(engine.run("let b = a * 2; b + 1"), Ok(Object::IntegerObject(11)));
+    assert_eq!(engine.get_global("b"), Some(Object::IntegerObject(10)));
+    assert_eq!(engine.get_global("missing"), None);
+}
+
+#[test]
+fn test_globals_from_host() {
+    let mut engine = Engine::new();
+    engine.set_global("name", "monkey");
+    engine.set_global("numbers", vec![1, 2, 3]);
+    engine.set_global("ages", HashMap::from([("ana", 30)]));
+    assert_eq!(engine.run("upper(name) + \"!\""), Ok(Object::StringObject("MONKEY!".to_string())));
+    assert_eq!(engine.run("len(numbers) + ages[\"ana\"]"), Ok(Object::IntegerObject(33)));
+
+    engine.set_global("name", "gorilla");
+    assert_eq!(engine.run("name"), Ok(Object::StringObject("gorilla".to_string())));
+
+    engine.run("let evens = filter(numbers, fn(x) { x / 2 * 2 == x });").unwrap();
+    let evens: Vec<i64> = engine.get_global("evens").unwrap().try_into().unwrap();
+    assert_eq!(evens, vec![2]);
+    let name: Result<i64, String> = engine.get_global("name").unwrap().try_into();
+    assert_eq!(name, Err("expected INTEGER, found STRING".to_string()));
+}
+
+#[test]
+fn test_register_fn() {
+    let mut engine = Engine::new();
+    engine.register_fn("square", square);
+    assert_eq!(engine.run("map([1, 2, 3], square)"), Ok(Object::from(vec![1, 4, 9])));
+
+    engine.register_fn("len", square);
+    assert_eq!(engine.run("len(4)"), Ok(Object::IntegerObject(16)));
+}
+
+#[test]
+fn test_register_closure() {
+    let calls = Rc::new(Cell::new(0));
+    let counter = calls.clone();
+    let mut engine = Engine::new();
+    engine.register_fn("tick", move |_args, _caller| {
+        counter.set(counter.get() + 1);
+        Object::IntegerObject(counter.get())
+    });
+    engine.run("let next = fn() { tick() };").unwrap();
+    assert_eq!(engine.run("next(); next(); tick()"), Ok(Object::IntegerObject(3)));
+    assert_eq!(calls.get(), 3);
+}
+
+#[test]
+fn test_builtins_registry() {
+    let mut builtins = Builtins::empty();
+    assert_eq!(builtins.register("square", square), 0);
+    assert_eq!(builtins.register("cube", |_args, _caller| Object::Null), 1);
+    assert_eq!(builtins.register("square", |_args, _caller| Object::Null), 0);
+    assert_eq!(builtins.names(), vec!["square", "cube"]);
+    assert_eq!(builtins.get(1).map(|builtin| builtin.name.as_str()), Some("cube"));
+    assert!(builtins.lookup("len").is_none());
+    assert_eq!(Builtins::new().names()[0], "len");
+}
+
+#[test]
+fn test_errors() {
+    let mut engine = Engine::new();
+    assert!(matches!(engine.run("let = 5;"), Err(EngineError::Parse(_))));
+    match engine.run("missing + 1") {
+        Err(EngineError::Compile(error)) => assert_eq!(error.message, "undefined variable missing"),
+        other => panic!("expected compile error, got {:?}", other)
+    }
+    match engine.run("let a = 1; 1 / 0") {
+        Err(EngineError::Runtime(error)) => assert_eq!(error.message, "division by zero"),
+        other => panic!("expected runtime error, got {:?}", other)
+    }
+    assert_eq!(engine.get_global("a"), None);
+    assert_eq!(engine.run("let a = 2; a"), Ok(Object::IntegerObject(2)));
+}
@@ -1,7 +1,10 @@
 #[allow(dead_code)]
+use std::time::Duration;
 use crate::ast::Node;
+use crate::budget::{Budget, LimitExceeded};
+use crate::builtins::BUILT_INS;
 use crate::environment::Environment;
-use crate::evaluator::eval;
+use crate::evaluator::{eval, eval_with_budget};
 use crate::lexer::Lexer;
 use crate::object::Object;
 use crate::object::Object::IntegerObject;
@@ -189,7 +192,7 @@ fn test_function_application() {
         ("let double = fn(x) { x * 2; }; double(5);", 10),
         ("let add = fn(x, y) { x + y ; }; add(5,5);", 10),
         ("let add = fn(x, y) { x + y ; }; add(5 + 5, add(5,5))", 20),
-        ("let fn(x) { x; }(5)", 5)
+        ("fn(x) { x; }(5)", 5)
     ];
 
     for input in inputs {
@@ -216,4 +219,169 @@ fn test_len_function() {
     for input in inputs {
         assert_eq!(test_eval(input.0.to_string()).expect("Couldn't eval"), input.1);
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_builtin_arities() {
+    // The counts in BUILT_INS must agree with the checks each function makes.
+    for (name, _, arity) in BUILT_INS {
+        let Some((least, most)) = arity else {
+            continue;
+        };
+        for count in [least - 1, most + 1] {
+            let source = format!("{}({})", name, vec!["1"; count].join(", "));
+            match test_eval(source.clone()).expect("Couldn't eval") {
+                Object::Error(message) => assert!(message.starts_with("wrong number of arguments"), "{}: {}", source, message),
+                other => panic!("{} returned {:?}", source, other)
+            }
+        }
+    }
+}
+
+#[test]
+fn test_hash_functions() {
+    let inputs = vec![
+        ("len({1: 2, 3: 4})", IntegerObject(2)),
+        ("keys({\"b\": 2, \"a\": 1})", Object::Array(vec![
+            Object::StringObject("a".to_string()),
+            Object::StringObject("b".to_string())].into())),
+        ("values({true: 1, false: 0})", Object::Array(vec![
+            IntegerObject(0),
+            IntegerObject(1)].into())),
+        ("entries({\"a\": 1})", Object::Array(vec![
+            Object::Array(vec![
+                Object::StringObject("a".to_string()),
+                IntegerObject(1)].into())].into())),
+        ("has({\"a\": 1}, \"a\")", Object::BooleanObject(true)),
+        ("has({\"a\": 1}, \"b\")", Object::BooleanObject(false)),
+        ("len(delete({\"a\": 1, \"b\": 2}, \"a\"))", IntegerObject(1)),
+        ("merge({\"a\": 1}, {\"a\": 2})[\"a\"]", IntegerObject(2)),
+        ("has({}, fn(x) { x })", Object::Error("type FUNCTION TYPE is not hashable".to_string())),
+        ("values(1, 2)", Object::Error("wrong number of arguments: got = 2, want = 1".to_string()))
+    ];
+
+    for input in inputs {
+        assert_eq!(test_eval(input.0.to_string()).expect("Couldn't eval"), input.1, "{}", input.0);
+    }
+}
+
+#[test]
+fn test_string_functions() {
+    let inputs = vec![
+        ("len(\"héllo\")", IntegerObject(5)),
+        ("split(\"añb\", \"\")", Object::Array(vec![
+            Object::StringObject("a".to_string()),
+            Object::StringObject("ñ".to_string()),
+            Object::StringObject("b".to_string())].into())),
+        ("join(split(\"a b c\", \" \"), \",\")", Object::StringObject("a,b,c".to_string())),
+        ("trim(\"\\t x \")", Object::StringObject("\\t x".to_string())),
+        ("upper(\"monkey\")", Object::StringObject("MONKEY".to_string())),
+        ("lower(\"MONKEY\")", Object::StringObject("monkey".to_string())),
+        ("contains(\"monkey\", \"onk\")", Object::BooleanObject(true)),
+        ("starts_with(\"monkey\", \"mon\")", Object::BooleanObject(true)),
+        ("ends_with(\"monkey\", \"mon\")", Object::BooleanObject(false)),
+        ("replace(\"banana\", \"an\", \"\")", Object::StringObject("ba".to_string())),
+        ("substr(\"ñandú\", 4, 10)", Object::StringObject("ú".to_string())),
+        ("substr(\"monkey\", -1)", Object::Error("substr bounds must not be negative: start = -1".to_string())),
+        ("substr(\"monkey\", 1, -2)", Object::Error("substr bounds must not be negative: start = 1, length = -2".to_string())),
+        ("substr(\"monkey\", 2)", Object::StringObject("nkey".to_string())),
+        ("len(chars(\"ñandú\"))", IntegerObject(5)),
+        ("index_of(\"ñandú\", \"n\")", IntegerObject(2)),
+        ("split(\"a\", 1)", Object::Error("not suported type: INTEGER in position 1, expected STRING".to_string()))
+    ];
+
+    for input in inputs {
+        assert_eq!(test_eval(input.0.to_string()).expect("Couldn't eval"), input.1, "{}", input.0);
+    }
+}
+
+#[test]
+fn test_higher_order_functions() {
+    let inputs = vec![
+        ("map([1, 2, 3], fn(x) { x + 1 })", Object::Array(vec![
+            IntegerObject(2),
+            IntegerObject(3),
+            IntegerObject(4)].into())),
+        ("filter([1, 0, 2], fn(x) { x })", Object::Array(vec![
+            IntegerObject(1),
+            IntegerObject(2)].into())),
+        ("reduce([\"a\", \"b\"], \"\", fn(acc, x) { acc + x })", Object::StringObject("ab".to_string())),
+        ("sort_by([\"bb\", \"a\", \"ccc\"], fn(a, b) { len(b) - len(a) })", Object::Array(vec![
+            Object::StringObject("ccc".to_string()),
+            Object::StringObject("bb".to_string()),
+            Object::StringObject("a".to_string())].into())),
+        ("find([1, 2, 3], fn(x) { x == 3 })", IntegerObject(3)),
+        ("map([1], fn(x) { x + true })", Object::Error("type mismatch: INTEGER PLUS BOOLEAN".to_string())),
+        ("map(1, len)", Object::Error("not suported type: INTEGER in position 0, expected ARRAY".to_string()))
+    ];
+
+    for input in inputs {
+        assert_eq!(test_eval(input.0.to_string()).expect("Couldn't eval"), input.1, "{}", input.0);
+    }
+}
+
+#[test]
+fn test_try_catch() {
+    let inputs = vec![
+        ("try { 1 } catch (e) { 2 }", IntegerObject(1)),
+        ("try { error(\"boom\") } catch (e) { e }", Object::StringObject("boom".to_string())),
+        ("try { 1 / 0 } catch (e) { e }", Object::StringObject("division by zero".to_string())),
+        ("try { 5 + true } catch (e) { e }", Object::StringObject("type mismatch: INTEGER PLUS BOOLEAN".to_string())),
+        ("let f = fn() { try { return 1; } catch (e) { 2 }; 3 }; f()", IntegerObject(1)),
+        ("try { map([0], fn(x) { 1 / x }) } catch (e) { \"caught: \" + e }", Object::StringObject("caught: division by zero".to_string())),
+        ("error(\"uncaught\"); 5", Object::Error("uncaught".to_string()))
+    ];
+
+    for input in inputs {
+        assert_eq!(test_eval(input.0.to_string()).expect("Couldn't eval"), input.1, "{}", input.0);
+    }
+}
+
+#[test]
+fn test_budget() {
+    let count = "let count = fn(g, n) { if (n == 0) { 0 } else { g(g, n - 1) + 1 } };";
+    let steps = Budget { max_steps: Some(1000), ..Budget::default() };
+    let run = |input: String, budget: &Budget| {
+        let lexer = Lexer::new(input);
+        let mut parser = Parser::new(lexer);
+        let program = parser.parse_program();
+        let mut env = Environment::new();
+        eval_with_budget(Node::Program(program), & mut env, budget)
+    };
+
+    assert_eq!(run(format!("{} count(count, 10)", count), &steps), Ok(IntegerObject(10)));
+    let inputs = vec![
+        format!("{} count(count, 500)", count),
+        format!("{} try {{ count(count, 500) }} catch (e) {{ 0 }}", count),
+        format!("{} map([1, 2], fn(x) {{ try {{ count(count, 500) }} catch (e) {{ 0 }} }})", count),
+    ];
+    for input in inputs {
+        assert_eq!(run(input.clone(), &steps), Err(LimitExceeded::Steps(1000)), "{}", input);
+    }
+
+    let timeout = Budget { timeout: Some(Duration::ZERO), ..Budget::default() };
+    assert_eq!(run(format!("{} count(count, 500)", count), &timeout), Err(LimitExceeded::Time(Duration::ZERO)));
+}
+
+#[test]
+fn test_memory_limit() {
+    let double = "let double = fn(g, s, n) { if (n == 0) { s } else { g(g, s + s, n - 1) } };";
+    let budget = Budget { max_memory: Some(100_000), ..Budget::default() };
+    let run = |input: String| {
+        let lexer = Lexer::new(input);
+        let mut parser = Parser::new(lexer);
+        let program = parser.parse_program();
+        let mut env = Environment::new();
+        eval_with_budget(Node::Program(program), & mut env, &budget)
+    };
+
+    assert_eq!(run(format!("{} len(double(double, \"ab\", 4))", double)), Ok(IntegerObject(32)));
+    let inputs = vec![
+        format!("{} len(double(double, \"ab\", 40))", double),
+        format!("{} try {{ double(double, \"ab\", 40) }} catch (e) {{ 0 }}", double),
+        format!("{} let big = double(double, \"a\", 9); len(replace(big, \"a\", big))", double),
+    ];
+    for input in inputs {
+        assert_eq!(run(input.clone()), Err(LimitExceeded::Memory(100_000)), "{}", input);
+    }
+}
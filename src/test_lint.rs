@@ -0,0 +1,81 @@
+use crate::analysis::Analysis;
+use crate::diagnostic::Severity;
+use crate::lint::{lint, LintConfig, Rule};
+use crate::token::Position;
+
+const SOURCE: &str = "let unused = 1;
+let _ignored = 2;
+let scale = fn(x, factor) {
+  let factor = 2;
+  return x * factor;
+  puts(x);
+};
+if (1 < 2) { puts(len(\"a\", \"b\")) }
+let len = fn(a, b) { a };
+scale(len(1, 2), 3);";
+
+fn warnings(source: &str, config: &LintConfig) -> Vec<(String, &'static str, Position)> {
+    let analysis = Analysis::new(source);
+    assert!(analysis.errors.is_empty());
+    lint(&analysis, config)
+        .into_iter()
+        .map(|warning| {
+            assert_eq!(warning.severity, Severity::Warning);
+            (warning.message, warning.code, warning.span.unwrap().start)
+        })
+        .collect()
+}
+
+fn at(line: usize, column: usize) -> Position {
+    Position { line, column }
+}
+
+#[test]
+fn test_rules() {
+    let expected = vec![
+        ("`unused` is never used".to_string(), "W0001", at(1, 5)),
+        ("`factor` shadows a parameter".to_string(), "W0002", at(4, 7)),
+        ("unreachable code".to_string(), "W0003", at(6, 3)),
+        ("this condition is always true".to_string(), "W0004", at(8, 5)),
+        ("`len` takes 1 argument but 2 were given".to_string(), "W0005", at(8, 19)),
+    ];
+    // The len redefined on line 9 is not the builtin, so its call is fine.
+    assert_eq!(warnings(SOURCE, &LintConfig::default()), expected);
+}
+
+#[test]
+fn test_rules_can_be_turned_off() {
+    let mut config = LintConfig::default();
+    for rule in Rule::ALL {
+        config.disable(rule);
+    }
+    assert!(warnings(SOURCE, &config).is_empty());
+
+    config.enable(Rule::from_name("builtin-arity").unwrap());
+    let found: Vec<&'static str> = warnings(SOURCE, &config).into_iter().map(|(_, code, _)| code).collect();
+    assert_eq!(found, vec!["W0005"]);
+    assert_eq!(Rule::from_name("no-such-rule"), None);
+}
+
+#[test]
+fn test_clean_program() {
+    let source = "let add = fn(a, b) { if (a > b) { return a; } b };
+let x = 3;
+if (add(x, 2) == 5) { puts(substr(\"hello\", 1)) } else { puts(push([1], 2)) }";
+    assert!(warnings(source, &LintConfig::default()).is_empty());
+}
+
+#[test]
+fn test_constant_conditions() {
+    let analysis = Analysis::new("if (0) { 1 } else { 2 };\nif (\"s\") { 1 };\nif (!false) { 1 } else { 2 };\nif (1) { 1 };");
+    let found: Vec<(String, Vec<String>)> = lint(&analysis, &LintConfig::default())
+        .into_iter()
+        .map(|warning| (warning.message, warning.help))
+        .collect();
+    // Strings aren't conditions both engines agree on, so "s" is left alone.
+    assert_eq!(found, vec![
+        ("this condition is always false".to_string(), vec!["the body never runs".to_string()]),
+        ("this condition is always true".to_string(), vec!["the else branch never runs".to_string()]),
+        ("this condition is always true".to_string(), vec![]),
+    ]);
+}
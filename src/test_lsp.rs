@@ -0,0 +1,106 @@
+use std::io::Cursor;
+use serde_json::{json, Value};
+use crate::lsp::serve;
+
+const URI: &str = "file:///main.monkey";
+
+fn frame(message: Value) -> String {
+    let body = message.to_string();
+    format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+}
+
+// Runs a session and returns the messages the server sent, and whether it
+// was shut down cleanly.
+fn session(messages: Vec<Value>) -> (Vec<Value>, bool) {
+    let input: String = messages.into_iter().map(frame).collect();
+    let mut output = Vec::new();
+    let clean = serve(Cursor::new(input), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let replies = output.split("Content-Length: ")
+        .filter(|part| !part.is_empty())
+        .map(|part| serde_json::from_str(part.split_once("\r\n\r\n").unwrap().1).unwrap())
+        .collect();
+    (replies, clean)
+}
+
+fn request(id: u64, method: &str, line: u64, character: u64) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "method": method, "params": {
+        "textDocument": {"uri": URI},
+        "position": {"line": line, "character": character},
+        "context": {"includeDeclaration": true}
+    }})
+}
+
+fn open(text: &str) -> Value {
+    json!({"jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+        "textDocument": {"uri": URI, "languageId": "monkey", "version": 1, "text": text}
+    }})
+}
+
+fn result(replies: &[Value], id: u64) -> &Value {
+    &replies.iter().find(|reply| reply["id"] == id).unwrap()["result"]
+}
+
+#[test]
+fn test_lifecycle() {
+    let (replies, clean) = session(vec![
+        json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}),
+        json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}),
+        json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/formatting", "params": {}}),
+        json!({"jsonrpc": "2.0", "id": 3, "method": "shutdown"}),
+        json!({"jsonrpc": "2.0", "method": "exit"}),
+    ]);
+    assert!(clean);
+    assert_eq!(replies.len(), 3);
+    assert_eq!(result(&replies, 1)["capabilities"]["definitionProvider"], true);
+    assert_eq!(replies[1]["error"]["code"], -32601);
+    assert_eq!(replies[2]["result"], Value::Null);
+
+    let (_, clean) = session(vec![json!({"jsonrpc": "2.0", "method": "exit"})]);
+    assert!(!clean);
+}
+
+#[test]
+fn test_publishes_diagnostics() {
+    let change = json!({"jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+        "textDocument": {"uri": URI, "version": 2},
+        "contentChanges": [{"text": "let a = 1;\na + b"}]
+    }});
+    let (replies, _) = session(vec![open("let = 1;\nlet x = (2;"), change]);
+    assert_eq!(replies.len(), 2);
+    assert_eq!(replies[0]["method"], "textDocument/publishDiagnostics");
+    let diagnostics = replies[0]["params"]["diagnostics"].as_array().unwrap();
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0]["message"], "expected an identifier, found `=`");
+    assert_eq!(diagnostics[0]["range"]["start"], json!({"line": 0, "character": 4}));
+    assert_eq!(diagnostics[1]["relatedInformation"][0]["location"]["range"]["start"], json!({"line": 1, "character": 8}));
+
+    let diagnostics = replies[1]["params"]["diagnostics"].as_array().unwrap();
+    assert_eq!(diagnostics[0]["code"], "E0101");
+    assert_eq!(diagnostics[0]["message"], "undefined variable b\nhelp: did you mean `a`?");
+    assert_eq!(diagnostics[0]["range"], json!({"start": {"line": 1, "character": 4}, "end": {"line": 1, "character": 5}}));
+}
+
+#[test]
+fn test_navigation() {
+    let text = "let double = fn(x) { x * 2 };\nlet y = double(2);\ndouble(y)";
+    let (replies, _) = session(vec![
+        open(text),
+        request(1, "textDocument/definition", 2, 2),
+        request(2, "textDocument/references", 0, 5),
+        request(3, "textDocument/hover", 1, 10),
+        request(4, "textDocument/hover", 0, 21),
+        request(5, "textDocument/completion", 0, 22),
+        request(6, "textDocument/definition", 2, 8),
+    ]);
+    assert_eq!(result(&replies, 1)["range"], json!({"start": {"line": 0, "character": 4}, "end": {"line": 0, "character": 10}}));
+    let lines: Vec<&Value> = result(&replies, 2).as_array().unwrap().iter().map(|location| &location["range"]["start"]["line"]).collect();
+    assert_eq!(lines, vec![0, 1, 2]);
+    assert_eq!(result(&replies, 3)["contents"]["value"], "```monkey\nlet double = fn(x) { x * 2 };\n```");
+    assert_eq!(result(&replies, 4)["contents"]["value"], "parameter `x`");
+
+    let labels: Vec<&str> = result(&replies, 5).as_array().unwrap().iter().map(|item| item["label"].as_str().unwrap()).collect();
+    assert_eq!(labels[0], "x");
+    assert!(labels.contains(&"len") && !labels.contains(&"double"));
+    assert_eq!(result(&replies, 6)["range"]["start"], json!({"line": 1, "character": 4}));
+}
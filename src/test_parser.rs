@@ -4,7 +4,7 @@ use std::hash::Hash;
 use crate::ast::{Expression, Identifier, Statement};
 use crate::ast::Expression::IdentifierExpression;
 use crate::ast::Statement::ExpressionStatement;
-use crate::token::Token;
+use crate::token::{Position, Span, Token};
 use crate::lexer::Lexer;
 use crate::parser::Parser;
 #[test]
@@ -128,7 +128,7 @@ fn test_parser() {
 }
 
 fn test_parse_let_statement(stmt: &Statement, name: String, value: String) -> bool {
-    if let Statement::LetStatement(id, expr) = stmt {
+    if let Statement::LetStatement(id, expr, _) = stmt {
         assert_eq!(id.get_id(), name);
         assert_eq!(expr.to_string(), value);
         return true;
@@ -154,7 +154,7 @@ fn test_return_statements() {
         panic!();
     }
     for i in 0..program.statements.len() {
-        if let Statement::ReturnStatement(expr) = &program.statements[i]
+        if let Statement::ReturnStatement(expr, _) = &program.statements[i]
         {
             assert_eq!(expr.to_string(), results[i]);
         }
@@ -171,7 +171,7 @@ fn test_identifier_expression() {
     let mut parser = Parser::new(lexer);
     let program = parser.parse_program();
     assert_eq!(program.statements.len(), 2);
-    if let Statement::ExpressionStatement(content) = program.statements.get(1).expect("No statement at 0") {
+    if let Statement::ExpressionStatement(content, _) = program.statements.get(1).expect("No statement at 0") {
         if let Expression::IdentifierExpression(id) = content {
             if id.get_id() == "adios" {
                 return;
@@ -196,7 +196,7 @@ fn test_integer_literals()  {
     let program = parser.parse_program();
 
     assert_eq!(program.statements.len(), 1);
-    if let Statement::ExpressionStatement(expr) = program.statements.get(0).expect("No statement at 0") {
+    if let Statement::ExpressionStatement(expr, _) = program.statements.get(0).expect("No statement at 0") {
         if let Expression::IntegerExpression(_) = expr {
             assert_eq!(expr.to_string(), "5");
         }
@@ -222,7 +222,7 @@ fn test_parsing_prefix_expr() {
         let mut parser = Parser::new(lexer);
         let program = parser.parse_program();
         assert_eq!(program.statements.len(), 1);
-        if let Statement::ExpressionStatement(expr) = &program.statements[0]
+        if let Statement::ExpressionStatement(expr, _) = &program.statements[0]
         {
             if let Expression::PrefixExpression(tok, inner_expr) = expr
             {
@@ -264,7 +264,7 @@ fn test_parsing_infix_expr() {
         let program = parser.parse_program();
         assert_eq!(program.statements.len(), 1);
         let stmt = &program.statements[0];
-        if let ExpressionStatement(expr) = stmt {
+        if let ExpressionStatement(expr, _) = stmt {
             if let Expression::InfixExpression(right, tok, _left) = expr
                 {
                     assert_eq!(tok.clone(), toks[i]);
@@ -357,7 +357,7 @@ fn test_booleans() {
     let mut parser = Parser::new(lexer);
     let program = parser.parse_program();
     assert_eq!(program.statements.len(), 1);
-    if let ExpressionStatement(expr) = &program.statements[0] {
+    if let ExpressionStatement(expr, _) = &program.statements[0] {
         if let Expression::BoolExpression(content) = expr {
             assert_eq!(*content, true);
             return;
@@ -394,7 +394,7 @@ fn test_if_expression() {
 
     assert_eq!(program.statements.len(), 1);
 
-    if let ExpressionStatement(expr) = &program.statements[0] {
+    if let ExpressionStatement(expr, _) = &program.statements[0] {
         if let Expression::IfExpression(content) = expr {
             test_infix_expression(content.condition.as_ref().clone(), ValueType::StringInput("x".to_string()), ValueType::StringInput("y".to_string()), Token::LT);
             assert_eq!(content.consequence.len(), 1);
@@ -422,7 +422,7 @@ fn test_fn_expression() {
 
     assert_eq!(program.statements.len(), 1);
 
-    if let ExpressionStatement(expr) = &program.statements[0] {
+    if let ExpressionStatement(expr, _) = &program.statements[0] {
         if let Expression::FnExpression(content) = expr {
             assert_eq!(content.params.len(), 2);
             assert_eq!(content.params[0].get_id(), "x");
@@ -445,7 +445,7 @@ fn test_call_expression() {
     let mut parser = Parser::new(lexer);
     let program = parser.parse_program();
     assert_eq!(program.statements.len(), 1);
-    if let ExpressionStatement(expr) = &program.statements[0]
+    if let ExpressionStatement(expr, _) = &program.statements[0]
     {
         if let Expression::CallExpression(content) = expr {
             assert_eq!(content.function.to_string().as_str(), "add");
@@ -499,4 +499,94 @@ fn hash_string()
 {
     let mut state = DefaultHasher::new();
     assert_eq!("hola".to_string().hash(& mut state), "hola".to_string().hash(& mut state))
-}
\ No newline at end of file
+}
+#[test]
+fn test_try_expression() {
+    let lexer = Lexer::new("try { x } catch (err) { err }".to_string());
+    let mut parser = Parser::new(lexer);
+    let program = parser.parse_program();
+    assert_eq!(parser.errors.len(), 0);
+    assert_eq!(program.statements.len(), 1);
+    if let Statement::ExpressionStatement(Expression::TryExpression(content), _) = &program.statements[0] {
+        assert_eq!(content.body.len(), 1);
+        assert_eq!(content.error.get_id(), "err");
+        assert_eq!(content.handler[0].to_string(), "err;");
+    }
+    else {
+        panic!("Expected try expression");
+    }
+}
+
+#[test]
+fn test_statement_spans() {
+    let input = "let a = 5;\n  a + 1\nreturn fn(x) {\n  x\n};";
+    let mut parser = Parser::new(Lexer::new(input.to_string()));
+    let program = parser.parse_program();
+    assert!(parser.errors.is_empty(), "{:?}", parser.errors);
+
+    let at = |line, column| Position { line, column };
+    let spans: Vec<Span> = program.statements.iter().map(|stmt| stmt.span()).collect();
+    assert_eq!(spans, vec![
+        Span::new(at(1, 1), at(1, 11)),
+        Span::new(at(2, 3), at(2, 8)),
+        Span::new(at(3, 1), at(5, 3)),
+    ]);
+    if let Statement::LetStatement(id, _, _) = &program.statements[0] {
+        assert_eq!(id.span, Span::new(at(1, 5), at(1, 6)));
+    }
+}
+
+fn parse_errors(input: &str) -> (Vec<Statement>, Vec<String>) {
+    let mut parser = Parser::new(Lexer::new(input.to_string()));
+    let program = parser.parse_program();
+    (program.statements, parser.errors.iter().map(|error| error.to_string()).collect())
+}
+
+#[test]
+fn test_parse_error_messages() {
+    let inputs = vec![
+        ("let = 5;", "1:5: expected an identifier, found `=`"),
+        ("let x 5;", "1:7: expected `=`, found `5`"),
+        ("if (x { 1 }", "1:7: expected `)`, found `{`"),
+        ("add(1 2)", "1:7: expected `,` or `)`, found `2`"),
+        ("[1, 2", "1:6: expected `,` or `]`, found end of input"),
+        ("{1: 2 3: 4}", "1:7: expected `,` or `}`, found `3`"),
+        ("fn(x, 1) { x }", "1:7: expected an identifier, found `1`"),
+        ("fn(x) { x", "1:10: expected `}`, found end of input"),
+        ("1 + ;", "1:5: expected an expression, found `;`"),
+        ("99999999999999999999", "1:1: 99999999999999999999 doesn't fit in an integer"),
+    ];
+    for (input, expected) in inputs {
+        let (_, errors) = parse_errors(input);
+        assert_eq!(errors, vec![expected.to_string()], "{}", input);
+    }
+}
+
+#[test]
+fn test_reports_every_error() {
+    let input = "let = 5;\nlet x = (1 + 2;\nlet f = fn(a) { a + ; let b = 2; b };\nlet y = 3;\nreturn y * ;";
+    let (statements, errors) = parse_errors(input);
+    assert_eq!(errors, vec![
+        "1:5: expected an identifier, found `=`",
+        "2:15: expected `)`, found `;`",
+        "3:21: expected an expression, found `;`",
+        "5:12: expected an expression, found `;`",
+    ]);
+
+    // The statements around the errors still parse, with error nodes in place
+    // of the broken expressions.
+    let statements: Vec<String> = statements.iter().map(|stmt| stmt.to_string()).collect();
+    assert_eq!(statements, vec![
+        "let x = <error>;",
+        "let f = fn(a){(a + <error>);let b = 2;b;};",
+        "let y = 3;",
+        "return (y * <error>);",
+    ]);
+}
+
+#[test]
+fn test_recovers_at_closing_brace() {
+    let (statements, errors) = parse_errors("let f = fn() { 1 + }; let g = 2;");
+    assert_eq!(errors, vec!["1:20: expected an expression, found `}`"]);
+    assert_eq!(statements.len(), 2);
+}
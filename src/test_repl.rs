@@ -0,0 +1,116 @@
+use crate::repl::{is_incomplete, ReplMode, Reply, Session};
+
+#[test]
+fn test_is_incomplete() {
+    let inputs = vec![
+        ("let a = 5;", false),
+        ("let add = fn(a, b) {", true),
+        ("let add = fn(a, b) {\n a + b\n};", false),
+        ("[1, 2,", true),
+        ("add(1,\n 2)", false),
+        ("puts(\"open", true),
+        ("\"{ inside a string\"", false),
+        ("}", false),
+        ("", false)
+    ];
+
+    for input in inputs {
+        assert_eq!(is_incomplete(input.0), input.1, "{}", input.0);
+    }
+}
+
+fn output(session: &mut Session, entry: &str) -> String {
+    match session.handle(entry) {
+        Reply::Output(output) => output,
+        Reply::Quit => panic!("unexpected quit for {}", entry)
+    }
+}
+
+#[test]
+fn test_meta_commands() {
+    let mut session = Session::new(ReplMode::CompilerMode);
+    output(&mut session, "let a = 5; let b = \"five\";");
+    assert_eq!(output(&mut session, ":globals"), "a = 5\nb = five");
+    assert_eq!(output(&mut session, ":type b"), "STRING");
+    assert_eq!(output(&mut session, ":type let c = 1; c"), "INTEGER");
+    assert_eq!(output(&mut session, ":globals"), "a = 5\nb = five");
+    assert_eq!(output(&mut session, ":disasm a + 1"), "0000 OpGetGlobal 0\n0003 OpConstant 2\n0006 OpAdd\n0007 OpPop");
+    assert!(output(&mut session, ":ast 1 + 2").contains("InfixExpression"));
+    assert_eq!(output(&mut session, ":mode"), "usage: :mode vm|eval");
+    assert_eq!(output(&mut session, ":nope"), "unknown command :nope, try :help");
+
+    output(&mut session, ":mode eval");
+    assert_eq!(output(&mut session, ":globals"), "no globals defined");
+    output(&mut session, "let d = [1, 2];");
+    assert_eq!(output(&mut session, ":globals"), "d = [1,2]");
+    assert_eq!(output(&mut session, ":type d"), "ARRAY");
+
+    output(&mut session, ":mode vm");
+    assert_eq!(output(&mut session, "a"), "5");
+    output(&mut session, ":reset");
+    assert_eq!(output(&mut session, ":globals"), "no globals defined");
+    assert!(matches!(session.handle(":quit"), Reply::Quit));
+}
+
+#[test]
+fn test_load_command() {
+    let path = std::env::temp_dir().join("monkey_test_load_command.monkey");
+    std::fs::write(&path, "let double = fn(x) {\n  x * 2\n};\ndouble(21)\n").unwrap();
+    let mut session = Session::new(ReplMode::CompilerMode);
+    assert_eq!(output(&mut session, &format!(":load {}", path.display())), "42");
+    assert_eq!(output(&mut session, "double(4)"), "8");
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(output(&mut session, ":load /no/such/file.monkey").starts_with("ERROR: could not read /no/such/file.monkey"));
+
+    // Errors in a loaded file are shown against its path.
+    let path = std::env::temp_dir().join("monkey_test_load_error.monkey");
+    std::fs::write(&path, "let x = 1;\nx + y\n").unwrap();
+    let reply = output(&mut session, &format!(":load {}", path.display()));
+    std::fs::remove_file(&path).unwrap();
+    assert!(reply.contains(&format!(" --> {}:2:5", path.display())), "{}", reply);
+}
+
+#[test]
+fn test_failed_entry_is_discarded() {
+    let mut session = Session::new(ReplMode::CompilerMode);
+    output(&mut session, "let a = 1;");
+    assert!(output(&mut session, "let b = 2; let c = a / 0;").starts_with("error[E0201]: division by zero"));
+    assert_eq!(output(&mut session, ":globals"), "a = 1");
+    assert!(output(&mut session, "c").starts_with("error[E0101]: undefined variable c"));
+    assert!(output(&mut session, "let d = 4; d + missing").starts_with("error[E0101]: undefined variable missing"));
+    assert!(output(&mut session, "d").starts_with("error[E0101]: undefined variable d"));
+    assert_eq!(output(&mut session, "let b = a + 1; b"), "2");
+    assert_eq!(output(&mut session, ":globals"), "a = 1\nb = 2");
+}
+
+#[test]
+fn test_errors_show_the_entry() {
+    let mut session = Session::new(ReplMode::CompilerMode);
+    assert_eq!(
+        output(&mut session, "let total = 1;\ntotl + 1"),
+        "error[E0101]: undefined variable totl\n --> <repl>:2:1\n  |\n2 | totl + 1\n  | ^^^^\n  = help: did you mean `total`?"
+    );
+    assert_eq!(
+        output(&mut session, "let x = (1;"),
+        "error[E0001]: expected `)`, found `;`\n --> <repl>:1:11\n  |\n1 | let x = (1;\n  |           ^\n  |         - opened here"
+    );
+
+    output(&mut session, ":mode eval");
+    assert_eq!(
+        output(&mut session, "let y = 2;\nlet z = y + true;"),
+        "error[E0201]: type mismatch: INTEGER PLUS BOOLEAN\n --> <repl>:2:1\n  |\n2 | let z = y + true;\n  | ^^^^^^^^^^^^^^^^^"
+    );
+}
+
+#[test]
+fn test_empty_entries() {
+    for mode in [ReplMode::CompilerMode, ReplMode::InterpreterMode] {
+        let mut session = Session::new(mode);
+        assert_eq!(output(&mut session, ";"), "");
+        assert_eq!(output(&mut session, ""), "");
+        assert_eq!(output(&mut session, "let a = 1;"), "");
+        assert_eq!(output(&mut session, ":type ;"), "NULL TYPE");
+        assert_eq!(output(&mut session, "a"), "1");
+    }
+}
@@ -0,0 +1,15 @@
+// Helpers for generating programs, shared by the tests and, through a path
+// module, by the benchmarks. Nothing here is part of the library.
+
+// A distinct identifier per number, since identifiers can't hold digits.
+pub fn identifier(number: usize) -> String {
+    let mut name = String::from("v");
+    let mut rest = number;
+    loop {
+        name.push((b'a' + (rest % 26) as u8) as char);
+        rest /= 26;
+        if rest == 0 {
+            return name;
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::builtins::BUILT_INS;
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::token::Position;
+use crate::types::{check, TypeCheck};
+
+fn check_source(source: &str) -> TypeCheck {
+    let mut parser = Parser::new(Lexer::new(source.to_string()));
+    let program = parser.parse_program();
+    assert!(parser.errors.is_empty());
+    check(&program)
+}
+
+fn types_of(source: &str) -> Vec<String> {
+    let checked = check_source(source);
+    assert_eq!(checked.errors, vec![], "{}", source);
+    checked.bindings.iter().map(|(name, ty)| format!("{}: {}", name, ty)).collect()
+}
+
+fn errors(source: &str) -> Vec<(String, usize)> {
+    check_source(source).errors.into_iter().map(|error| (error.message, error.span.start.line)).collect()
+}
+
+#[test]
+fn test_infers_types() {
+    let source = "let add = fn(a, b) { a + b };
+let total = add(1, 2);
+let greeting = add(\"hello \", \"world\");
+let id = fn(x) { x };
+let pair = [id(1), len(id(\"two\"))];
+let names = map([1, 2], fn(n) { upper(\"n\") });
+let ages = {\"ann\": 30, \"bob\": 25};
+let sum = reduce(values(ages), 0, fn(acc, age) { acc + age });
+let sign = fn(n) { if (n < 0) { return \"negative\"; } \"positive\" };
+let caught = try { error(\"oops\") } catch (err) { err };";
+    let expected = vec![
+        "add: fn(a, a) -> a",
+        "total: int",
+        "greeting: string",
+        "id: fn(a) -> a",
+        "pair: [int]",
+        "names: [string]",
+        "ages: {string: int}",
+        "sum: int",
+        "sign: fn(int) -> string",
+        "caught: string"
+    ];
+    assert_eq!(types_of(source), expected);
+}
+
+#[test]
+fn test_reports_type_errors() {
+    let source = "let a = 1 + true;
+let b = -\"x\";
+let c = len(5);
+let d = fn(x, y) { x * y }(2, \"three\");
+let e = upper(\"x\", \"y\");
+let f = [1, 2][\"first\"];
+let g = {[1]: 2};
+let h = 3(4);
+let i = \"a\" == \"b\";";
+    let expected = vec![
+        ("`+` can't be applied to int and bool".to_string(), 1),
+        ("`-` can't be applied to string".to_string(), 2),
+        ("argument 1 of `len` should be a string, array or hash, found int".to_string(), 3),
+        ("argument 2 of the function should be int, found string".to_string(), 4),
+        ("`upper` takes 1 argument but 2 were given".to_string(), 5),
+        ("an array index should be int, found string".to_string(), 6),
+        ("[int] can't be a hash key".to_string(), 7),
+        ("int can't be called".to_string(), 8),
+        ("`==` can only compare ints and bools, not string".to_string(), 9),
+    ];
+    assert_eq!(errors(source), expected);
+
+    let checked = check_source("let x = 1;\nputs(x);\nlet y = x + \"s\";");
+    assert_eq!(checked.errors[0].span.start, Position { line: 3, column: 1 });
+}
+
+#[test]
+fn test_dynamic_code_is_not_reported() {
+    // Mixed arrays and hashes, functions passed to themselves, values that
+    // may be null, and unknown names all fall back to any.
+    let source = "let mixed = [1, \"two\", fn(x) { x }];
+let person = {\"name\": \"ann\", \"age\": 30};
+let fib = fn(f, n) { if (n < 2) { return n; } f(f, n - 1) + f(f, n - 2) };
+let result = fib(fib, 10);
+let maybe = if (result > 5) { 1 };
+let name = person[\"name\"];
+let anything = fn(x) { x[0] };
+let p = push([1], \"a\");
+puts(mixed, maybe, len(name), anything([1]) + 1, anything({0: \"s\"}), p);";
+    assert_eq!(types_of(source), vec![
+        "mixed: [any]",
+        "person: {string: any}",
+        "fib: fn(any, int) -> int",
+        "result: int",
+        "maybe: any",
+        "name: any",
+        "anything: fn(a) -> any",
+        "p: [any]"
+    ]);
+}
+
+#[test]
+fn test_let_polymorphism() {
+    // Each use of a let-bound function gets fresh type variables.
+    let source = "let id = fn(x) { x };
+let n = id(1);
+let s = id(\"s\");
+let twice = fn(f, x) { f(f(x)) };
+let four = twice(fn(x) { x * 2 }, 1);
+let shout = twice(upper, \"hi\");
+let wrap = fn(x) { [x] };
+let nested = wrap(wrap(true));
+let compose = fn(f, g) { fn(x) { f(g(x)) } };
+let size = compose(fn(n) { n + 1 }, len);
+let inner = fn() { let local = fn(y) { y }; [local(1), len(local(\"s\"))] };";
+    assert_eq!(types_of(source), vec![
+        "id: fn(a) -> a",
+        "n: int",
+        "s: string",
+        "twice: fn(fn(a) -> a, a) -> a",
+        "four: int",
+        "shout: string",
+        "wrap: fn(a) -> [a]",
+        "nested: [[bool]]",
+        "compose: fn(fn(a) -> b, fn(c) -> a) -> fn(c) -> b",
+        "size: fn(a) -> int",
+        "inner: fn() -> [int]"
+    ]);
+
+    // A function parameter is not generalised inside the body.
+    assert_eq!(errors("let f = fn(g) { [g(1), g(\"s\")] };"), vec![
+        ("argument 1 of `g` should be int, found string".to_string(), 1)
+    ]);
+}
+
+#[test]
+fn test_hashes_and_indexing() {
+    let source = "let ages = {\"ann\": 30};
+let age = ages[\"ann\"];
+let flags = {1: true, 2: false};
+let flag = flags[1];
+let by_bool = {true: \"yes\"};
+let empty = {};
+let grid = [[1, 2], [3]];
+let cell = grid[0][1];
+let lookup = fn(h, k) { h[k] };
+let merged = merge(ages, {\"bob\": \"x\"});
+let pushed = push([1], 2);";
+    assert_eq!(types_of(source), vec![
+        "ages: {string: int}",
+        "age: int",
+        "flags: {int: bool}",
+        "flag: bool",
+        "by_bool: {bool: string}",
+        "empty: {a: b}",
+        "grid: [[int]]",
+        "cell: int",
+        "lookup: fn(a, b) -> any",
+        "merged: {string: any}",
+        "pushed: [int]"
+    ]);
+
+    let source = "let ages = {\"ann\": 30};
+let a = ages[1];
+let b = 5[0];
+let c = {fn(x) { x }: 1};
+let d = \"abc\"[0];
+let e = true[0];
+let f = ages[\"ann\"] + \"s\";";
+    assert_eq!(errors(source), vec![
+        ("this hash has string keys, found int".to_string(), 2),
+        ("int can't be indexed".to_string(), 3),
+        ("fn(a) -> a can't be a hash key".to_string(), 4),
+        ("string can't be indexed".to_string(), 5),
+        ("bool can't be indexed".to_string(), 6),
+        ("`+` can't be applied to int and string".to_string(), 7),
+    ]);
+}
+
+#[test]
+fn test_builtin_signatures() {
+    let signatures = [
+        ("len", "fn(a) -> int"),
+        ("first", "fn([a]) -> a"),
+        ("rest", "fn([a]) -> [a]"),
+        ("last", "fn([a]) -> a"),
+        ("push", "fn([a], b) -> any"),
+        ("puts", "any"),
+        ("keys", "fn({a: b}) -> [a]"),
+        ("values", "fn({a: b}) -> [b]"),
+        ("has", "fn({a: b}, a) -> bool"),
+        ("delete", "fn({a: b}, a) -> {a: b}"),
+        ("merge", "fn({a: b}, {c: d}) -> any"),
+        ("entries", "fn({a: b}) -> [[any]]"),
+        ("split", "fn(string, string) -> [string]"),
+        ("join", "fn([a], string) -> string"),
+        ("trim", "fn(string) -> string"),
+        ("upper", "fn(string) -> string"),
+        ("lower", "fn(string) -> string"),
+        ("contains", "fn(string, string) -> bool"),
+        ("starts_with", "fn(string, string) -> bool"),
+        ("ends_with", "fn(string, string) -> bool"),
+        ("replace", "fn(string, string, string) -> string"),
+        ("substr", "fn(string, int, int) -> string"),
+        ("chars", "fn(string) -> [string]"),
+        ("index_of", "fn(string, string) -> int"),
+        ("map", "fn([a], fn(a) -> b) -> [b]"),
+        ("filter", "fn([a], fn(a) -> b) -> [a]"),
+        ("reduce", "fn([a], b, fn(b, a) -> b) -> b"),
+        ("sort_by", "fn([a], fn(a, a) -> int) -> [a]"),
+        ("find", "fn([a], fn(a) -> b) -> a"),
+        ("error", "fn(a) -> b"),
+    ];
+    let names: Vec<&str> = signatures.iter().map(|(name, _)| *name).collect();
+    let registered: Vec<&str> = BUILT_INS.iter().map(|(name, _, _)| *name).collect();
+    assert_eq!(names, registered);
+
+    let source: String = signatures.iter().map(|(name, _)| format!("let {}_type = {};\n", name, name)).collect();
+    let expected: Vec<String> = signatures.iter().map(|(name, ty)| format!("{}_type: {}", name, ty)).collect();
+    assert_eq!(types_of(&source), expected);
+
+    // push and merge mix types the way literals do.
+    assert_eq!(types_of("let a = push([1], 2);\nlet b = push([1], \"s\");\nlet c = merge({1: 2}, {3: 4});"), vec![
+        "a: [int]",
+        "b: [any]",
+        "c: {int: int}"
+    ]);
+    assert_eq!(types_of("let d = merge({1: 2}, {\"k\": true});"), vec!["d: {any: any}"]);
+}
@@ -1,11 +1,17 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
 use std::vec;
 use crate::ast::{Node, Program};
+use crate::code::Opcode;
 use crate::compiler::Compiler;
 use crate::lexer::Lexer;
 use crate::object::Object;
 use crate::parser::Parser;
-use crate::vm::Vm;
+use crate::budget::{Budget, LimitExceeded};
+use crate::test_support::identifier;
+use crate::vm::{ErrorKind, FrameInfo, HookAction, RuntimeError, Vm, VmConfig, VmHook};
 
 struct VmTestCase
 {
@@ -20,20 +26,27 @@ fn parse(input: String) -> Program
 }
 
 
+// Every case runs twice, the second time with the optimizer on, which must
+// not change the result.
 fn run_vm_tests(tests: Vec<VmTestCase>) {
     for test in tests
     {
-        let program = parse(test.input.clone());
-
-        let mut compiler = Compiler::new();
-        compiler.compile(Node::Program(program));
+        for optimize in [false, true]
+        {
+            let program = parse(test.input.clone());
 
-        let mut vm = Vm::new(compiler.get_bytecode());
-        vm.run();
+            let mut compiler = Compiler::new();
+            compiler.optimize = optimize;
+            compiler.compile(Node::Program(program)).expect("Couldn't compile program");
 
-        let stack_element = vm.last_popped_stack_element();
+            let mut vm = Vm::new(compiler.get_bytecode(), VmConfig::default());
+            let stack_element = match vm.run() {
+                Ok(()) => vm.last_popped_stack_element(),
+                Err(error) => Object::Error(error.message)
+            };
 
-        assert_eq!(test.expected, stack_element, "{}", test.input);
+            assert_eq!(test.expected, stack_element, "{} (optimize: {})", test.input, optimize);
+        }
     }
 }
 
@@ -164,6 +177,19 @@ fn test_conditionals() {
       VmTestCase {
           input: "if (false) { 10 }".to_string(),
           expected: Object::Null
+      },
+      // The optimizer drops the jumps of an empty if, but not the check of its condition.
+      VmTestCase {
+          input: "if (\"a\") {}".to_string(),
+          expected: Object::Error("type not supported: STRING".to_string())
+      },
+      VmTestCase {
+          input: "let f = fn(x) { if (x) {} else {} }; f([1])".to_string(),
+          expected: Object::Error("type not supported: ARRAY".to_string())
+      },
+      VmTestCase {
+          input: "let f = fn(x) { if (x == 1) {}; x }; f(1)".to_string(),
+          expected: Object::IntegerObject(1)
       }
     ];
 
@@ -207,14 +233,14 @@ fn test_array_expr() {
     let tests = vec![
         VmTestCase{
             input: "[]".to_string(),
-            expected:Object::Array(vec![])
+            expected:Object::Array(vec![].into())
         },
         VmTestCase{
             input: "[1, 2, 3];".to_string(),
             expected:Object::Array(vec![
-                Box::new(Object::IntegerObject(1)),
-                Box::new(Object::IntegerObject(2)),
-                Box::new(Object::IntegerObject(3))])
+                Object::IntegerObject(1),
+                Object::IntegerObject(2),
+                Object::IntegerObject(3)].into())
         }
     ];
 
@@ -342,6 +368,146 @@ fn test_local_bindings() {
     run_vm_tests(tests);
 }
 
+#[test]
+fn test_tail_calls() {
+    let tests = vec![
+        VmTestCase {
+            input: "let count = fn(f, n, acc) { if (n == 0) { acc } else { f(f, n - 1, acc + n) } };
+                    count(count, 5000, 0)".to_string(),
+            expected: Object::IntegerObject(12502500)
+        },
+        VmTestCase {
+            input: "let build = fn(f, n, acc) { if (n == 0) { return acc; } f(f, n - 1, push(acc, n)) };
+                    let sum = fn(f, arr, acc) { if (len(arr) == 0) { acc } else { f(f, rest(arr), acc + first(arr)) } };
+                    sum(sum, build(build, 1100, []), 0)".to_string(),
+            expected: Object::IntegerObject(605550)
+        },
+        VmTestCase {
+            input: "let even = fn(odd, even, n) { if (n == 0) { true } else { odd(even, odd, n - 1) } };
+                    let odd = fn(even, odd, n) { if (n == 0) { false } else { even(odd, even, n - 1) } };
+                    even(odd, even, 5001)".to_string(),
+            expected: Object::BooleanObject(false)
+        },
+        // The try block still catches what the call raises.
+        VmTestCase {
+            input: "let fail = fn() { error(\"boom\") };
+                    let safe = fn() { try { return fail(); } catch (e) { \"caught \" + e } };
+                    safe()".to_string(),
+            expected: Object::StringObject("caught boom".to_string())
+        },
+        VmTestCase {
+            input: "let size = fn(arr) { len(arr) }; size([1, 2, 3])".to_string(),
+            expected: Object::IntegerObject(3)
+        },
+    ];
+
+    run_vm_tests(tests);
+
+    // A loop of 100,000 iterations fits in a handful of frames.
+    let input = "let count = fn(f, n, acc) { if (n == 0) { acc } else { f(f, n - 1, acc + n) } };
+                 count(count, 100000, 0)";
+    let result = run_with_config(input, VmConfig { max_frames: 4, ..VmConfig::default() });
+    assert_eq!(result.unwrap(), Object::IntegerObject(5000050000));
+
+    // So do walks over a list of 100,000 elements built with push, since rest
+    // and push don't copy it.
+    let input = "let build = fn(f, n, list) { if (n == 0) { list } else { f(f, n - 1, push(list, n)) } };
+                 let sum = fn(f, list, acc) { if (len(list) == 0) { acc } else { f(f, rest(list), acc + first(list)) } };
+                 sum(sum, build(build, 100000, []), 0)";
+    let result = run_with_config(input, VmConfig { max_frames: 4, ..VmConfig::default() });
+    assert_eq!(result.unwrap(), Object::IntegerObject(5000050000));
+
+    let input = "let inner = fn(x) { x };
+                 let outer = fn() { inner() };
+                 outer();";
+    let error = run_with_config(input, VmConfig::default()).unwrap_err();
+    assert_eq!(error.message, "wrong number of arguments: want=1, got=0");
+    assert_eq!(error.call_stack, vec!["outer (2:37)".to_string(), "<main> (3:18)".to_string()]);
+}
+
+// The optimizer's superinstructions must behave like the code they replace,
+// errors included.
+#[test]
+fn test_superinstructions() {
+    let tests = vec![
+        VmTestCase {
+            input: "let f = fn(n) { if (n > 0) { n - 1 } else { n + 2 } }; f(5) + f(0)".to_string(),
+            expected: Object::IntegerObject(6)
+        },
+        VmTestCase {
+            input: "let f = fn(x) { if (x == true) { 1 } else { 2 } }; f(false)".to_string(),
+            expected: Object::IntegerObject(2)
+        },
+        VmTestCase {
+            input: "let f = fn(s) { s + \"!\" }; f(\"hi\")".to_string(),
+            expected: Object::StringObject("hi!".to_string())
+        },
+        VmTestCase {
+            input: "let f = fn(s) { s - 1 }; f(\"hi\")".to_string(),
+            expected: Object::Error("operators not supported: STRING OpSub INTEGER".to_string())
+        },
+        VmTestCase {
+            input: "let f = fn(x) { if (x > true) { 1 } }; f(1)".to_string(),
+            expected: Object::Error("Comparison operands not supported".to_string())
+        },
+        VmTestCase {
+            input: "let f = fn(n) { n + 1 }; f(9223372036854775807)".to_string(),
+            expected: Object::Error("integer overflow".to_string())
+        },
+    ];
+
+    run_vm_tests(tests);
+}
+
+// Operands past u8 and u16 are encoded behind an OpWide prefix.
+#[test]
+fn test_wide_operands() {
+    let locals: Vec<String> = (0..300).map(|i| format!("let {} = {};", identifier(i), i)).collect();
+    let params: Vec<String> = (0..300).map(identifier).collect();
+    let args: Vec<String> = (0..300).map(|i| i.to_string()).collect();
+    // The large code is never run, which would be slow, only jumped over.
+    let constants: Vec<String> = (0..70000).map(|i| format!("{};", i)).collect();
+    let long = "1;".repeat(25000);
+    let tests = vec![
+        VmTestCase {
+            input: format!("let f = fn() {{ {} {} + {}; }}; f()", locals.join(" "), identifier(0), identifier(299)),
+            expected: Object::IntegerObject(299)
+        },
+        VmTestCase {
+            input: format!("let f = fn({}) {{ {} + {}; }}; f({})", params.join(", "), identifier(1), identifier(299), args.join(", ")),
+            expected: Object::IntegerObject(300)
+        },
+        VmTestCase {
+            input: format!("let f = fn() {{ {} }}; let g = fn() {{ 70000 + 1; }}; g()", constants.join(" ")),
+            expected: Object::IntegerObject(70001)
+        },
+        VmTestCase {
+            input: format!("let x = false; if (x) {{ {} 2; }} else {{ 3; }}", long),
+            expected: Object::IntegerObject(3)
+        },
+        VmTestCase {
+            input: format!("let x = true; if (x) {{ 2; }} else {{ {} 3; }}", long),
+            expected: Object::IntegerObject(2)
+        },
+        VmTestCase {
+            input: format!("try {{ error(\"boom\"); {} 4; }} catch (e) {{ e }}", long),
+            expected: Object::StringObject("boom".to_string())
+        },
+        VmTestCase {
+            input: format!("try {{ 4; }} catch (e) {{ {} 5; }}", long),
+            expected: Object::IntegerObject(4)
+        },
+    ];
+
+    let mut compiler = Compiler::new();
+    compiler.compile(Node::Program(parse(tests[3].input.clone()))).expect("Couldn't compile program");
+    let listing = compiler.get_bytecode().instructions.to_string();
+    assert!(listing.contains("OpWide OpJumpNotTrue"), "{}", listing);
+    assert!(listing.contains("OpWide OpJump "), "{}", listing);
+
+    run_vm_tests(tests);
+}
+
 #[test]
 fn test_calling_functions_with_args()
 {
@@ -420,9 +586,632 @@ fn test_built_in_fn()
         VmTestCase
         {
             input: "push([], 1);".to_string(),
-            expected: Object::Array(vec![Box::new(Object::IntegerObject(1))])
+            expected: Object::Array(vec![Object::IntegerObject(1)].into())
+        },
+        VmTestCase
+        {
+            input: "first([])".to_string(),
+            expected: Object::Null
+        },
+        // rest and push share elements, which must not show through.
+        VmTestCase
+        {
+            input: "let a = [1]; let b = push(a, 2); let c = push(a, 3); [a, b, c, push(rest(b), 4), b]".to_string(),
+            expected: Object::from(vec![
+                Object::from(vec![1]),
+                Object::from(vec![1, 2]),
+                Object::from(vec![1, 3]),
+                Object::from(vec![2, 4]),
+                Object::from(vec![1, 2])])
+        },
+        VmTestCase
+        {
+            input: "let a = [1]; let b = push(a, a); push(a, 2); b".to_string(),
+            expected: Object::from(vec![Object::from(1), Object::from(vec![1])])
+        }
+    ];
+
+    run_vm_tests(test);
+}
+#[test]
+fn test_hash_built_in_fn()
+{
+    let test = vec![
+        VmTestCase
+        {
+            input: "len({1: 2, 3: 4})".to_string(),
+            expected: Object::IntegerObject(2)
+        },
+        VmTestCase
+        {
+            input: "keys({\"b\": 2, \"a\": 1})".to_string(),
+            expected: Object::Array(vec![
+                Object::StringObject("a".to_string()),
+                Object::StringObject("b".to_string())].into())
+        },
+        VmTestCase
+        {
+            input: "values({3: 30, 1: 10, 2: 20})".to_string(),
+            expected: Object::Array(vec![
+                Object::IntegerObject(10),
+                Object::IntegerObject(20),
+                Object::IntegerObject(30)].into())
+        },
+        VmTestCase
+        {
+            input: "entries({1: true})".to_string(),
+            expected: Object::Array(vec![
+                Object::Array(vec![
+                    Object::IntegerObject(1),
+                    Object::BooleanObject(true)].into())].into())
+        },
+        VmTestCase
+        {
+            input: "has({1: 2}, 1)".to_string(),
+            expected: Object::BooleanObject(true)
+        },
+        VmTestCase
+        {
+            input: "has({1: 2}, 2)".to_string(),
+            expected: Object::BooleanObject(false)
+        },
+        VmTestCase
+        {
+            input: "let a = {1: 2, 3: 4}; delete(a, 1); a".to_string(),
+            expected: Object::HashMap(HashMap::from([(Object::IntegerObject(1), Object::IntegerObject(2)), (Object::IntegerObject(3), Object::IntegerObject(4))]))
+        },
+        VmTestCase
+        {
+            input: "delete({1: 2, 3: 4}, 1)".to_string(),
+            expected: Object::HashMap(HashMap::from([(Object::IntegerObject(3), Object::IntegerObject(4))]))
+        },
+        VmTestCase
+        {
+            input: "merge({1: 2, 3: 4}, {3: 5, 6: 7})".to_string(),
+            expected: Object::HashMap(HashMap::from([
+                (Object::IntegerObject(1), Object::IntegerObject(2)),
+                (Object::IntegerObject(3), Object::IntegerObject(5)),
+                (Object::IntegerObject(6), Object::IntegerObject(7))]))
+        },
+        VmTestCase
+        {
+            input: "has({}, [])".to_string(),
+            expected: Object::Error("type ARRAY is not hashable".to_string())
+        },
+        VmTestCase
+        {
+            input: "keys([1])".to_string(),
+            expected: Object::Error("not suported type: ARRAY in position 0, expected HASH MAP".to_string())
+        },
+        VmTestCase
+        {
+            input: "merge({}, 1)".to_string(),
+            expected: Object::Error("not suported type: INTEGER in position 1, expected HASH MAP".to_string())
         }
     ];
 
     run_vm_tests(test);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_string_built_in_fn()
+{
+    let test = vec![
+        VmTestCase
+        {
+            input: "len(\"héllo\")".to_string(),
+            expected: Object::IntegerObject(5)
+        },
+        VmTestCase
+        {
+            input: "split(\"a,b,c\", \",\")".to_string(),
+            expected: Object::Array(vec![
+                Object::StringObject("a".to_string()),
+                Object::StringObject("b".to_string()),
+                Object::StringObject("c".to_string())].into())
+        },
+        VmTestCase
+        {
+            input: "join([\"a\", \"b\", \"c\"], \"-\")".to_string(),
+            expected: Object::StringObject("a-b-c".to_string())
+        },
+        VmTestCase
+        {
+            input: "trim(\"  monkey \")".to_string(),
+            expected: Object::StringObject("monkey".to_string())
+        },
+        VmTestCase
+        {
+            input: "upper(\"straße\")".to_string(),
+            expected: Object::StringObject("STRASSE".to_string())
+        },
+        VmTestCase
+        {
+            input: "lower(\"ÀB\")".to_string(),
+            expected: Object::StringObject("àb".to_string())
+        },
+        VmTestCase
+        {
+            input: "contains(\"monkey\", \"key\")".to_string(),
+            expected: Object::BooleanObject(true)
+        },
+        VmTestCase
+        {
+            input: "starts_with(\"monkey\", \"key\")".to_string(),
+            expected: Object::BooleanObject(false)
+        },
+        VmTestCase
+        {
+            input: "ends_with(\"monkey\", \"key\")".to_string(),
+            expected: Object::BooleanObject(true)
+        },
+        VmTestCase
+        {
+            input: "replace(\"a-b-c\", \"-\", \"+\")".to_string(),
+            expected: Object::StringObject("a+b+c".to_string())
+        },
+        VmTestCase
+        {
+            input: "substr(\"ñandú\", 1, 3)".to_string(),
+            expected: Object::StringObject("and".to_string())
+        },
+        VmTestCase
+        {
+            input: "substr(\"ñandú\", 3)".to_string(),
+            expected: Object::StringObject("dú".to_string())
+        },
+        VmTestCase
+        {
+            input: "chars(\"añ\")".to_string(),
+            expected: Object::Array(vec![
+                Object::StringObject("a".to_string()),
+                Object::StringObject("ñ".to_string())].into())
+        },
+        VmTestCase
+        {
+            input: "index_of(\"ñandú\", \"dú\")".to_string(),
+            expected: Object::IntegerObject(3)
+        },
+        VmTestCase
+        {
+            input: "index_of(\"monkey\", \"z\")".to_string(),
+            expected: Object::IntegerObject(-1)
+        },
+        VmTestCase
+        {
+            input: "upper(1)".to_string(),
+            expected: Object::Error("not suported type: INTEGER in position 0, expected STRING".to_string())
+        }
+    ];
+
+    run_vm_tests(test);
+}
+
+#[test]
+fn test_higher_order_built_in_fn()
+{
+    let test = vec![
+        VmTestCase
+        {
+            input: "map([1, 2, 3], fn(x) { x * 2 })".to_string(),
+            expected: Object::Array(vec![
+                Object::IntegerObject(2),
+                Object::IntegerObject(4),
+                Object::IntegerObject(6)].into())
+        },
+        VmTestCase
+        {
+            input: "map([\"a\", \"bc\"], len)".to_string(),
+            expected: Object::Array(vec![
+                Object::IntegerObject(1),
+                Object::IntegerObject(2)].into())
+        },
+        VmTestCase
+        {
+            input: "filter([1, 2, 3, 4], fn(x) { x > 2 })".to_string(),
+            expected: Object::Array(vec![
+                Object::IntegerObject(3),
+                Object::IntegerObject(4)].into())
+        },
+        VmTestCase
+        {
+            input: "let add = fn(acc, x) { acc + x }; reduce([1, 2, 3, 4], 0, add)".to_string(),
+            expected: Object::IntegerObject(10)
+        },
+        VmTestCase
+        {
+            input: "sort_by([3, 1, 2, 1], fn(a, b) { a - b })".to_string(),
+            expected: Object::Array(vec![
+                Object::IntegerObject(1),
+                Object::IntegerObject(1),
+                Object::IntegerObject(2),
+                Object::IntegerObject(3)].into())
+        },
+        VmTestCase
+        {
+            input: "find([1, 2, 3], fn(x) { x > 1 })".to_string(),
+            expected: Object::IntegerObject(2)
+        },
+        VmTestCase
+        {
+            input: "find([1, 2, 3], fn(x) { x > 5 })".to_string(),
+            expected: Object::Null
+        },
+        VmTestCase
+        {
+            input: "let double = fn(arr) { map(arr, fn(x) { x * 2 }) };
+                    let nested = map([[1], [2, 3]], double);
+                    len(nested) + first(last(nested))".to_string(),
+            expected: Object::IntegerObject(6)
+        },
+        VmTestCase
+        {
+            input: "map([1], fn(a, b) { a })".to_string(),
+            expected: Object::Error("wrong number of arguments: want=2, got=1".to_string())
+        },
+        VmTestCase
+        {
+            input: "sort_by([1, 2], fn(a, b) { true })".to_string(),
+            expected: Object::Error("sort_by comparator must return INTEGER, got BOOLEAN".to_string())
+        }
+    ];
+
+    run_vm_tests(test);
+}
+
+#[test]
+fn test_try_catch()
+{
+    let test = vec![
+        VmTestCase
+        {
+            input: "try { 1 } catch (e) { 2 }".to_string(),
+            expected: Object::IntegerObject(1)
+        },
+        VmTestCase
+        {
+            input: "try { error(\"boom\"); 1 } catch (e) { e }".to_string(),
+            expected: Object::StringObject("boom".to_string())
+        },
+        VmTestCase
+        {
+            input: "try { 1 / 0 } catch (e) { e }".to_string(),
+            expected: Object::StringObject("division by zero".to_string())
+        },
+        VmTestCase
+        {
+            input: "try { len(1) } catch (e) { \"caught: \" + e }".to_string(),
+            expected: Object::StringObject("caught: not suported type: INTEGER".to_string())
+        },
+        VmTestCase
+        {
+            input: "let fail = fn(x) { if (x > 2) { error(\"too deep\") } else { 1 } };
+                    let outer = fn(x) { let y = x + 1; fail(y) + 1 };
+                    try { outer(2) } catch (e) { e }".to_string(),
+            expected: Object::StringObject("too deep".to_string())
+        },
+        VmTestCase
+        {
+            input: "let safe = fn() { try { 1 + true } catch (e) { return 5; } };
+                    safe() + safe()".to_string(),
+            expected: Object::IntegerObject(10)
+        },
+        VmTestCase
+        {
+            input: "let early = fn() { try { return 1; } catch (e) { 2 } };
+                    early();
+                    try { error(\"after return\") } catch (e) { e }".to_string(),
+            expected: Object::StringObject("after return".to_string())
+        },
+        VmTestCase
+        {
+            input: "try { try { error(\"inner\") } catch (e) { error(e + \" outer\") } } catch (e) { e }".to_string(),
+            expected: Object::StringObject("inner outer".to_string())
+        },
+        VmTestCase
+        {
+            input: "try { map([1, 0], fn(x) { 1 / x }) } catch (e) { e }".to_string(),
+            expected: Object::StringObject("division by zero".to_string())
+        },
+        VmTestCase
+        {
+            input: "map([1, 0], fn(x) { try { 1 / x } catch (e) { -1 } })".to_string(),
+            expected: Object::Array(vec![
+                Object::IntegerObject(1),
+                Object::IntegerObject(-1)].into())
+        },
+        VmTestCase
+        {
+            input: "[1, 2, try { 3 } catch (e) { 4 }]".to_string(),
+            expected: Object::Array(vec![
+                Object::IntegerObject(1),
+                Object::IntegerObject(2),
+                Object::IntegerObject(3)].into())
+        },
+        VmTestCase
+        {
+            input: "error(\"uncaught\")".to_string(),
+            expected: Object::Error("uncaught".to_string())
+        }
+    ];
+
+    run_vm_tests(test);
+}
+
+fn run_with_config(input: &str, config: VmConfig) -> Result<Object, RuntimeError>
+{
+    let program = parse(input.to_string());
+
+    let mut compiler = Compiler::new();
+    compiler.compile(Node::Program(program)).expect("Couldn't compile program");
+
+    let mut vm = Vm::new(compiler.get_bytecode(), config);
+    vm.run()?;
+    Ok(vm.last_popped_stack_element())
+}
+
+#[test]
+fn test_vm_limits()
+{
+    let input = "let a = fn() { 1 };
+                 let b = fn() { a() + 1 };
+                 let c = fn() { b() + 1 };
+                 c();";
+
+    let config = VmConfig { max_frames: 4, ..VmConfig::default() };
+    assert_eq!(run_with_config(input, config), Ok(Object::IntegerObject(3)));
+
+    let config = VmConfig { max_frames: 3, ..VmConfig::default() };
+    let error = run_with_config(input, config).unwrap_err();
+    assert_eq!(error.message, "maximum call depth exceeded: the limit is 3 frames");
+    assert_eq!(error.call_stack, vec![
+        "b (2:33)".to_string(),
+        "c (3:33)".to_string(),
+        "<main> (4:18)".to_string()
+    ]);
+
+    let config = VmConfig { stack_size: 3, ..VmConfig::default() };
+    assert_eq!(run_with_config("[1, 2, 3]", config.clone()), Ok(Object::Array(vec![
+        Object::IntegerObject(1),
+        Object::IntegerObject(2),
+        Object::IntegerObject(3)].into())));
+    assert_eq!(run_with_config("[1, 2, 3, 4]", config).unwrap_err().message, "stack overflow: the stack size is 3");
+
+    let config = VmConfig { max_globals: 2, ..VmConfig::default() };
+    assert_eq!(run_with_config("let a = 1; let b = 2; a + b", config.clone()), Ok(Object::IntegerObject(3)));
+    assert_eq!(run_with_config("let a = 1; let b = 2; let c = 3;", config).unwrap_err().message, "too many globals: the limit is 2");
+
+    let config = VmConfig { max_frames: 3, ..VmConfig::default() };
+    assert_eq!(run_with_config(&format!("try {{ {} }} catch (e) {{ e }}", input), config),
+               Ok(Object::StringObject("maximum call depth exceeded: the limit is 3 frames".to_string())));
+}
+
+#[test]
+fn test_vm_budget()
+{
+    let count = "let count = fn(g, n) { if (n == 0) { 0 } else { g(g, n - 1) + 1 } };";
+    let steps = |max_steps| VmConfig { budget: Budget { max_steps: Some(max_steps), ..Budget::default() }, ..VmConfig::default() };
+
+    assert_eq!(run_with_config(&format!("{} count(count, 10)", count), steps(1000)), Ok(Object::IntegerObject(10)));
+
+    let error = run_with_config(&format!("{} count(count, 500)", count), steps(1000)).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Limit(LimitExceeded::Steps(1000)));
+    assert_eq!(error.message, "step limit exceeded: the limit is 1000 steps");
+    assert!(!error.call_stack.is_empty());
+
+    let inputs = vec![
+        format!("{} try {{ count(count, 500) }} catch (e) {{ 0 }}", count),
+        format!("{} map([1, 2], fn(x) {{ try {{ count(count, 500) }} catch (e) {{ 0 }} }})", count),
+        format!("{} first(map([1, 2], fn(x) {{ count(count, 500) }}))", count),
+    ];
+    for input in inputs {
+        assert_eq!(run_with_config(&input, steps(1000)).unwrap_err().kind, ErrorKind::Limit(LimitExceeded::Steps(1000)), "{}", input);
+    }
+
+    let config = VmConfig { budget: Budget { timeout: Some(Duration::ZERO), ..Budget::default() }, ..VmConfig::default() };
+    let error = run_with_config(&format!("{} count(count, 500)", count), config).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Limit(LimitExceeded::Time(Duration::ZERO)));
+
+    let program = parse(format!("{} let done = 1; let result = count(count, 500);", count));
+    let mut compiler = Compiler::new();
+    compiler.compile(Node::Program(program)).expect("Couldn't compile program");
+    let mut vm = Vm::new(compiler.get_bytecode(), steps(1000));
+    assert!(vm.run().is_err());
+    assert_eq!(vm.steps(), 1001);
+    assert_eq!(vm.globals.len(), 2);
+    assert_eq!(vm.globals[1], Some(Object::IntegerObject(1)));
+}
+
+#[test]
+fn test_vm_memory_limit()
+{
+    let double = "let double = fn(g, s, n) { if (n == 0) { s } else { g(g, s + s, n - 1) } };";
+    let grow = "let grow = fn(g, a, n) { if (n == 0) { a } else { g(g, push(a, n), n - 1) } };";
+    let memory = |max_memory| VmConfig { budget: Budget { max_memory: Some(max_memory), ..Budget::default() }, ..VmConfig::default() };
+    let out_of_memory = ErrorKind::Limit(LimitExceeded::Memory(100_000));
+
+    assert_eq!(run_with_config(&format!("{} len(double(double, \"ab\", 4))", double), memory(100_000)), Ok(Object::IntegerObject(32)));
+    assert_eq!(run_with_config(&format!("{} len(grow(grow, [], 10))", grow), memory(100_000)), Ok(Object::IntegerObject(10)));
+
+    let error = run_with_config(&format!("{} len(double(double, \"ab\", 40))", double), memory(100_000)).unwrap_err();
+    assert_eq!(error.kind, out_of_memory);
+    assert_eq!(error.message, "out of memory: the limit is 100000 bytes");
+
+    let inputs = vec![
+        format!("{} len(grow(grow, [], 5000))", grow),
+        format!("{} try {{ double(double, \"ab\", 40) }} catch (e) {{ 0 }}", double),
+        format!("{} let big = double(double, \"a\", 9); len(replace(big, \"a\", big))", double),
+        format!("{} let big = double(double, \"a\", 14); [big, big, big, big, big, big, big, big, big, big]", double),
+    ];
+    for input in inputs {
+        assert_eq!(run_with_config(&input, memory(100_000)).unwrap_err().kind, out_of_memory, "{}", input);
+    }
+}
+
+#[test]
+fn test_unset_global()
+{
+    let mut compiler = Compiler::new();
+    compiler.symbol_table.define("a".to_string());
+    compiler.compile(Node::Program(parse("a".to_string()))).expect("Couldn't compile program");
+
+    let mut vm = Vm::new(compiler.get_bytecode(), VmConfig::default());
+    assert_eq!(vm.run().unwrap_err().message, "global 0 used before it was set");
+}
+
+#[test]
+fn test_error_positions()
+{
+    let input = "let check = fn(x) {
+    if (x > 2) {
+        x / 0
+    } else {
+        x
+    }
+};
+let total = if (true) {
+    1
+} else {
+    2
+} + check(3);";
+    let error = run_with_config(input, VmConfig::default()).unwrap_err();
+    assert_eq!(error.message, "division by zero");
+    assert_eq!(error.call_stack, vec!["check (3:9)".to_string(), "<main> (8:1)".to_string()]);
+
+    // The optimizer drops the else branch and the jumps but keeps the source
+    // map in step, so the trace is the same without it.
+    let mut compiler = Compiler::new();
+    compiler.optimize = false;
+    compiler.compile(Node::Program(parse(input.to_string()))).unwrap();
+    let mut vm = Vm::new(compiler.get_bytecode(), VmConfig::default());
+    assert_eq!(vm.run().unwrap_err().call_stack, error.call_stack);
+
+    let input = "let items = [1, 2];
+map(items, fn(x) {
+    x + \"a\"
+});";
+    let error = run_with_config(input, VmConfig::default()).unwrap_err();
+    assert_eq!(error.call_stack, vec!["<fn> (3:5)".to_string(), "<main> (2:1)".to_string()]);
+}
+
+#[test]
+fn test_profile()
+{
+    let input = "let double = fn(x) { x * 2 };
+let twice = fn(f, x) { f(f(x)) };
+twice(double, 1) + twice(double, 2);";
+    let program = parse(input.to_string());
+    let mut compiler = Compiler::new();
+    compiler.compile(Node::Program(program)).expect("Couldn't compile program");
+
+    let mut vm = Vm::new(compiler.get_bytecode(), VmConfig { profile: true, ..VmConfig::default() });
+    vm.run().unwrap();
+    let profile = vm.take_profile().expect("profiling was enabled");
+    assert!(vm.take_profile().is_none());
+
+    assert_eq!(profile.instructions(), vm.steps());
+    let opcodes = profile.opcodes();
+    assert!(opcodes.contains(&(Opcode::OpCall, 4)), "{:?}", opcodes);
+    assert!(opcodes.contains(&(Opcode::OpTailCall, 2)), "{:?}", opcodes);
+    assert!(opcodes.contains(&(Opcode::OpMul, 4)), "{:?}", opcodes);
+    assert_eq!(opcodes.iter().map(|(_, count)| count).sum::<u64>(), profile.instructions());
+
+    let calls: Vec<(String, u64)> = profile.functions().into_iter().map(|function| (function.name, function.calls)).collect();
+    for expected in [("<main>".to_string(), 1), ("double (1:22)".to_string(), 4), ("twice (2:24)".to_string(), 2)] {
+        assert!(calls.contains(&expected), "{:?}", calls);
+    }
+
+    let hottest = &profile.hot_instructions(1)[0];
+    assert_eq!(hottest.count, 4);
+    assert!(hottest.function.starts_with("double"), "{:?}", hottest);
+
+    let folded = profile.folded();
+    let stacks: Vec<&str> = folded.lines().map(|line| line.rsplit_once(' ').unwrap().0).collect();
+    // The outer call in twice is a tail call, so double replaces twice.
+    assert_eq!(stacks, vec!["<main>", "<main>;double (1:22)", "<main>;twice (2:24)", "<main>;twice (2:24);double (1:22)"]);
+    assert!(profile.to_string().contains("OpCall"));
+}
+
+#[test]
+fn test_profile_callbacks()
+{
+    // map calls back into the VM once per element without the number of
+    // frames going back to the caller's in between.
+    let input = "let inc = fn(x) { x + 1 };
+map([1, 2, 3, 4, 5], inc);";
+    let program = parse(input.to_string());
+    let mut compiler = Compiler::new();
+    compiler.compile(Node::Program(program)).expect("Couldn't compile program");
+
+    let mut vm = Vm::new(compiler.get_bytecode(), VmConfig { profile: true, ..VmConfig::default() });
+    vm.run().unwrap();
+    let profile = vm.take_profile().expect("profiling was enabled");
+    let inc = profile.functions().into_iter().find(|function| function.name.starts_with("inc")).unwrap();
+    assert_eq!(inc.calls, 5);
+    assert_eq!(inc.instructions % 5, 0);
+}
+
+#[test]
+fn test_profile_wide_instructions()
+{
+    let locals: Vec<String> = (0..300).map(|i| format!("let {} = {};", identifier(i), i)).collect();
+    let input = format!("let f = fn() {{ {} {}; }}; f()", locals.join(" "), identifier(299));
+    let program = parse(input);
+    let mut compiler = Compiler::new();
+    compiler.compile(Node::Program(program)).expect("Couldn't compile program");
+
+    let mut vm = Vm::new(compiler.get_bytecode(), VmConfig { profile: true, ..VmConfig::default() });
+    vm.run().unwrap();
+    let opcodes = vm.take_profile().expect("profiling was enabled").opcodes();
+    // Locals past 255 are set and read behind an OpWide prefix.
+    assert!(opcodes.contains(&(Opcode::OpSetLocal, 300)), "{:?}", opcodes);
+    assert!(opcodes.contains(&(Opcode::OpGetLocal, 1)), "{:?}", opcodes);
+    assert!(opcodes.iter().all(|(opcode, _)| *opcode != Opcode::OpWide), "{:?}", opcodes);
+}
+
+// Keeps the frames the VM had when it first entered a function.
+struct FirstCall(Rc<RefCell<Vec<FrameInfo>>>);
+
+impl VmHook for FirstCall {
+    fn before_instruction(&mut self, vm: &Vm) -> HookAction {
+        if vm.depth() == 2 && self.0.borrow().is_empty() {
+            *self.0.borrow_mut() = vm.frames();
+        }
+        HookAction::Continue
+    }
+}
+
+#[test]
+fn test_frames_at_wide_call()
+{
+    let params: Vec<String> = (0..300).map(identifier).collect();
+    let args: Vec<String> = (0..300).map(|i| i.to_string()).collect();
+    let input = format!("let f = fn({}) {{\n{} / 0 }};\nf({})", params.join(", "), identifier(0), args.join(", "));
+    let program = parse(input);
+    let mut compiler = Compiler::new();
+    compiler.compile(Node::Program(program)).expect("Couldn't compile program");
+
+    let frames = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = Vm::new(compiler.get_bytecode(), VmConfig::default());
+    vm.set_hook(Box::new(FirstCall(frames.clone())));
+    let error = vm.run().unwrap_err();
+    // The caller is shown at the start of its call, OpWide prefix included.
+    let caller = &frames.borrow()[1];
+    assert_eq!(caller.instruction, Some("OpWide OpCall 300".to_string()));
+    assert_eq!(caller.span.unwrap().start.line, 3);
+    assert_eq!(error.call_stack, vec!["f (2:1)".to_string(), "<main> (3:1)".to_string()]);
+}
+
+#[test]
+fn test_runtime_error_display()
+{
+    let mut error = RuntimeError::new("boom".to_string());
+    error.call_stack = vec!["<fn> (offset 3)".to_string(), "<main> (offset 7)".to_string()];
+    assert_eq!(error.to_string(), "boom\n    at <fn> (offset 3)\n    at <main> (offset 7)");
+
+    error.call_stack = (0..25).map(|i| format!("frame {}", i)).collect();
+    let lines = error.to_string().lines().map(|line| line.to_string()).collect::<Vec<String>>();
+    assert_eq!(lines.len(), 22);
+    assert_eq!(lines[11], "    ... 5 more frames");
+    assert_eq!(lines[21], "    at frame 24");
+}
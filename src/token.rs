@@ -1,5 +1,40 @@
 use phf::phf_map;
+use std::fmt::{Display, Formatter};
 use strum_macros::IntoStaticStr;
+
+// A place in the source, counting lines and columns from 1. Columns count
+// characters, not bytes.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Default, Hash)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+// The source covered by a token or a node, end exclusive.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default, Hash)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position
+}
+
+impl Span {
+    pub fn new(start: Position, end: Position) -> Self {
+        Span {
+            start,
+            end
+        }
+    }
+
+    pub fn to(&self, other: Span) -> Span {
+        Span::new(self.start, other.end)
+    }
+}
 #[derive(PartialEq, Debug, Clone, IntoStaticStr, Hash)]
 pub enum Token{
     EMPTY,
@@ -39,7 +74,9 @@ pub enum Token{
     FALSE,
     IF,
     ELSE,
-    RETURN
+    RETURN,
+    TRY,
+    CATCH
 }
 
   
@@ -50,7 +87,9 @@ static KEYWORDS: phf::Map<&'static str, Token> = phf_map!{
     "false" => Token::FALSE,
     "if" => Token::IF,
     "else" => Token::ELSE,
-    "return" => Token::RETURN
+    "return" => Token::RETURN,
+    "try" => Token::TRY,
+    "catch" => Token::CATCH
 };
 
 impl Token {
@@ -95,7 +134,9 @@ impl Token {
             Token::FALSE => "false".to_string(),
             Token::IF => "if".to_string(),
             Token::ELSE => "else".to_string(),
-            Token::RETURN => "return".to_string()
+            Token::RETURN => "return".to_string(),
+            Token::TRY => "try".to_string(),
+            Token::CATCH => "catch".to_string()
         }
     }
 
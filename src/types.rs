@@ -0,0 +1,607 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use crate::ast::{Expression, FnStruct, Program, Statement};
+use crate::builtins::{arity, describe_arity, BUILT_INS};
+use crate::diagnostic::{Diagnostic, TYPE_ERROR};
+use crate::token::{Span, Token};
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum Type {
+    Integer,
+    Boolean,
+    String,
+    Array(Box<Type>),
+    Hash(Box<Type>, Box<Type>),
+    Function(Vec<Type>, Box<Type>),
+    // A type not known yet, found by unification.
+    Var(usize),
+    // A value whose type can't be pinned down, such as an element of an array
+    // mixing types. It agrees with every type, so code using it is not checked.
+    Any
+}
+
+impl Type {
+    fn array(element: &Type) -> Type {
+        Type::Array(Box::new(element.clone()))
+    }
+
+    fn function(params: Vec<Type>, result: Type) -> Type {
+        Type::Function(params, Box::new(result))
+    }
+
+    // Writes the type with its variables named a, b, ... in the order they
+    // appear in names, which can be shared to show several types together.
+    fn show(&self, names: &mut Vec<usize>) -> String {
+        match self {
+            Type::Integer => "int".to_string(),
+            Type::Boolean => "bool".to_string(),
+            Type::String => "string".to_string(),
+            Type::Array(element) => format!("[{}]", element.show(names)),
+            Type::Hash(key, value) => format!("{{{}: {}}}", key.show(names), value.show(names)),
+            Type::Function(params, result) => {
+                let params: Vec<String> = params.iter().map(|param| param.show(names)).collect();
+                format!("fn({}) -> {}", params.join(", "), result.show(names))
+            }
+            Type::Var(var) => {
+                let index = names.iter().position(|name| name == var).unwrap_or_else(|| {
+                    names.push(*var);
+                    names.len() - 1
+                });
+                match index {
+                    0..=25 => ((b'a' + index as u8) as char).to_string(),
+                    _ => format!("t{}", index)
+                }
+            }
+            Type::Any => "any".to_string()
+        }
+    }
+
+    fn occurs(&self, var: usize) -> bool {
+        match self {
+            Type::Var(other) => *other == var,
+            Type::Array(element) => element.occurs(var),
+            Type::Hash(key, value) => key.occurs(var) || value.occurs(var),
+            Type::Function(params, result) => params.iter().any(|param| param.occurs(var)) || result.occurs(var),
+            _ => false
+        }
+    }
+
+    fn free_vars(&self, vars: &mut Vec<usize>) {
+        match self {
+            Type::Var(var) if !vars.contains(var) => vars.push(*var),
+            Type::Array(element) => element.free_vars(vars),
+            Type::Hash(key, value) => {
+                key.free_vars(vars);
+                value.free_vars(vars);
+            }
+            Type::Function(params, result) => {
+                for param in params {
+                    param.free_vars(vars);
+                }
+                result.free_vars(vars);
+            }
+            _ => {}
+        }
+    }
+
+    fn substitute(&self, replacements: &HashMap<usize, Type>) -> Type {
+        match self {
+            Type::Var(var) => replacements.get(var).cloned().unwrap_or(Type::Var(*var)),
+            Type::Array(element) => Type::Array(Box::new(element.substitute(replacements))),
+            Type::Hash(key, value) => Type::Hash(Box::new(key.substitute(replacements)), Box::new(value.substitute(replacements))),
+            Type::Function(params, result) => Type::Function(
+                params.iter().map(|param| param.substitute(replacements)).collect(),
+                Box::new(result.substitute(replacements))
+            ),
+            _ => self.clone()
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.show(&mut Vec::new()))
+    }
+}
+
+// A type generalised over vars, which each use of the name replaces with
+// fresh variables. This is what lets `let id = fn(x) { x }` take any type.
+#[derive(Clone, Debug)]
+struct Scheme {
+    vars: Vec<usize>,
+    ty: Type
+}
+
+impl Scheme {
+    fn mono(ty: Type) -> Self {
+        Scheme { vars: Vec::new(), ty }
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct TypeError {
+    pub message: String,
+    // The statement the error was found in.
+    pub span: Span
+}
+
+impl Display for TypeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.span.start, self.message)
+    }
+}
+
+impl From<&TypeError> for Diagnostic {
+    fn from(error: &TypeError) -> Self {
+        Diagnostic::error(TYPE_ERROR, &error.message).with_span(Some(error.span))
+    }
+}
+
+pub struct TypeCheck {
+    pub errors: Vec<TypeError>,
+    // The type of every top level let, in order.
+    pub bindings: Vec<(String, Type)>
+}
+
+// Infers the type of every expression in a program that parsed and compiled,
+// Hindley-Milner style, and reports operations that can only fail at runtime.
+// Checking is optional and conservative: where Monkey code relies on being
+// dynamic, as with arrays mixing types or functions passed to themselves, the
+// type becomes Any and nothing is reported.
+pub fn check(program: &Program) -> TypeCheck {
+    let mut checker = Checker::new();
+    checker.block(&program.statements);
+    let bindings = checker.bindings.iter().map(|(name, ty)| (name.clone(), checker.resolve(ty))).collect();
+    TypeCheck { errors: checker.errors, bindings }
+}
+
+struct Checker {
+    // What each type variable has been unified with.
+    substitution: Vec<Option<Type>>,
+    // The names in scope: the builtins, the program, then one frame for each
+    // function being checked.
+    frames: Vec<HashMap<String, Scheme>>,
+    // For each function being checked, the types its return statements agree on.
+    returns: Vec<Option<Type>>,
+    span: Span,
+    errors: Vec<TypeError>,
+    bindings: Vec<(String, Type)>
+}
+
+impl Checker {
+    fn new() -> Self {
+        let mut checker = Checker {
+            substitution: Vec::new(),
+            frames: vec![HashMap::new()],
+            returns: Vec::new(),
+            span: Span::default(),
+            errors: Vec::new(),
+            bindings: Vec::new()
+        };
+        for (name, _, _) in BUILT_INS {
+            let ty = checker.builtin(name).unwrap_or(Type::Any);
+            let scheme = checker.generalize(&ty);
+            checker.frames[0].insert(name.to_string(), scheme);
+        }
+        checker.frames.push(HashMap::new());
+        checker
+    }
+
+    // The signature of a builtin, or None for puts, which takes anything.
+    fn builtin(&mut self, name: &str) -> Option<Type> {
+        let (a, b) = (self.fresh(), self.fresh());
+        let hash = Type::Hash(Box::new(a.clone()), Box::new(b.clone()));
+        let string = || Type::String;
+        let ty = match name {
+            "len" => Type::function(vec![a], Type::Integer),
+            "first" | "last" => Type::function(vec![Type::array(&a)], a),
+            "rest" => Type::function(vec![Type::array(&a)], Type::array(&a)),
+            // The results of push and merge are worked out by builtin_result.
+            "push" => Type::function(vec![Type::array(&a), b], Type::Any),
+            "keys" => Type::function(vec![hash], Type::array(&a)),
+            "values" => Type::function(vec![hash], Type::array(&b)),
+            // Each entry is a [key, value] pair.
+            "entries" => Type::function(vec![hash], Type::array(&Type::array(&Type::Any))),
+            "has" => Type::function(vec![hash, a], Type::Boolean),
+            "delete" => Type::function(vec![hash.clone(), a], hash),
+            "merge" => {
+                let other = Type::Hash(Box::new(self.fresh()), Box::new(self.fresh()));
+                Type::function(vec![hash, other], Type::Any)
+            }
+            "split" => Type::function(vec![string(), string()], Type::array(&string())),
+            "join" => Type::function(vec![Type::array(&a), string()], string()),
+            "trim" | "upper" | "lower" => Type::function(vec![string()], string()),
+            "contains" | "starts_with" | "ends_with" => Type::function(vec![string(), string()], Type::Boolean),
+            "replace" => Type::function(vec![string(), string(), string()], string()),
+            "substr" => Type::function(vec![string(), Type::Integer, Type::Integer], string()),
+            "chars" => Type::function(vec![string()], Type::array(&string())),
+            "index_of" => Type::function(vec![string(), string()], Type::Integer),
+            "map" => Type::function(vec![Type::array(&a), Type::function(vec![a], b.clone())], Type::array(&b)),
+            "filter" => Type::function(vec![Type::array(&a), Type::function(vec![a.clone()], b)], Type::array(&a)),
+            "find" => Type::function(vec![Type::array(&a), Type::function(vec![a.clone()], b)], a),
+            "reduce" => Type::function(
+                vec![Type::array(&a), b.clone(), Type::function(vec![b.clone(), a], b.clone())],
+                b
+            ),
+            "sort_by" => Type::function(
+                vec![Type::array(&a), Type::function(vec![a.clone(), a.clone()], Type::Integer)],
+                Type::array(&a)
+            ),
+            // error never returns, so its result fits anywhere.
+            "error" => Type::function(vec![a], b),
+            _ => return None
+        };
+        Some(ty)
+    }
+
+    fn fresh(&mut self) -> Type {
+        self.substitution.push(None);
+        Type::Var(self.substitution.len() - 1)
+    }
+
+    // The type with every bound variable replaced by what it is bound to.
+    fn resolve(&self, ty: &Type) -> Type {
+        match ty {
+            Type::Var(var) => match &self.substitution[*var] {
+                Some(bound) => self.resolve(bound),
+                None => ty.clone()
+            },
+            _ => {
+                let mut vars = Vec::new();
+                ty.free_vars(&mut vars);
+                let replacements = vars.into_iter()
+                    .filter(|var| self.substitution[*var].is_some())
+                    .map(|var| (var, self.resolve(&Type::Var(var))))
+                    .collect();
+                ty.substitute(&replacements)
+            }
+        }
+    }
+
+    // Makes the two types equal, binding variables as needed. If they can't
+    // be, nothing is bound.
+    fn unify(&mut self, a: &Type, b: &Type) -> bool {
+        let saved = self.substitution.clone();
+        let unified = self.unify_parts(a, b);
+        if !unified {
+            self.substitution = saved;
+        }
+        unified
+    }
+
+    fn unify_parts(&mut self, a: &Type, b: &Type) -> bool {
+        match (self.resolve(a), self.resolve(b)) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Var(a), Type::Var(b)) if a == b => true,
+            (Type::Var(var), other) | (other, Type::Var(var)) => {
+                // A type containing itself, as for a function passed to
+                // itself, can't be written down, so it is left unchecked.
+                let bound = if other.occurs(var) { Type::Any } else { other };
+                self.substitution[var] = Some(bound);
+                true
+            }
+            (Type::Array(a), Type::Array(b)) => self.unify_parts(&a, &b),
+            (Type::Hash(a_key, a_value), Type::Hash(b_key, b_value)) => {
+                self.unify_parts(&a_key, &b_key) && self.unify_parts(&a_value, &b_value)
+            }
+            (Type::Function(a_params, a_result), Type::Function(b_params, b_result)) => {
+                a_params.len() == b_params.len()
+                    && a_params.iter().zip(&b_params).all(|(a, b)| self.unify_parts(a, b))
+                    && self.unify_parts(&a_result, &b_result)
+            }
+            (a, b) => a == b
+        }
+    }
+
+    // The type two values that can reach the same place have in common, or
+    // Any when they have none.
+    fn join(&mut self, a: &Type, b: &Type) -> Type {
+        if !self.unify(a, b) {
+            return Type::Any;
+        }
+        self.resolve(a)
+    }
+
+    fn generalize(&self, ty: &Type) -> Scheme {
+        let ty = self.resolve(ty);
+        let mut in_scope = Vec::new();
+        for scheme in self.frames.iter().flat_map(|frame| frame.values()) {
+            let mut vars = Vec::new();
+            self.resolve(&scheme.ty).free_vars(&mut vars);
+            in_scope.extend(vars.into_iter().filter(|var| !scheme.vars.contains(var)));
+        }
+        let mut vars = Vec::new();
+        ty.free_vars(&mut vars);
+        vars.retain(|var| !in_scope.contains(var));
+        Scheme { vars, ty }
+    }
+
+    fn instantiate(&mut self, scheme: &Scheme) -> Type {
+        let replacements = scheme.vars.iter().map(|var| (*var, self.fresh())).collect();
+        scheme.ty.substitute(&replacements)
+    }
+
+    // Types shown together, so a variable has the same name in each.
+    fn show(&self, types: &[&Type]) -> Vec<String> {
+        let mut names = Vec::new();
+        types.iter().map(|ty| self.resolve(ty).show(&mut names)).collect()
+    }
+
+    fn error(&mut self, message: String) {
+        self.errors.push(TypeError { message, span: self.span });
+    }
+
+    fn define(&mut self, name: &str, scheme: Scheme) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.insert(name.to_string(), scheme);
+        }
+    }
+
+    // The scheme of a name, and whether it is a builtin.
+    fn lookup(&self, name: &str) -> Option<(Scheme, bool)> {
+        self.frames.iter()
+            .enumerate()
+            .rev()
+            .find_map(|(depth, frame)| frame.get(name).map(|scheme| (scheme.clone(), depth == 0)))
+    }
+
+    // The type of the block's value: that of its last statement.
+    fn block(&mut self, block: &[Statement]) -> Type {
+        let mut value = Type::Any;
+        for stmt in block {
+            value = self.statement(stmt);
+        }
+        value
+    }
+
+    fn statement(&mut self, stmt: &Statement) -> Type {
+        let outer = std::mem::replace(&mut self.span, stmt.span());
+        let ty = match stmt {
+            Statement::LetStatement(id, expr, _) => {
+                let ty = self.expression(expr);
+                let scheme = self.generalize(&ty);
+                if self.frames.len() == 2 {
+                    self.bindings.push((id.id.clone(), scheme.ty.clone()));
+                }
+                self.define(&id.id, scheme);
+                Type::Any
+            }
+            Statement::ReturnStatement(expr, _) => {
+                let ty = self.expression(expr);
+                if let Some(index) = self.returns.len().checked_sub(1) {
+                    let returned = match self.returns[index].take() {
+                        Some(returned) => self.join(&returned, &ty),
+                        None => ty.clone()
+                    };
+                    self.returns[index] = Some(returned);
+                }
+                ty
+            }
+            Statement::ExpressionStatement(expr, _) => self.expression(expr)
+        };
+        self.span = outer;
+        ty
+    }
+
+    fn expression(&mut self, expr: &Expression) -> Type {
+        match expr {
+            Expression::IntegerExpression(_) => Type::Integer,
+            Expression::BoolExpression(_) => Type::Boolean,
+            Expression::StringExpression(_) => Type::String,
+            // Undefined names are reported by the compiler.
+            Expression::IdentifierExpression(id) => match self.lookup(&id.id) {
+                Some((scheme, _)) => self.instantiate(&scheme),
+                None => Type::Any
+            },
+            Expression::PrefixExpression(operator, right) => {
+                let right = self.expression(right);
+                if *operator != Token::MINUS {
+                    return Type::Boolean;
+                }
+                if !self.unify(&right, &Type::Integer) {
+                    let right = self.show(&[&right]).remove(0);
+                    self.error(format!("`-` can't be applied to {}", right));
+                }
+                Type::Integer
+            }
+            Expression::InfixExpression(left, operator, right) => {
+                let left = self.expression(left);
+                let right = self.expression(right);
+                self.infix(operator, &left, &right)
+            }
+            Expression::IfExpression(content) => {
+                self.expression(&content.condition);
+                let consequence = self.block(&content.consequence);
+                match &content.alternative {
+                    Some(alternative) => {
+                        let alternative = self.block(alternative);
+                        self.join(&consequence, &alternative)
+                    }
+                    // Null when the condition is false.
+                    None => Type::Any
+                }
+            }
+            Expression::FnExpression(content) => self.function(content),
+            Expression::CallExpression(content) => {
+                let callee = self.expression(&content.function);
+                let args: Vec<Type> = content.args.iter().map(|arg| self.expression(arg)).collect();
+                let name = match content.function.as_ref() {
+                    Expression::IdentifierExpression(id) => Some(id.id.as_str()),
+                    _ => None
+                };
+                self.call(name, &callee, &args)
+            }
+            Expression::ArrayLiteral(content) => {
+                let mut element = self.fresh();
+                for expr in &content.elements {
+                    let ty = self.expression(expr);
+                    element = self.join(&element, &ty);
+                }
+                Type::array(&element)
+            }
+            Expression::IndexExpression(content) => {
+                let left = self.expression(&content.left);
+                let index = self.expression(&content.index);
+                self.index(&left, &index)
+            }
+            Expression::HashExpression(content) => {
+                let (mut key, mut value) = (self.fresh(), self.fresh());
+                for (key_expr, value_expr) in &content.pairs {
+                    let key_type = self.expression(key_expr);
+                    self.hashable(&key_type);
+                    let value_type = self.expression(value_expr);
+                    key = self.join(&key, &key_type);
+                    value = self.join(&value, &value_type);
+                }
+                Type::Hash(Box::new(key), Box::new(value))
+            }
+            Expression::TryExpression(content) => {
+                let body = self.block(&content.body);
+                self.define(&content.error.id, Scheme::mono(Type::String));
+                let handler = self.block(&content.handler);
+                self.join(&body, &handler)
+            }
+            Expression::Error(_) => Type::Any
+        }
+    }
+
+    fn infix(&mut self, operator: &Token, left: &Type, right: &Type) -> Type {
+        let symbol = operator.inspect();
+        let (left, right) = (self.resolve(left), self.resolve(right));
+        let (operands, result) = match operator {
+            Token::PLUS => {
+                let addable = |ty: &Type| matches!(ty, Type::Integer | Type::String | Type::Var(_) | Type::Any);
+                if addable(&left) && addable(&right) && self.unify(&left, &right) {
+                    return self.join(&left, &right);
+                }
+                (None, Type::Any)
+            }
+            Token::MINUS | Token::ASTERISK | Token::SLASH => (Some(Type::Integer), Type::Integer),
+            Token::LT | Token::GT => (Some(Type::Integer), Type::Boolean),
+            Token::EQ | Token::NotEq => {
+                if !self.unify(&left, &right) {
+                    let shown = self.show(&[&left, &right]);
+                    self.error(format!("`{}` can't compare {} with {}", symbol, shown[0], shown[1]));
+                }
+                else if matches!(self.resolve(&left), Type::String | Type::Array(_) | Type::Hash(..) | Type::Function(..)) {
+                    let shown = self.show(&[&left]);
+                    self.error(format!("`{}` can only compare ints and bools, not {}", symbol, shown[0]));
+                }
+                return Type::Boolean;
+            }
+            _ => return Type::Any
+        };
+        let unified = operands.is_some_and(|operand| self.unify(&left, &operand) && self.unify(&right, &operand));
+        if !unified {
+            let shown = self.show(&[&left, &right]);
+            self.error(format!("`{}` can't be applied to {} and {}", symbol, shown[0], shown[1]));
+        }
+        result
+    }
+
+    fn function(&mut self, function: &FnStruct) -> Type {
+        let params: Vec<Type> = function.params.iter().map(|_| self.fresh()).collect();
+        let frame = function.params.iter()
+            .zip(&params)
+            .map(|(param, ty)| (param.id.clone(), Scheme::mono(ty.clone())))
+            .collect();
+        self.frames.push(frame);
+        self.returns.push(None);
+        let value = self.block(&function.body);
+        self.frames.pop();
+        let result = match self.returns.pop().flatten() {
+            Some(returned) => self.join(&returned, &value),
+            None => value
+        };
+        Type::function(params, result)
+    }
+
+    fn call(&mut self, name: Option<&str>, callee: &Type, args: &[Type]) -> Type {
+        let builtin = name.and_then(|name| self.lookup(name)).is_some_and(|(_, builtin)| builtin);
+        let called = name.map_or("the function".to_string(), |name| format!("`{}`", name));
+        match self.resolve(callee) {
+            Type::Function(params, result) => {
+                let (least, most) = name.and_then(arity)
+                    .filter(|_| builtin)
+                    .unwrap_or((params.len(), params.len()));
+                if args.len() < least || args.len() > most {
+                    let were = if args.len() == 1 { "was" } else { "were" };
+                    self.error(format!("{} takes {} but {} {} given", called, describe_arity(least, most), args.len(), were));
+                    return Type::Any;
+                }
+                for (index, (param, arg)) in params.iter().zip(args).enumerate() {
+                    if !self.unify(param, arg) {
+                        let shown = self.show(&[param, arg]);
+                        self.error(format!("argument {} of {} should be {}, found {}", index + 1, called, shown[0], shown[1]));
+                        return Type::Any;
+                    }
+                }
+                match name.filter(|_| builtin) {
+                    Some(name) => self.builtin_result(name, args).unwrap_or_else(|| self.resolve(&result)),
+                    None => self.resolve(&result)
+                }
+            }
+            Type::Var(_) => {
+                let result = self.fresh();
+                self.unify(callee, &Type::function(args.to_vec(), result.clone()));
+                result
+            }
+            Type::Any => Type::Any,
+            other => {
+                self.error(format!("{} can't be called", other));
+                Type::Any
+            }
+        }
+    }
+
+    // What the signatures of a few builtins can't say: len only takes some
+    // types, and push and merge can mix types the way literals can, so their
+    // results are joined like the elements of a literal.
+    fn builtin_result(&mut self, name: &str, args: &[Type]) -> Option<Type> {
+        let args: Vec<Type> = args.iter().map(|arg| self.resolve(arg)).collect();
+        match (name, args.as_slice()) {
+            ("len", [ty @ (Type::Integer | Type::Boolean | Type::Function(..))]) => {
+                self.error(format!("argument 1 of `len` should be a string, array or hash, found {}", ty));
+                None
+            }
+            ("push", [Type::Array(element), pushed]) => Some(Type::array(&self.join(element, pushed))),
+            ("merge", [Type::Hash(key, value), Type::Hash(other_key, other_value)]) => {
+                let key = self.join(key, other_key);
+                Some(Type::Hash(Box::new(key), Box::new(self.join(value, other_value))))
+            }
+            _ => None
+        }
+    }
+
+    fn index(&mut self, left: &Type, index: &Type) -> Type {
+        match self.resolve(left) {
+            Type::Array(element) => {
+                if !self.unify(index, &Type::Integer) {
+                    let shown = self.show(&[index]);
+                    self.error(format!("an array index should be int, found {}", shown[0]));
+                }
+                *element
+            }
+            Type::Hash(key, value) => {
+                self.hashable(index);
+                if !self.unify(index, &key) {
+                    let shown = self.show(&[&key, index]);
+                    self.error(format!("this hash has {} keys, found {}", shown[0], shown[1]));
+                }
+                *value
+            }
+            // Could be an array or a hash.
+            Type::Var(_) | Type::Any => Type::Any,
+            other => {
+                self.error(format!("{} can't be indexed", other));
+                Type::Any
+            }
+        }
+    }
+
+    fn hashable(&mut self, key: &Type) {
+        if let ty @ (Type::Array(_) | Type::Hash(..) | Type::Function(..)) = self.resolve(key) {
+            self.error(format!("{} can't be a hash key", ty));
+        }
+    }
+}
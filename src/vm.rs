@@ -1,26 +1,55 @@
-use crate::builtins::{get_built_in, BUILT_INS};
+use crate::budget::{Budget, LimitExceeded, Meter};
+use crate::builtins::Builtins;
 use crate::code::Opcode::{OpAdd, OpDiv, OpMul, OpSub};
-use crate::code::{look_up, Instructions, Opcode};
+use crate::code::{look_up, Opcode, MAX_INSTRUCTION_WIDTH};
 use crate::compiler::ByteCode;
-use crate::main;
-use crate::object::Object::{BooleanObject, CompiledFunction, IntegerObject};
-use crate::object::{ClosureStruct, CompiledFunctionStruct, Object};
+use crate::diagnostic::{Diagnostic, INTERRUPTED, LIMIT_EXCEEDED, RUNTIME_ERROR};
+use crate::object::Object::{BooleanObject, IntegerObject};
+use crate::object::{ClosureStruct, CompiledFunctionStruct, FunctionCaller, Object};
+use crate::profiler::Profile;
+use crate::token::Span;
 use byteorder::{BigEndian, ReadBytesExt};
 use num_traits::FromPrimitive;
-use core::panic;
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::io::Cursor;
-use std::os::linux::raw::stat;
-use std::os::unix::raw::off_t;
+use std::rc::Rc;
 
 const STACK_SIZE: usize = 2048;
+const MAX_FRAMES: usize = 1024;
 const GLOBAL_SIZE: usize = 65536;
+const SHOWN_CALL_STACK_FRAMES: usize = 10;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct VmConfig {
+    pub stack_size: usize,
+    pub max_frames: usize,
+    pub max_globals: usize,
+    pub budget: Budget,
+    // Collect a Profile of the run, at some cost in speed.
+    pub profile: bool,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            stack_size: STACK_SIZE,
+            max_frames: MAX_FRAMES,
+            max_globals: GLOBAL_SIZE,
+            budget: Budget::default(),
+            profile: false,
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Clone)]
 struct Frame {
     f: ClosureStruct,
     ip: i64,
     base_pointer: usize,
+    // Where the last call instruction run by this frame starts, which is the
+    // call it is waiting on while it isn't the innermost frame.
+    call: usize,
 }
 
 impl Frame {
@@ -29,16 +58,147 @@ impl Frame {
             f,
             ip: -1,
             base_pointer,
+            call: 0,
+        }
+    }
+
+    fn name(&self) -> String {
+        self.f.function.name.clone().unwrap_or_else(|| "<fn>".to_string())
+    }
+}
+
+// A snapshot of one call frame, for hooks and other tools.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FrameInfo {
+    pub name: String,
+    // The instruction the frame is at: the next one to run for the innermost
+    // frame, the call it is waiting on for the others.
+    pub offset: usize,
+    pub instruction: Option<String>,
+    pub span: Option<Span>,
+    pub locals: Vec<(String, Object)>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HookAction {
+    Continue,
+    // Abandon the run with an Interrupted error.
+    Stop,
+}
+
+// Called before every instruction the VM runs, including the ones run on
+// behalf of builtins, with the VM paused on that instruction. This is the
+// extension point for debuggers, profilers and tracers.
+pub trait VmHook {
+    fn before_instruction(&mut self, vm: &Vm) -> HookAction;
+}
+
+// Registered by OpTry and consumed when an error is raised inside the try block.
+#[derive(Debug, PartialEq, Clone)]
+struct Handler {
+    catch_pos: usize,
+    sp: usize,
+    frames: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ErrorKind {
+    // Raised by the program or a builtin; can be caught with try/catch.
+    Fault,
+    // The run used up its budget; try/catch can't stop it.
+    Limit(LimitExceeded),
+    // A hook asked the VM to stop.
+    Interrupted,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RuntimeError {
+    pub message: String,
+    pub kind: ErrorKind,
+    // Innermost frame first; only filled in once the error leaves the VM.
+    pub call_stack: Vec<String>,
+    // The statement the innermost frame was running, filled in with call_stack.
+    pub span: Option<Span>,
+}
+
+impl RuntimeError {
+    pub fn new(message: String) -> Self {
+        RuntimeError {
+            message,
+            kind: ErrorKind::Fault,
+            call_stack: Vec::new(),
+            span: None,
+        }
+    }
+
+    pub fn is_catchable(&self) -> bool {
+        self.kind == ErrorKind::Fault
+    }
+}
+
+impl From<LimitExceeded> for RuntimeError {
+    fn from(limit: LimitExceeded) -> Self {
+        RuntimeError {
+            message: limit.to_string(),
+            kind: ErrorKind::Limit(limit),
+            call_stack: Vec::new(),
+            span: None,
+        }
+    }
+}
+
+impl RuntimeError {
+    // The call stack as shown to users, with the middle of a deep one left out.
+    pub fn shown_call_stack(&self) -> Vec<String> {
+        let len = self.call_stack.len();
+        let mut lines = Vec::new();
+        for (i, frame) in self.call_stack.iter().enumerate() {
+            if len > 2 * SHOWN_CALL_STACK_FRAMES && i == SHOWN_CALL_STACK_FRAMES {
+                lines.push(format!("... {} more frames", len - 2 * SHOWN_CALL_STACK_FRAMES));
+            }
+            if len > 2 * SHOWN_CALL_STACK_FRAMES
+                && i >= SHOWN_CALL_STACK_FRAMES
+                && i < len - SHOWN_CALL_STACK_FRAMES
+            {
+                continue;
+            }
+            lines.push(format!("at {}", frame));
         }
+        lines
     }
+}
 
-    pub fn get_instructions(&self) -> Instructions {
-        self.f.function.instructions.clone()
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        for line in self.shown_call_stack() {
+            write!(f, "\n    {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+// The call stack becomes notes, unless the error happened in the main program.
+impl From<&RuntimeError> for Diagnostic {
+    fn from(error: &RuntimeError) -> Self {
+        let code = match error.kind {
+            ErrorKind::Fault => RUNTIME_ERROR,
+            ErrorKind::Limit(_) => LIMIT_EXCEEDED,
+            ErrorKind::Interrupted => INTERRUPTED,
+        };
+        let mut diagnostic = Diagnostic::error(code, &error.message).with_span(error.span);
+        if error.call_stack.len() > 1 {
+            for line in error.shown_call_stack() {
+                diagnostic = diagnostic.with_note(&line);
+            }
+        }
+        diagnostic
     }
 }
 
 pub struct Vm {
     constants: Vec<Object>,
+    builtins: Builtins,
 
     stack: Vec<Object>,
     sp: usize,
@@ -46,37 +206,138 @@ pub struct Vm {
     pub globals: Vec<Option<Object>>,
 
     frames: Vec<Frame>,
-    frames_index: usize,
+    handlers: Vec<Handler>,
+
+    config: VmConfig,
+    meter: Meter,
+    hook: Option<Box<dyn VmHook>>,
+    // Like an exceeded budget, a stop request sticks, so a builtin that drops
+    // the error can't keep the run going.
+    interrupted: bool,
+    // The error that ended the last callback run for a builtin, so its call
+    // stack can be kept if the builtin passes the error on.
+    callback_error: Option<RuntimeError>,
+    profile: Option<Profile>,
 }
 
 impl Vm {
-    pub fn new(bytecode: ByteCode) -> Self {
+    pub fn new(bytecode: ByteCode, config: VmConfig) -> Self {
         let main_frame = Frame::new(
             ClosureStruct {
-                function: CompiledFunctionStruct {
+                function: Rc::new(CompiledFunctionStruct {
                     instructions: bytecode.instructions,
-                    num_vars: 0,
-                    num_args: 0,
-                },
+                    name: Some("<main>".to_string()),
+                    source_map: bytecode.source_map,
+                    ..Default::default()
+                }),
                 free_vars: vec![],
             },
             0,
         );
         Vm {
             constants: bytecode.constants,
+            builtins: bytecode.builtins,
             stack: Vec::new(),
             sp: 0,
             globals: Vec::new(),
             frames: vec![main_frame],
-            frames_index: 0,
+            handlers: Vec::new(),
+            meter: Meter::new(&config.budget),
+            profile: config.profile.then(Profile::new),
+            config,
+            hook: None,
+            interrupted: false,
+            callback_error: None,
         }
     }
 
-    pub fn new_with_state(bytecode: ByteCode, globals: Vec<Option<Object>>) -> Self {
-        let mut vm = Vm::new(bytecode);
+    pub fn new_with_state(
+        bytecode: ByteCode,
+        globals: Vec<Option<Object>>,
+        config: VmConfig,
+    ) -> Self {
+        let mut vm = Vm::new(bytecode, config);
         vm.globals = globals;
         vm
     }
+
+    // Number of instructions executed so far, including ones run for builtins.
+    pub fn steps(&self) -> u64 {
+        self.meter.steps()
+    }
+
+    // Approximate bytes of strings, arrays and hashes created so far.
+    pub fn allocated(&self) -> usize {
+        self.meter.allocated()
+    }
+
+    // The profile collected so far, if VmConfig::profile was set. Taking it
+    // ends profiling for this VM.
+    pub fn take_profile(&mut self) -> Option<Profile> {
+        let mut profile = self.profile.take()?;
+        profile.finish();
+        Some(profile)
+    }
+
+    pub fn set_hook(&mut self, hook: Box<dyn VmHook>) {
+        self.hook = Some(hook);
+    }
+
+    pub fn remove_hook(&mut self) -> Option<Box<dyn VmHook>> {
+        self.hook.take()
+    }
+
+    // The live part of the operand stack, bottom first.
+    pub fn stack(&self) -> &[Object] {
+        &self.stack[..self.sp]
+    }
+
+    // Every active frame, innermost first.
+    pub fn frames(&self) -> Vec<FrameInfo> {
+        let innermost = self.frames.len() - 1;
+        self.frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, frame)| {
+                let offset = if i == innermost { (frame.ip + 1) as usize } else { frame.call };
+                let function = &frame.f.function;
+                let locals = if i == 0 {
+                    Vec::new()
+                } else {
+                    (0..function.num_vars)
+                        .map(|slot| {
+                            let name = function.local_names.get(slot).cloned().unwrap_or_else(|| format!("local {}", slot));
+                            let value = self.stack.get(frame.base_pointer + slot).cloned().unwrap_or(Object::Null);
+                            (name, value)
+                        })
+                        .collect()
+                };
+                FrameInfo {
+                    name: frame.name(),
+                    offset,
+                    instruction: function.instructions.instruction_at(offset).map(|(text, _)| text),
+                    span: function.source_map.lookup(offset),
+                    locals,
+                }
+            })
+            .collect()
+    }
+
+    // Cheaper than frames() for hooks that only need to know where the VM is.
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn current_function(&self) -> &CompiledFunctionStruct {
+        &self.frames[self.frames.len() - 1].f.function
+    }
+
+    // The offset of the next instruction to run in the current function.
+    pub fn current_offset(&self) -> usize {
+        (self.frames[self.frames.len() - 1].ip + 1) as usize
+    }
+
     pub fn get_stack_top(&self) -> Option<Object> {
         if self.sp == 0 {
             None
@@ -85,38 +346,54 @@ impl Vm {
         }
     }
 
-    pub fn handle_infix_expression(&mut self, operator: Opcode) {
+    pub fn handle_infix_expression(&mut self, operator: Opcode) -> Result<(), RuntimeError> {
         let second = self.pop();
         let first = self.pop();
+        self.handle_infix(first, second, operator)
+    }
+
+    fn handle_infix(&mut self, first: Object, second: Object, operator: Opcode) -> Result<(), RuntimeError> {
 
         if let Object::IntegerObject(first) = first.clone() {
             if let Object::IntegerObject(second) = second.clone() {
-                self.handle_integer_infix_expression(first, second, operator.clone());
-                return;
+                return self.handle_integer_infix_expression(first, second, operator.clone());
             }
         } else if let Object::StringObject(first) = first.clone() {
             if let Object::StringObject(second) = second.clone() {
-                self.handle_string_infix_expression(first, second, operator.clone());
-                return;
+                return self.handle_string_infix_expression(first, second, operator.clone());
             }
         }
-        panic!(
+        Err(RuntimeError::new(format!(
             "operators not supported: {} {} {}",
             first.get_type(),
             look_up(&operator).unwrap().name,
             second.get_type()
-        )
+        )))
     }
 
-    pub fn handle_integer_infix_expression(&mut self, first: i64, second: i64, operator: Opcode) {
-        match operator {
-            Opcode::OpAdd => self.push(Object::IntegerObject(first + second)),
-            Opcode::OpMul => self.push(Object::IntegerObject(first * second)),
-            Opcode::OpDiv => self.push(Object::IntegerObject(first / second)),
-            Opcode::OpSub => self.push(Object::IntegerObject(first - second)),
+    pub fn handle_integer_infix_expression(
+        &mut self,
+        first: i64,
+        second: i64,
+        operator: Opcode,
+    ) -> Result<(), RuntimeError> {
+        let result = match operator {
+            Opcode::OpAdd => first.checked_add(second),
+            Opcode::OpMul => first.checked_mul(second),
+            Opcode::OpDiv => {
+                if second == 0 {
+                    return Err(RuntimeError::new("division by zero".to_string()));
+                }
+                first.checked_div(second)
+            }
+            Opcode::OpSub => first.checked_sub(second),
             _ => {
-                panic!("opcode not supported");
+                return Err(RuntimeError::new("opcode not supported".to_string()));
             }
+        };
+        match result {
+            Some(content) => self.push(Object::IntegerObject(content)),
+            None => Err(RuntimeError::new("integer overflow".to_string())),
         }
     }
 
@@ -125,54 +402,31 @@ impl Vm {
         first: String,
         second: String,
         operator: Opcode,
-    ) {
+    ) -> Result<(), RuntimeError> {
         match operator {
-            OpAdd => self.push(Object::StringObject(first + second.as_str())),
-            _ => {
-                panic!("opcode not supported");
+            OpAdd => {
+                self.charge_memory(std::mem::size_of::<Object>() + first.len() + second.len())?;
+                self.push(Object::StringObject(first + second.as_str()))
             }
+            _ => Err(RuntimeError::new("opcode not supported".to_string())),
         }
     }
 
-    pub fn handle_comparison(&mut self, operator: Opcode) {
+    pub fn handle_comparison(&mut self, operator: Opcode) -> Result<(), RuntimeError> {
         let second = self.pop();
         let first = self.pop();
-
-        if let IntegerObject(first) = first {
-            if let IntegerObject(second) = second {
-                match operator {
-                    Opcode::OpEq => self.push(BooleanObject(first == second)),
-                    Opcode::OpNotEq => self.push(BooleanObject(first != second)),
-                    Opcode::OpGreaterThan => self.push(BooleanObject(first > second)),
-                    _ => {
-                        panic!("operator not supported {}", operator as u8)
-                    }
-                }
-                return;
-            }
-        }
-
-        if let BooleanObject(first) = first {
-            if let BooleanObject(second) = second {
-                match operator {
-                    Opcode::OpEq => self.push(BooleanObject(first == second)),
-                    Opcode::OpNotEq => self.push(BooleanObject(first != second)),
-                    _ => {
-                        panic!("operator not supported {}", operator as u8)
-                    }
-                }
-                return;
-            }
-        }
-
-        panic!("Comparison operands not supported");
+        let result = compare(first, second, operator)?;
+        self.push(BooleanObject(result))
     }
 
-    fn handle_prefix(&mut self, operator: Opcode) {
+    fn handle_prefix(&mut self, operator: Opcode) -> Result<(), RuntimeError> {
         let prev = self.pop();
         match prev {
             IntegerObject(content) => match operator {
-                Opcode::OpMinus => self.push(IntegerObject(-content)),
+                Opcode::OpMinus => match content.checked_neg() {
+                    Some(content) => self.push(IntegerObject(content)),
+                    None => Err(RuntimeError::new("integer overflow".to_string())),
+                },
                 Opcode::OpBang => {
                     if content == 0 {
                         self.push(BooleanObject(false))
@@ -180,23 +434,32 @@ impl Vm {
                         self.push(BooleanObject(true))
                     }
                 }
-                _ => {
-                    panic!("operator not supported {}", operator as u8);
-                }
+                _ => Err(RuntimeError::new(format!(
+                    "operator not supported {}",
+                    operator as u8
+                ))),
             },
             BooleanObject(content) => match operator {
                 Opcode::OpBang => self.push(BooleanObject(!content)),
-                _ => {
-                    panic!("operator not supported {}", operator as u8);
-                }
+                _ => Err(RuntimeError::new(format!(
+                    "operator not supported {}",
+                    operator as u8
+                ))),
             },
-            _ => {
-                panic!("operand not supported {}", prev.get_type())
-            }
+            _ => Err(RuntimeError::new(format!(
+                "operand not supported {}",
+                prev.get_type()
+            ))),
         }
     }
 
-    pub fn push_global(&mut self, element: Object, pos: usize) {
+    pub fn push_global(&mut self, element: Object, pos: usize) -> Result<(), RuntimeError> {
+        if pos >= self.config.max_globals {
+            return Err(RuntimeError::new(format!(
+                "too many globals: the limit is {}",
+                self.config.max_globals
+            )));
+        }
         if self.globals.get(pos).is_none() {
             let mut i = self.globals.len();
             while i != pos {
@@ -207,56 +470,78 @@ impl Vm {
         } else {
             self.globals[pos] = Some(element);
         }
+        Ok(())
     }
 
-    pub fn get_array_from_stack(&mut self, len: u16) -> Vec<Box<Object>> {
+    pub fn get_array_from_stack(&mut self, len: usize) -> Vec<Object> {
         let mut array = Vec::new();
         for _index in (0..len).rev() {
-            array.push(Box::new(self.pop()));
+            array.push(self.pop());
         }
         array.reverse();
         array
     }
 
-    pub fn get_hash_from_stack(&mut self, len: u16) -> HashMap<Object, Object> {
+    pub fn get_hash_from_stack(&mut self, len: usize) -> Result<HashMap<Object, Object>, RuntimeError> {
         let mut hash = HashMap::new();
         for _index in (0..len).rev() {
             let second = self.pop();
             let first = self.pop();
+            if !first.is_hashable() {
+                return Err(RuntimeError::new(format!(
+                    "Type {} not hashable",
+                    first.get_type()
+                )));
+            }
             hash.insert(first, second);
         }
-        hash
+        Ok(hash)
     }
 
-    fn handle_index(&self, index: Object, array: Object) -> Object {
+    fn handle_index(&self, index: Object, array: Object) -> Result<Object, RuntimeError> {
         if let Object::Array(content) = array {
             if let Object::IntegerObject(index) = &index {
+                if *index < 0 {
+                    return Ok(Object::Null);
+                }
                 if let Some(object) = content.get(*index as usize) {
-                    object.as_ref().clone()
+                    Ok(object)
                 } else {
-                    Object::Null
+                    Ok(Object::Null)
                 }
             } else {
-                panic!("Type {} not supported as index", index.get_type());
+                Err(RuntimeError::new(format!(
+                    "Type {} not supported as index",
+                    index.get_type()
+                )))
             }
         } else if let Object::HashMap(array) = array {
             if index.is_hashable() {
                 if let Some(content) = array.get(&index) {
-                    content.clone()
+                    Ok(content.clone())
                 } else {
-                    Object::Null
+                    Ok(Object::Null)
                 }
             } else {
-                panic!("Type {} not hashable", index.get_type())
+                Err(RuntimeError::new(format!(
+                    "Type {} not hashable",
+                    index.get_type()
+                )))
             }
         } else {
-            panic!("Type {} not indexable", index.get_type())
+            Err(RuntimeError::new(format!(
+                "Type {} not indexable",
+                array.get_type()
+            )))
         }
     }
 
-    fn push(&mut self, object: Object) {
-        if self.sp > STACK_SIZE {
-            panic!("STACK OVERFLOW");
+    fn push(&mut self, object: Object) -> Result<(), RuntimeError> {
+        if self.sp >= self.config.stack_size {
+            return Err(RuntimeError::new(format!(
+                "stack overflow: the stack size is {}",
+                self.config.stack_size
+            )));
         }
         if self.stack.get(self.sp).is_none() {
             self.stack.push(object);
@@ -264,214 +549,427 @@ impl Vm {
             self.stack[self.sp] = object;
         }
         self.sp += 1;
+        Ok(())
     }
 
-    fn get_current_frame(&self) -> Frame {
-        self.frames[self.frames_index].clone()
+    fn charge_memory(&mut self, bytes: usize) -> Result<(), RuntimeError> {
+        self.meter.allocate(bytes).map_err(RuntimeError::from)
+    }
+
+    fn get_current_frame(&self) -> &Frame {
+        &self.frames[self.frames.len() - 1]
     }
 
     fn set_ip_current_frame(&mut self, pos: i64) {
-        self.frames[self.frames_index].ip = pos;
+        let index = self.frames.len() - 1;
+        self.frames[index].ip = pos;
     }
 
     fn push_frame(&mut self, frame: Frame) {
-        self.frames_index += 1;
+        if let Some(profile) = &mut self.profile {
+            profile.call(self.frames.len(), &frame.f.function);
+        }
         self.frames.push(frame);
     }
 
     fn pop_frame(&mut self) -> Frame {
-        self.frames_index -= 1;
-        self.frames.pop().unwrap()
+        let frame = self.frames.pop().unwrap();
+        while let Some(handler) = self.handlers.last() {
+            if handler.frames <= self.frames.len() {
+                break;
+            }
+            self.handlers.pop();
+        }
+        frame
     }
 
-    fn push_closure(&mut self, index: usize) {
+    fn push_closure(&mut self, index: usize) -> Result<(), RuntimeError> {
         let constant = self.constants[index].clone();
-        if let Object::CompiledFunction(content) = constant 
-        {
-            let closure = Object::Closure(ClosureStruct{
-                function:content,
-                free_vars: vec![]    
+        if let Object::CompiledFunction(content) = constant {
+            let closure = Object::Closure(ClosureStruct {
+                function: Rc::new(content),
+                free_vars: vec![],
             });
             self.push(closure)
+        } else {
+            Err(RuntimeError::new("not a function".to_string()))
         }
-        else {
-            panic!("not a function\n");
-        }
     }
 
-    pub fn run(&mut self) {
-        let mut ip = 0;
-        while self.get_current_frame().ip
-            < (self.get_current_frame().get_instructions().content.len() - 1) as i64
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        self.run_until(0)
+    }
+
+    // Runs until the main frame is exhausted or, for calls made from builtins,
+    // until the frame stack shrinks back to stop_depth.
+    fn run_until(&mut self, stop_depth: usize) -> Result<(), RuntimeError> {
+        while self.frames.len() > stop_depth
+            && self.get_current_frame().ip + 1
+                < self.get_current_frame().f.function.instructions.content.len() as i64
         {
-            self.set_ip_current_frame(self.get_current_frame().ip + 1);
-            ip = self.get_current_frame().ip;
-
-            let mut cursor = Cursor::new(self.get_current_frame().get_instructions().content);
-            cursor.set_position(ip as u64);
-            let opcode = Opcode::from_u8(cursor.read_u8().unwrap()).unwrap();
-            match opcode {
-                Opcode::OpConstant => {
-                    let index = cursor.read_u16::<BigEndian>().unwrap();
-                    self.set_ip_current_frame(ip + 2);
-                    self.push(self.constants[index as usize].clone());
-                }
-                Opcode::OpClosure => {
-                    let index = cursor.read_u16::<BigEndian>().unwrap();
-                    let free_vars = cursor.read_u8().unwrap();
-                    self.set_ip_current_frame(ip + 3);
-                    self.push_closure(index as usize);
-                }
-                Opcode::OpAdd => {
-                    self.handle_infix_expression(OpAdd);
-                }
-                Opcode::OpMul => {
-                    self.handle_infix_expression(OpMul);
-                }
-                Opcode::OpSub => {
-                    self.handle_infix_expression(OpSub);
-                }
-                Opcode::OpDiv => {
-                    self.handle_infix_expression(OpDiv);
+            if let Some(mut hook) = self.hook.take() {
+                let action = hook.before_instruction(self);
+                self.hook = Some(hook);
+                self.interrupted |= action == HookAction::Stop;
+            }
+            if self.interrupted {
+                let mut error = RuntimeError::new("interrupted".to_string());
+                error.kind = ErrorKind::Interrupted;
+                self.locate(&mut error);
+                return Err(error);
+            }
+            if let Err(limit) = self.meter.tick() {
+                let mut error = RuntimeError::from(limit);
+                self.locate(&mut error);
+                return Err(error);
+            }
+            if let Some(profile) = &mut self.profile {
+                let frame = &self.frames[self.frames.len() - 1];
+                profile.record(self.frames.len(), &frame.f.function, (frame.ip + 1) as usize);
+            }
+            if let Err(mut error) = self.execute_instruction() {
+                if error.call_stack.is_empty() {
+                    self.locate(&mut error);
                 }
-                Opcode::OpJumpNotTrue => {
-                    let pos = cursor.read_u16::<BigEndian>().unwrap();
-                    self.set_ip_current_frame(ip + 2);
-                    if !is_true(self.pop()) {
-                        self.set_ip_current_frame((pos - 1) as i64);
-                    }
+                self.unwind(error, stop_depth)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Jumps to the innermost catch block, dropping every frame and stack slot
+    // created inside its try block. Handlers registered outside the current
+    // run_until call are left alone so the error reaches the builtin that
+    // started it.
+    fn unwind(&mut self, error: RuntimeError, stop_depth: usize) -> Result<(), RuntimeError> {
+        match self.handlers.last() {
+            Some(handler) if handler.frames > stop_depth && error.is_catchable() => {
+                let handler = self.handlers.pop().unwrap();
+                self.frames.truncate(handler.frames);
+                self.sp = handler.sp;
+                self.set_ip_current_frame(handler.catch_pos as i64 - 1);
+                self.push(Object::StringObject(error.message))
+            }
+            _ => Err(error),
+        }
+    }
+
+    fn execute_instruction(&mut self) -> Result<(), RuntimeError> {
+        let ip = self.get_current_frame().ip + 1;
+        self.set_ip_current_frame(ip);
+
+        // Only the bytes of this instruction are copied out, so that the arms
+        // are free to change the frame.
+        let code = &self.get_current_frame().f.function.instructions.content;
+        let mut bytes = [0; MAX_INSTRUCTION_WIDTH];
+        let end = code.len().min(ip as usize + MAX_INSTRUCTION_WIDTH);
+        bytes[..end - ip as usize].copy_from_slice(&code[ip as usize..end]);
+        let mut cursor = Cursor::new(bytes);
+        let mut opcode = Opcode::from_u8(cursor.read_u8().unwrap()).unwrap();
+        let wide = opcode == Opcode::OpWide;
+        if wide {
+            opcode = Opcode::from_u8(cursor.read_u8().unwrap()).unwrap();
+        }
+        match opcode {
+            Opcode::OpConstant => {
+                let index = read_operand(&mut cursor, 2, wide);
+                self.set_ip_current_frame(ip + cursor.position() as i64 - 1);
+                self.push(self.constants[index].clone())?;
+            }
+            Opcode::OpClosure => {
+                let index = read_operand(&mut cursor, 2, wide);
+                let _free_vars = read_operand(&mut cursor, 1, wide);
+                self.set_ip_current_frame(ip + cursor.position() as i64 - 1);
+                self.push_closure(index)?;
+            }
+            Opcode::OpAdd => {
+                self.handle_infix_expression(OpAdd)?;
+            }
+            Opcode::OpMul => {
+                self.handle_infix_expression(OpMul)?;
+            }
+            Opcode::OpSub => {
+                self.handle_infix_expression(OpSub)?;
+            }
+            Opcode::OpDiv => {
+                self.handle_infix_expression(OpDiv)?;
+            }
+            Opcode::OpJumpNotTrue => {
+                let pos = read_operand(&mut cursor, 2, wide);
+                self.set_ip_current_frame(ip + cursor.position() as i64 - 1);
+                let condition = self.pop();
+                if !is_true(condition)? {
+                    self.set_ip_current_frame(pos as i64 - 1);
                 }
-                Opcode::OpGetGlobal => {
-                    let pos = cursor.read_u16::<BigEndian>().unwrap();
-                    self.set_ip_current_frame(ip + 2);
-                    if let Some(content) = self.globals.get(pos as usize).clone() {
-                        self.push(content.clone().unwrap());
+            }
+            Opcode::OpGetGlobal => {
+                let pos = read_operand(&mut cursor, 2, wide);
+                self.set_ip_current_frame(ip + cursor.position() as i64 - 1);
+                match self.globals.get(pos).cloned().flatten() {
+                    Some(content) => self.push(content)?,
+                    None => {
+                        return Err(RuntimeError::new(format!("global {} used before it was set", pos)));
                     }
                 }
-                Opcode::OpGetBuiltin => {
-                    let pos = cursor.read_u8().unwrap();
-                    self.set_ip_current_frame(ip + 1);
-                    if let Some(content) = get_built_in(BUILT_INS[pos as usize].to_string()) {
-                        self.push(content);
+            }
+            Opcode::OpGetBuiltin => {
+                let pos = read_operand(&mut cursor, 1, wide);
+                self.set_ip_current_frame(ip + cursor.position() as i64 - 1);
+                match self.builtins.get(pos) {
+                    Some(content) => self.push(Object::BuiltIn(content.clone()))?,
+                    None => {
+                        return Err(RuntimeError::new(format!("unknown builtin {}", pos)));
                     }
                 }
-                Opcode::OpSetGlobal => {
-                    let pos = cursor.read_u16::<BigEndian>().unwrap();
-                    self.set_ip_current_frame(ip + 2);
-                    let element = self.pop();
-                    self.push_global(element, pos as usize);
-                }
-                Opcode::OpSetLocal => {
-                    let local_index = cursor.read_u8().unwrap();
-                    let base_pointer = self.get_current_frame().base_pointer;
-                    self.set_ip_current_frame(ip + 1);
+            }
+            Opcode::OpSetGlobal => {
+                let pos = read_operand(&mut cursor, 2, wide);
+                self.set_ip_current_frame(ip + cursor.position() as i64 - 1);
+                let element = self.pop();
+                self.push_global(element, pos)?;
+            }
+            Opcode::OpSetLocal => {
+                let local_index = read_operand(&mut cursor, 1, wide);
+                let base_pointer = self.get_current_frame().base_pointer;
+                self.set_ip_current_frame(ip + cursor.position() as i64 - 1);
 
-                    self.stack[base_pointer + local_index as usize] = self.pop();
-                }
-                Opcode::OpGetLocal => {
-                    let local_index = cursor.read_u8().unwrap();
-                    let base_pointer = self.get_current_frame().base_pointer;
-                    self.set_ip_current_frame(ip + 1);
+                self.stack[base_pointer + local_index] = self.pop();
+            }
+            Opcode::OpGetLocal => {
+                let local_index = read_operand(&mut cursor, 1, wide);
+                let base_pointer = self.get_current_frame().base_pointer;
+                self.set_ip_current_frame(ip + cursor.position() as i64 - 1);
 
-                    let object = self.stack[local_index as usize + base_pointer].clone();
-                    self.push(object);
-                }
-                Opcode::OpJump => {
-                    let pos = cursor.read_u16::<BigEndian>().unwrap();
-                    self.set_ip_current_frame((pos - 1) as i64);
-                }
-                Opcode::OpEq => {
-                    self.handle_comparison(opcode);
-                }
-                Opcode::OpNotEq => {
-                    self.handle_comparison(opcode);
-                }
-                Opcode::OpGreaterThan => {
-                    self.handle_comparison(opcode);
+                let object = self.stack[local_index + base_pointer].clone();
+                self.push(object)?;
+            }
+            Opcode::OpJump => {
+                let pos = read_operand(&mut cursor, 2, wide);
+                self.set_ip_current_frame(pos as i64 - 1);
+            }
+            Opcode::OpAddLocalConstant | Opcode::OpSubLocalConstant => {
+                let local_index = read_operand(&mut cursor, 1, wide);
+                let constant_index = read_operand(&mut cursor, 2, wide);
+                self.set_ip_current_frame(ip + cursor.position() as i64 - 1);
+                let first = self.stack[self.get_current_frame().base_pointer + local_index].clone();
+                let second = self.constants[constant_index].clone();
+                let operator = if opcode == Opcode::OpAddLocalConstant { OpAdd } else { OpSub };
+                self.handle_infix(first, second, operator)?;
+            }
+            Opcode::OpJumpNotEq | Opcode::OpJumpNotGreater => {
+                let pos = read_operand(&mut cursor, 2, wide);
+                self.set_ip_current_frame(ip + cursor.position() as i64 - 1);
+                let second = self.pop();
+                let first = self.pop();
+                let operator = if opcode == Opcode::OpJumpNotEq { Opcode::OpEq } else { Opcode::OpGreaterThan };
+                if !compare(first, second, operator)? {
+                    self.set_ip_current_frame(pos as i64 - 1);
                 }
-                Opcode::OpMinus => {
-                    self.handle_prefix(opcode);
+            }
+            Opcode::OpEq => {
+                self.handle_comparison(opcode)?;
+            }
+            Opcode::OpNotEq => {
+                self.handle_comparison(opcode)?;
+            }
+            Opcode::OpGreaterThan => {
+                self.handle_comparison(opcode)?;
+            }
+            Opcode::OpMinus => {
+                self.handle_prefix(opcode)?;
+            }
+            Opcode::OpBang => {
+                self.handle_prefix(opcode)?;
+            }
+            Opcode::OpNull => {
+                self.push(Object::Null)?;
+            }
+            Opcode::OpArray => {
+                let len = read_operand(&mut cursor, 2, wide);
+                self.set_ip_current_frame(ip + cursor.position() as i64 - 1);
+                let array = Object::from(self.get_array_from_stack(len));
+                self.charge_memory(array.approximate_size())?;
+                self.push(array)?;
+            }
+            Opcode::OpIndex => {
+                let index = self.pop();
+                let array = self.pop();
+                let element = self.handle_index(index, array)?;
+                self.push(element)?;
+            }
+            Opcode::OpCall => {
+                let argc = read_operand(&mut cursor, 1, wide);
+                self.set_ip_current_frame(ip + cursor.position() as i64 - 1);
+                let index = self.frames.len() - 1;
+                self.frames[index].call = ip as usize;
+                self.call(argc)?;
+            }
+            Opcode::OpTailCall => {
+                let argc = read_operand(&mut cursor, 1, wide);
+                self.set_ip_current_frame(ip + cursor.position() as i64 - 1);
+                let index = self.frames.len() - 1;
+                self.frames[index].call = ip as usize;
+                self.tail_call(argc)?;
+            }
+            Opcode::OpReturnValue => {
+                let return_value = self.pop();
+
+                let frame = self.pop_frame();
+                self.sp = frame.base_pointer - 1;
+
+                self.push(return_value)?;
+            }
+            Opcode::OpReturn => {
+                let frame = self.pop_frame();
+                self.sp = frame.base_pointer - 1;
+
+                self.push(Object::Null)?;
+            }
+            Opcode::OpHash => {
+                let len = read_operand(&mut cursor, 2, wide);
+                self.set_ip_current_frame(ip + cursor.position() as i64 - 1);
+                let hash = Object::HashMap(self.get_hash_from_stack(len)?);
+                self.charge_memory(hash.approximate_size())?;
+                self.push(hash)?;
+            }
+            Opcode::OpTry => {
+                let catch_pos = read_operand(&mut cursor, 2, wide);
+                self.set_ip_current_frame(ip + cursor.position() as i64 - 1);
+                self.handlers.push(Handler {
+                    catch_pos,
+                    sp: self.sp,
+                    frames: self.frames.len(),
+                });
+            }
+            Opcode::OpEndTry => {
+                self.handlers.pop();
+            }
+            Opcode::OpPop => {
+                self.pop();
+            }
+            Opcode::OpTrue => self.push(Object::BooleanObject(true))?,
+            Opcode::OpFalse => self.push(Object::BooleanObject(false))?,
+            _ => {
+                return Err(RuntimeError::new(format!(
+                    "Not supported Opcode: {:?}",
+                    opcode
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, argc: usize) -> Result<(), RuntimeError> {
+        let stack_top = self.stack[self.sp - 1 - argc].clone();
+        match stack_top {
+            Object::Closure(content) => {
+                if argc != content.function.num_args {
+                    return Err(RuntimeError::new(format!(
+                        "wrong number of arguments: want={}, got={}",
+                        content.function.num_args, argc
+                    )));
                 }
-                Opcode::OpBang => {
-                    self.handle_prefix(opcode);
+                if self.frames.len() >= self.config.max_frames {
+                    return Err(RuntimeError::new(format!(
+                        "maximum call depth exceeded: the limit is {} frames",
+                        self.config.max_frames
+                    )));
                 }
-                Opcode::OpNull => {
-                    self.push(Object::Null);
+                let num_vars = content.function.num_vars;
+                let frame = Frame::new(content, self.sp - argc);
+                self.push_frame(frame);
+                for _ in 0..num_vars {
+                    self.push(Object::Null)?;
                 }
-                Opcode::OpArray => {
-                    let len = cursor.read_u16::<BigEndian>().unwrap();
-                    self.set_ip_current_frame(ip + 2);
-                    let array = self.get_array_from_stack(len);
-                    self.push(Object::Array(array));
+                Ok(())
+            }
+            Object::BuiltIn(builtin) => {
+                let mut args = Vec::new();
+                for _ in 0..argc {
+                    args.push(self.pop())
                 }
-                Opcode::OpIndex => {
-                    let index = self.pop();
-                    let array = self.pop();
-                    self.push(self.handle_index(index, array));
+                self.pop();
+
+                self.callback_error = None;
+                let result = builtin.call(args.into_iter().rev().collect(), self);
+                // A builtin sees a blown budget only as an error value, which it
+                // may drop; the meter still knows the run has to stop.
+                if let Some(limit) = self.meter.exceeded() {
+                    return Err(RuntimeError::from(limit));
                 }
-                Opcode::OpCall => {
-                    let argc = cursor.read_u8().unwrap();
-                    self.set_ip_current_frame(self.get_current_frame().ip + 1);
-                    let stack_top = self.stack[self.sp - 1 - argc as usize].clone();
-                    match stack_top {
-                        Object::Closure(content) => {
-                            if argc as usize != content.function.num_args {
-                                panic!(
-                                    "wrong number of arguments: want={}, got={}",
-                                    content.function.num_args, argc
-                                );
-                            }
-                            let num_vars = content.function.num_vars;
-                            let frame = Frame::new(content, self.sp - argc as usize);
-                            self.push_frame(frame);
-                            for _ in 0..num_vars {
-                                self.push(Object::Null);
+                match result {
+                    Object::Error(message) => {
+                        let mut error = RuntimeError::new(message);
+                        if let Some(callback_error) = self.callback_error.take() {
+                            if callback_error.message == error.message {
+                                error.call_stack = callback_error.call_stack;
+                                error.span = callback_error.span;
                             }
                         }
-                        Object::BuiltIn(builtin) => {
-                            let mut args = Vec::new();
-                            for _ in 0..argc {
-                                args.push(self.pop())
-                            }
-
-                            let result = builtin(args.into_iter().rev().collect());
-                            self.push(result);
-                        }
-                        _ => {
-                            panic!("Expected CompiledFunction but found otherwise\n");
-                        }
+                        Err(error)
                     }
+                    result => self.push(result),
                 }
-                Opcode::OpReturnValue => {
-                    let return_value = self.pop();
+            }
+            _ => Err(RuntimeError::new(
+                "Expected CompiledFunction but found otherwise".to_string(),
+            )),
+        }
+    }
 
-                    let frame = self.pop_frame();
-                    self.sp = frame.base_pointer - 1;
+    // Calls the function below the argc arguments in place of the current
+    // frame, so that loops written as tail recursion run in constant space.
+    // Builtins, calls that will fail and calls made inside a try block, whose
+    // handler belongs to the current frame, go through call as usual; the
+    // OpReturnValue after the OpTailCall then returns their result.
+    fn tail_call(&mut self, argc: usize) -> Result<(), RuntimeError> {
+        let callee = self.sp - 1 - argc;
+        let reusable = match &self.stack[callee] {
+            Object::Closure(content) => content.function.num_args == argc,
+            _ => false,
+        };
+        let in_try = self.handlers.last().is_some_and(|handler| handler.frames == self.frames.len());
+        if !reusable || in_try {
+            return self.call(argc);
+        }
 
-                    self.push(return_value);
-                }
-                Opcode::OpReturn => {
-                    let frame = self.pop_frame();
-                    self.sp = frame.base_pointer - 1;
+        let frame = self.pop_frame();
+        let start = frame.base_pointer - 1;
+        for offset in 0..=argc {
+            self.stack[start + offset] = self.stack[callee + offset].clone();
+        }
+        self.sp = start + 1 + argc;
+        if let Some(profile) = &mut self.profile {
+            profile.tail_call();
+        }
+        self.call(argc)
+    }
 
-                    self.push(Object::Null);
-                }
-                Opcode::OpHash => {
-                    let len = cursor.read_u16::<BigEndian>().unwrap();
-                    self.set_ip_current_frame(ip + 2);
-                    let array = self.get_hash_from_stack(len);
-                    self.push(Object::HashMap(array));
-                }
-                Opcode::OpPop => {
-                    self.pop();
-                }
-                Opcode::OpTrue => self.push(Object::BooleanObject(true)),
-                Opcode::OpFalse => self.push(Object::BooleanObject(false)),
-                _ => {
-                    panic!("Not supported Opcode: {:?}", opcode)
+    fn locate(&self, error: &mut RuntimeError) {
+        error.call_stack = self.call_stack();
+        error.span = self.frames.last().and_then(|frame| {
+            frame.f.function.source_map.lookup(frame.ip.max(0) as usize)
+        });
+    }
+
+    // One line per frame, innermost first, naming the function and the source
+    // position of the statement it was running.
+    fn call_stack(&self) -> Vec<String> {
+        let innermost = self.frames.len() - 1;
+        self.frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, frame)| {
+                // ip is the last byte read, which belongs to the instruction that
+                // failed; the callers are at their call.
+                let offset = if i == innermost { frame.ip.max(0) as usize } else { frame.call };
+                match frame.f.function.source_map.lookup(offset) {
+                    Some(span) => format!("{} ({})", frame.name(), span.start),
+                    None => format!("{} (offset {})", frame.name(), offset),
                 }
-            }
-        }
+            })
+            .collect()
     }
 
     fn pop(&mut self) -> Object {
@@ -485,13 +983,96 @@ impl Vm {
     }
 }
 
-fn is_true(object: Object) -> bool {
-    match object {
-        IntegerObject(content) => content != 0,
-        BooleanObject(content) => content,
-        Object::Null => false,
-        _ => {
-            panic!("type not supported: {}", object.get_type())
+impl FunctionCaller for Vm {
+    fn call_function(&mut self, function: Object, args: Vec<Object>) -> Object {
+        if let Object::Closure(content) = &function {
+            if args.len() != content.function.num_args {
+                return Object::Error(format!(
+                    "wrong number of arguments: want={}, got={}",
+                    content.function.num_args,
+                    args.len()
+                ));
+            }
+        } else if !matches!(function, Object::BuiltIn(_)) {
+            return Object::Error(format!("Not a function {}", function.get_type()));
         }
+
+        let depth = self.frames.len();
+        let sp = self.sp;
+        let argc = args.len();
+        let result = self
+            .push(function)
+            .and_then(|_| args.into_iter().try_for_each(|arg| self.push(arg)))
+            .and_then(|_| self.call(argc))
+            .and_then(|_| self.run_until(depth));
+        match result {
+            Ok(()) => self.pop(),
+            Err(error) => {
+                self.frames.truncate(depth);
+                self.handlers.retain(|handler| handler.frames <= depth);
+                self.sp = sp;
+                let message = error.message.clone();
+                self.callback_error = Some(error);
+                Object::Error(message)
+            }
+        }
+    }
+
+    fn allocate(&mut self, bytes: usize) -> bool {
+        self.meter.allocate(bytes).is_ok()
+    }
+}
+
+// Reads an operand of the given width, or of four bytes after an OpWide prefix.
+fn read_operand(cursor: &mut Cursor<[u8; MAX_INSTRUCTION_WIDTH]>, width: usize, wide: bool) -> usize {
+    match (wide, width) {
+        (true, _) => cursor.read_u32::<BigEndian>().unwrap() as usize,
+        (false, 1) => cursor.read_u8().unwrap() as usize,
+        _ => cursor.read_u16::<BigEndian>().unwrap() as usize,
+    }
+}
+
+fn compare(first: Object, second: Object, operator: Opcode) -> Result<bool, RuntimeError> {
+    if let IntegerObject(first) = first {
+        if let IntegerObject(second) = second {
+            return match operator {
+                Opcode::OpEq => Ok(first == second),
+                Opcode::OpNotEq => Ok(first != second),
+                Opcode::OpGreaterThan => Ok(first > second),
+                _ => Err(RuntimeError::new(format!(
+                    "operator not supported {}",
+                    operator as u8
+                ))),
+            };
+        }
+    }
+
+    if let BooleanObject(first) = first {
+        if let BooleanObject(second) = second {
+            return match operator {
+                Opcode::OpEq => Ok(first == second),
+                Opcode::OpNotEq => Ok(first != second),
+                _ => Err(RuntimeError::new(format!(
+                    "operator not supported {}",
+                    operator as u8
+                ))),
+            };
+        }
+    }
+
+    Err(RuntimeError::new(
+        "Comparison operands not supported".to_string(),
+    ))
+}
+
+fn is_true(object: Object) -> Result<bool, RuntimeError> {
+    match object {
+        IntegerObject(content) => Ok(content != 0),
+        BooleanObject(content) => Ok(content),
+        Object::Null => Ok(false),
+        _ => Err(RuntimeError::new(format!(
+            "type not supported: {}",
+            object.get_type()
+        ))),
     }
 }